@@ -0,0 +1,201 @@
+use std::sync::Arc;
+
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use futures::future::LocalBoxFuture;
+
+use super::{
+    get_bearer_token, get_session_token,
+    users::{
+        access_token::is_access_token,
+        api_token::{is_api_token, ApiScope},
+        session_token::SessionToken,
+        user::UserId,
+    },
+    ApiError,
+};
+use crate::{config::Config, database::DatabaseManager};
+
+/// How a request proved who it's from.
+pub enum Credential {
+    Session(SessionToken),
+    /// A personal API token, limited to its scopes.
+    ApiToken(Vec<ApiScope>),
+    /// A signed access token, checked without looking up its session.
+    AccessToken { session_id: String },
+}
+
+/// The user behind the session or API token of a request.
+///
+/// Taking it as a handler argument rejects anonymous requests before the handler runs,
+/// the handler still has to `require` the scope it needs.
+pub struct AuthenticatedUser {
+    pub id: UserId,
+    pub credential: Credential,
+}
+
+impl AuthenticatedUser {
+    /// Sessions may do everything, API tokens only what their scopes allow.
+    pub fn require(&self, scope: ApiScope) -> Result<(), ApiError> {
+        match &self.credential {
+            Credential::Session(_) | Credential::AccessToken { .. } => Ok(()),
+            Credential::ApiToken(scopes) if scopes.iter().any(|s| s.allows(scope)) => Ok(()),
+            Credential::ApiToken(_) => Err(ApiError::InsufficientScope),
+        }
+    }
+
+    /// `require(ApiScope::Account)`, which for access tokens also checks that their session
+    /// is still live. Elsewhere they outlive the end of their session by up to their TTL.
+    pub async fn require_account(&self, db_mgr: &DatabaseManager) -> Result<(), ApiError> {
+        self.require(ApiScope::Account)?;
+        if let Credential::AccessToken { session_id } = &self.credential {
+            if !db_mgr.users.has_session(&self.id, session_id).await? {
+                return Err(ApiError::InvalidAccessToken);
+            }
+        }
+        Ok(())
+    }
+
+    /// For what only a login may do where a `SessionUser` is too strict, like changing the
+    /// email address password resets are sent to as part of a profile update.
+    pub fn require_session(&self) -> Result<(), ApiError> {
+        match &self.credential {
+            Credential::Session(_) => Ok(()),
+            _ => Err(ApiError::SessionRequired),
+        }
+    }
+}
+
+/// The user behind the session of a request, for what API and access tokens may never do,
+/// like managing sessions, passwords and tokens.
+pub struct SessionUser {
+    pub id: UserId,
+    pub session_token: SessionToken,
+}
+
+fn authenticate(req: &HttpRequest) -> LocalBoxFuture<'static, Result<AuthenticatedUser, ApiError>> {
+    let bearer_token = get_bearer_token(req);
+    if let Some(token) = bearer_token.filter(|token| is_access_token(token)) {
+        let config = req.app_data::<web::Data<Arc<Config>>>();
+        let user = match config {
+            Some(config) if config.access_tokens.enabled => config
+                .access_tokens
+                .verify(token)
+                .map(|claims| AuthenticatedUser {
+                    id: claims.sub,
+                    credential: Credential::AccessToken {
+                        session_id: claims.sid,
+                    },
+                })
+                .ok_or(ApiError::InvalidAccessToken),
+            Some(_) => Err(ApiError::InvalidAccessToken),
+            None => Err(ApiError::InternalServerError),
+        };
+        return Box::pin(async move { user });
+    }
+    let api_token = bearer_token
+        .filter(|token| is_api_token(token))
+        .map(str::to_string);
+    let session_token = get_session_token(req);
+    let db_mgr = req.app_data::<web::Data<Arc<DatabaseManager>>>().cloned();
+
+    Box::pin(async move {
+        let db_mgr = db_mgr.ok_or(ApiError::InternalServerError)?;
+        if let Some(api_token) = api_token {
+            return db_mgr
+                .api_tokens
+                .get_access(&api_token)
+                .await?
+                .map(|access| AuthenticatedUser {
+                    id: access.user_id,
+                    credential: Credential::ApiToken(access.scopes),
+                })
+                .ok_or(ApiError::InvalidApiToken);
+        }
+
+        let session_token = session_token.ok_or(ApiError::MissingSessionToken)?;
+        db_mgr
+            .users
+            .get_session_token(session_token.clone())
+            .await?
+            .map(|user| AuthenticatedUser {
+                id: user.id,
+                credential: Credential::Session(session_token),
+            })
+            .ok_or(ApiError::InvalidSessionToken)
+    })
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user = authenticate(req);
+        Box::pin(async move { user.await.map_err(|api_err| api_err.into()) })
+    }
+}
+
+impl FromRequest for SessionUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user = authenticate(req);
+        Box::pin(async move {
+            let user = match user.await {
+                Ok(AuthenticatedUser {
+                    id,
+                    credential: Credential::Session(session_token),
+                }) => Ok(SessionUser { id, session_token }),
+                Ok(_) => Err(ApiError::SessionRequired),
+                Err(api_err) => Err(api_err),
+            };
+            user.map_err(|api_err| api_err.into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(credential: Credential) -> AuthenticatedUser {
+        AuthenticatedUser {
+            id: UserId::new(),
+            credential,
+        }
+    }
+
+    #[test]
+    fn api_tokens_are_limited_to_their_scopes() {
+        let todo_write = user(Credential::ApiToken(vec![ApiScope::TodoWrite]));
+        assert!(todo_write.require(ApiScope::TodoRead).is_ok());
+        assert!(todo_write.require(ApiScope::TodoWrite).is_ok());
+        assert!(matches!(todo_write.require(ApiScope::Account), Err(ApiError::InsufficientScope)));
+
+        let todo_read = user(Credential::ApiToken(vec![ApiScope::TodoRead]));
+        assert!(matches!(todo_read.require(ApiScope::TodoWrite), Err(ApiError::InsufficientScope)));
+        let account = user(Credential::ApiToken(vec![ApiScope::Account]));
+        assert!(account.require(ApiScope::Account).is_ok());
+        assert!(matches!(account.require(ApiScope::TodoRead), Err(ApiError::InsufficientScope)));
+        assert!(user(Credential::ApiToken(vec![])).require(ApiScope::TodoRead).is_err());
+    }
+
+    #[test]
+    fn only_sessions_count_as_sessions() {
+        let session = user(Credential::Session(SessionToken::new()));
+        for scope in [ApiScope::TodoRead, ApiScope::TodoWrite, ApiScope::Account] {
+            assert!(session.require(scope).is_ok());
+        }
+        assert!(session.require_session().is_ok());
+
+        let account = user(Credential::ApiToken(vec![ApiScope::Account]));
+        assert!(matches!(account.require_session(), Err(ApiError::SessionRequired)));
+        let access = user(Credential::AccessToken {
+            session_id: String::from("session"),
+        });
+        assert!(matches!(access.require_session(), Err(ApiError::SessionRequired)));
+    }
+}
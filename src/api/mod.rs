@@ -1,11 +1,28 @@
+pub mod auth;
+pub mod rate_limit;
 pub mod users;
 pub mod todo;
 
-use actix_web::{dev::HttpResponseBuilder, web, HttpRequest, HttpResponse};
+use actix::MailboxError;
+use actix_web::{
+    http::{header, HeaderMap, StatusCode},
+    web, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
 use serde::Serialize;
-use HttpResponse as HR;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use thiserror::Error;
 
-use self::users::session_token::SessionToken;
+use crate::database::DbError;
+
+use self::users::{
+    policy::{PasswordViolation, UsernameViolation},
+    session_token::SessionToken,
+};
+
+pub use self::auth::{AuthenticatedUser, SessionUser};
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -20,6 +37,9 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 #[derive(Serialize)]
 pub struct ApiResponse<T> {
     message: String,
+    /// Stable code of the error, for clients to act on instead of parsing `message`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<T>,
 }
@@ -27,28 +47,10 @@ impl ApiResponse<()> {
     pub fn new<T: Into<String>>(message: T) -> Self {
         ApiResponse {
             message: message.into(),
+            error: None,
             content: None,
         }
     }
-    #[allow(unreachable_patterns)]
-    pub fn from(err: ApiError) -> HttpResponse {
-        let prefix = String::from("Error: ");
-
-        let (http_response, description): (fn() -> HttpResponseBuilder, &str) = match err {
-            ApiError::PasswordInsufficient => (HR::BadRequest, "insufficient password"),
-            ApiError::EmailInUse => (HR::BadRequest, "email in use"),
-            ApiError::UsernameInUse => (HR::BadRequest, "username in use"),
-            ApiError::InvalidUsername => (
-                HR::BadRequest,
-                "username invalid (too short, long or containing invalid characters)",
-            ),
-
-            ApiError::MissingSessionToken => (HR::Unauthorized, "missing header session_token"),
-            ApiError::IncorrectCredentials => (HR::Forbidden, "the credentials are incorrect"),
-            ApiError::InternalServerError => (HR::InternalServerError, "internal server error"),
-        };
-        http_response().json(ApiResponse::new(prefix + description))
-    }
 }
 
 impl<T> ApiResponse<T> {
@@ -56,27 +58,341 @@ impl<T> ApiResponse<T> {
     pub fn with_content(message: &str, content: T) -> Self {
         ApiResponse {
             message: message.to_owned(),
+            error: None,
             content: Some(content),
         }
     }
+
+    fn error(err: &ApiError, content: Option<T>) -> Self {
+        ApiResponse {
+            message: format!("Error: {}", err),
+            error: Some(err.code()),
+            content,
+        }
+    }
 }
 
 #[allow(dead_code)]
 #[non_exhaustive]
+#[derive(Debug, Error)]
 pub enum ApiError {
+    #[error("username in use")]
     UsernameInUse,
+    #[error("email in use")]
     EmailInUse,
-    PasswordInsufficient,
-    InvalidUsername,
+    #[error("email address invalid")]
+    InvalidEmail,
+    #[error("link invalid or expired")]
+    InvalidToken,
+    #[error("insufficient password")]
+    PasswordInsufficient(Vec<PasswordViolation>),
+    #[error("username invalid (too short, long or containing invalid characters)")]
+    InvalidUsername(Vec<UsernameViolation>),
+    #[error("todo item not found")]
+    TodoItemNotFound,
+    /// The account was removed while a token of it was still valid.
+    #[error("todo list not found")]
+    TodoListNotFound,
+    #[error("session not found")]
+    SessionNotFound,
+    #[error("the credentials are incorrect")]
     IncorrectCredentials,
+    #[error("two-factor authentication is already enabled")]
+    TwoFactorAlreadyEnabled,
+    #[error("two-factor authentication is not set up")]
+    TwoFactorNotEnrolled,
+    #[error("the two-factor code is incorrect")]
+    InvalidTwoFactorCode,
+    #[error("two-factor login expired, log in again")]
+    TwoFactorLoginExpired,
+    #[error("api token name must not be empty or too long")]
+    InvalidApiTokenName,
+    #[error("api tokens need at least one scope")]
+    MissingApiTokenScope,
+    #[error("too many api tokens, revoke unused ones first")]
+    TooManyApiTokens,
+    #[error("api token not found")]
+    ApiTokenNotFound,
+    #[error("the api token lacks the scope for this")]
+    InsufficientScope,
+    #[error("this needs a login session, api tokens can't do it")]
+    SessionRequired,
+    #[error("login with an identity provider is not set up")]
+    OidcNotConfigured,
+    #[error("access tokens are not enabled")]
+    AccessTokensDisabled,
+    #[error("login with the identity provider failed")]
+    OidcLoginFailed,
+    #[error("this identity is linked to another account")]
+    IdentityInUse,
+    #[error("no account is linked to this identity")]
+    IdentityNotLinked,
+    #[error("missing session token (cookie or bearer token)")]
     MissingSessionToken,
+    #[error("session token invalid or expired")]
+    InvalidSessionToken,
+    #[error("api token invalid or expired")]
+    InvalidApiToken,
+    #[error("access token invalid or expired")]
+    InvalidAccessToken,
+    #[error("refresh token was already used, the session was ended")]
+    RefreshTokenReused,
+    /// Answered with a `Retry-After` header of the given wait.
+    #[error("too many requests, try again later")]
+    TooManyRequests(Duration),
+    #[error("the database is unavailable, try again later")]
+    DatabaseUnavailable,
+    #[error("internal server error")]
     InternalServerError,
 }
 
+impl ApiError {
+    /// Sent along in `ApiResponse::error`. Clients rely on these, never change one.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::UsernameInUse => "username_in_use",
+            ApiError::EmailInUse => "email_in_use",
+            ApiError::InvalidEmail => "invalid_email",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::PasswordInsufficient(_) => "password_insufficient",
+            ApiError::InvalidUsername(_) => "invalid_username",
+            ApiError::TodoItemNotFound => "todo_item_not_found",
+            ApiError::TodoListNotFound => "todo_list_not_found",
+            ApiError::SessionNotFound => "session_not_found",
+            ApiError::IncorrectCredentials => "incorrect_credentials",
+            ApiError::TwoFactorAlreadyEnabled => "two_factor_already_enabled",
+            ApiError::TwoFactorNotEnrolled => "two_factor_not_enrolled",
+            ApiError::InvalidTwoFactorCode => "invalid_two_factor_code",
+            ApiError::TwoFactorLoginExpired => "two_factor_login_expired",
+            ApiError::InvalidApiTokenName => "invalid_api_token_name",
+            ApiError::MissingApiTokenScope => "missing_api_token_scope",
+            ApiError::TooManyApiTokens => "too_many_api_tokens",
+            ApiError::ApiTokenNotFound => "api_token_not_found",
+            ApiError::InsufficientScope => "insufficient_scope",
+            ApiError::SessionRequired => "session_required",
+            ApiError::OidcNotConfigured => "oidc_not_configured",
+            ApiError::AccessTokensDisabled => "access_tokens_disabled",
+            ApiError::OidcLoginFailed => "oidc_login_failed",
+            ApiError::IdentityInUse => "identity_in_use",
+            ApiError::IdentityNotLinked => "identity_not_linked",
+            ApiError::MissingSessionToken => "missing_session_token",
+            ApiError::InvalidSessionToken => "invalid_session_token",
+            ApiError::InvalidApiToken => "invalid_api_token",
+            ApiError::InvalidAccessToken => "invalid_access_token",
+            ApiError::RefreshTokenReused => "refresh_token_reused",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::DatabaseUnavailable => "database_unavailable",
+            ApiError::InternalServerError => "internal_server_error",
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::UsernameInUse
+            | ApiError::EmailInUse
+            | ApiError::InvalidEmail
+            | ApiError::InvalidToken
+            | ApiError::PasswordInsufficient(_)
+            | ApiError::InvalidUsername(_)
+            | ApiError::TwoFactorAlreadyEnabled
+            | ApiError::TwoFactorNotEnrolled
+            | ApiError::InvalidApiTokenName
+            | ApiError::MissingApiTokenScope
+            | ApiError::TooManyApiTokens
+            | ApiError::IdentityInUse => StatusCode::BAD_REQUEST,
+
+            ApiError::TodoItemNotFound
+            | ApiError::TodoListNotFound
+            | ApiError::OidcNotConfigured
+            | ApiError::AccessTokensDisabled
+            | ApiError::ApiTokenNotFound
+            | ApiError::SessionNotFound => StatusCode::NOT_FOUND,
+
+            ApiError::MissingSessionToken
+            | ApiError::InvalidSessionToken
+            | ApiError::InvalidApiToken
+            | ApiError::InvalidAccessToken
+            | ApiError::RefreshTokenReused
+            | ApiError::OidcLoginFailed
+            | ApiError::TwoFactorLoginExpired => StatusCode::UNAUTHORIZED,
+
+            ApiError::IdentityNotLinked
+            | ApiError::IncorrectCredentials
+            | ApiError::InvalidTwoFactorCode
+            | ApiError::InsufficientScope
+            | ApiError::SessionRequired => StatusCode::FORBIDDEN,
+
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::DatabaseUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::PasswordInsufficient(violations) => {
+                response.json(ApiResponse::error(self, Some(violations)))
+            }
+            ApiError::InvalidUsername(violations) => {
+                response.json(ApiResponse::error(self, Some(violations)))
+            }
+            ApiError::TooManyRequests(retry_after) => {
+                // Rounded up, retrying early would only be rejected again.
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response
+                    .header(header::RETRY_AFTER, secs.to_string())
+                    .json(ApiResponse::<()>::error(self, None))
+            }
+            _ => response.json(ApiResponse::<()>::error(self, None)),
+        }
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(err: DbError) -> Self {
+        match err {
+            DbError::DuplicateKey(index) if index == "username_key_unique" => ApiError::UsernameInUse,
+            DbError::DuplicateKey(index) if index == "email_key_unique" => ApiError::EmailInUse,
+            DbError::DuplicateKey(index) if index == "identity_unique" => ApiError::IdentityInUse,
+            DbError::Mongo(_) => {
+                println!("Database request failed: {}", err);
+                ApiError::DatabaseUnavailable
+            }
+            #[cfg(any(feature = "sqlite", feature = "postgres"))]
+            DbError::Sql(_) => {
+                println!("Database request failed: {}", err);
+                ApiError::DatabaseUnavailable
+            }
+            err => {
+                println!("Database request failed: {}", err);
+                ApiError::InternalServerError
+            }
+        }
+    }
+}
+
+impl From<MailboxError> for ApiError {
+    fn from(_: MailboxError) -> Self {
+        ApiError::InternalServerError
+    }
+}
+
+/// The reverse proxies the server runs behind, from the comma separated addresses in
+/// `TRUSTED_PROXIES`. Only their `X-Forwarded-For` headers are believed.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<IpAddr>);
+
+impl TrustedProxies {
+    pub fn from_env() -> TrustedProxies {
+        let proxies = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+        TrustedProxies(
+            proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy.parse().unwrap_or_else(|err| {
+                        panic!("Invalid address {:?} in TRUSTED_PROXIES: {}", proxy, err)
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+}
+
+/// The address of the client: the one it connects from, unless that is a trusted proxy.
+///
+/// Then it is the last address in `X-Forwarded-For` that isn't a trusted proxy, the ones
+/// before it were sent by the client and can be anything.
+pub fn client_ip(
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+    proxies: &TrustedProxies,
+) -> Option<IpAddr> {
+    let mut client = peer?.ip();
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded.into_iter().rev() {
+        if !proxies.contains(&client) {
+            break;
+        }
+        let hop = hop.trim();
+        match hop.parse().or_else(|_| hop.parse().map(|addr: SocketAddr| addr.ip())) {
+            Ok(ip) => client = ip,
+            // Whatever the proxy forwards, it isn't an address to tell clients apart by.
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+/// The session token from the session cookie, or from an `Authorization: Bearer` header
+/// for clients that don't keep cookies.
 pub fn get_session_token(req: &HttpRequest) -> Option<SessionToken> {
-    println!("{:?}", req.headers().get("Cookie"));
+    req.cookie(users::SESSION_COOKIE_NAME)
+        .map(|cookie| cookie.value().to_owned())
+        .filter(|token| !token.is_empty())
+        .map(|token| SessionToken::parse(&token))
+        .or_else(|| get_bearer_token(req).map(SessionToken::parse))
+}
+
+pub fn get_bearer_token(req: &HttpRequest) -> Option<&str> {
     req.headers()
-        .get("Cookie")
-        .map(|s| s.to_str().ok().map(|s| SessionToken::parse(s)))
-        .flatten()
-}
\ No newline at end of file
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn ip(req: &HttpRequest, proxies: &[&str]) -> Option<String> {
+        let proxies = TrustedProxies(proxies.iter().map(|proxy| proxy.parse().unwrap()).collect());
+        client_ip(req.peer_addr(), req.headers(), &proxies).map(|ip| ip.to_string())
+    }
+
+    fn forwarded(peer: &str, forwarded_for: &str) -> HttpRequest {
+        TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .header("x-forwarded-for", forwarded_for)
+            .to_http_request()
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_without_trusted_proxies() {
+        let req = forwarded("203.0.113.7:50000", "198.51.100.1");
+        assert_eq!(ip(&req, &[]), Some(String::from("203.0.113.7")));
+        assert_eq!(ip(&req, &["10.0.0.1"]), Some(String::from("203.0.113.7")));
+        assert_eq!(ip(&TestRequest::default().to_http_request(), &[]), None);
+    }
+
+    #[test]
+    fn trusted_proxies_name_the_address_they_got_the_request_from() {
+        let proxies = ["10.0.0.1", "10.0.0.2"];
+        let req = forwarded("10.0.0.1:50000", "198.51.100.1, 203.0.113.7, 10.0.0.2");
+        assert_eq!(ip(&req, &proxies), Some(String::from("203.0.113.7")));
+
+        let req = forwarded("10.0.0.1:50000", "[2001:db8::1]:443");
+        assert_eq!(ip(&req, &proxies), Some(String::from("2001:db8::1")));
+        let req = forwarded("10.0.0.1:50000", "unknown");
+        assert_eq!(ip(&req, &proxies), Some(String::from("10.0.0.1")));
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:50000".parse().unwrap())
+            .to_http_request();
+        assert_eq!(ip(&req, &proxies), Some(String::from("10.0.0.1")));
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error,
+};
+use dashmap::DashMap;
+use futures::future::{ok, Either, Ready};
+
+use super::{client_ip, ApiError, TrustedProxies};
+use crate::config::env_var;
+
+const REQUESTS_PER_WINDOW_DEFAULT: u32 = 120;
+const WINDOW_SECS_DEFAULT: u64 = 60;
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Requests a client may make per window, further ones get `429 Too Many Requests`.
+    pub requests_per_window: u32,
+    pub window: Duration,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> RateLimitConfig {
+        RateLimitConfig {
+            requests_per_window: env_var("RATE_LIMIT_REQUESTS", REQUESTS_PER_WINDOW_DEFAULT),
+            window: Duration::from_secs(env_var("RATE_LIMIT_WINDOW_SECS", WINDOW_SECS_DEFAULT)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    requests: u32,
+}
+
+/// Middleware limiting the requests per client IP address in fixed windows.
+#[derive(Clone)]
+pub struct RateLimit {
+    config: RateLimitConfig,
+    proxies: TrustedProxies,
+    clients: Arc<DashMap<String, Window>>,
+}
+
+impl RateLimit {
+    pub fn new(config: RateLimitConfig, proxies: TrustedProxies) -> RateLimit {
+        RateLimit {
+            config,
+            proxies,
+            clients: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Counts a request, or returns how long the client has to wait when over the limit.
+    fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut window = self.clients.entry(client.to_string()).or_insert(Window {
+            started: now,
+            requests: 0,
+        });
+        if now.duration_since(window.started) >= self.config.window {
+            window.started = now;
+            window.requests = 0;
+        }
+        if window.requests >= self.config.requests_per_window {
+            return Err(self.config.window - now.duration_since(window.started));
+        }
+        window.requests += 1;
+        Ok(())
+    }
+
+    /// Drops the windows that are over, so clients that left don't pile up.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let window = self.config.window;
+        self.clients
+            .retain(|_, client| now.duration_since(client.started) < window);
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service,
+            limit: self.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limit: RateLimit,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let client = client_ip(req.peer_addr(), req.headers(), &self.limit.proxies)
+            .map(|ip| ip.to_string())
+            .unwrap_or_default();
+        match self.limit.check(&client) {
+            Ok(()) => Either::Left(self.service.call(req)),
+            Err(retry_after) => {
+                Either::Right(ok(req.error_response(ApiError::TooManyRequests(retry_after))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+    fn limit(requests_per_window: u32, window: Duration) -> RateLimit {
+        let config = RateLimitConfig {
+            requests_per_window,
+            window,
+        };
+        RateLimit::new(config, TrustedProxies(vec!["10.0.0.1".parse().unwrap()]))
+    }
+
+    #[test]
+    fn limits_each_client_per_window() {
+        let limit = limit(2, Duration::from_millis(50));
+        assert!(limit.check("203.0.113.7").is_ok());
+        assert!(limit.check("203.0.113.7").is_ok());
+        let wait = limit.check("203.0.113.7").unwrap_err();
+        assert!(wait <= Duration::from_millis(50));
+        assert!(limit.check("198.51.100.1").is_ok());
+
+        std::thread::sleep(Duration::from_millis(60));
+        limit.prune();
+        assert!(limit.clients.is_empty());
+        assert!(limit.check("203.0.113.7").is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn forwarded_for_does_not_reset_the_limit() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limit(2, Duration::from_secs(60)))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |peer: &str, forwarded_for: &str| {
+            test::TestRequest::get()
+                .peer_addr(peer.parse().unwrap())
+                .header("x-forwarded-for", forwarded_for)
+                .to_request()
+        };
+
+        for spoofed in &["198.51.100.1", "198.51.100.2"] {
+            let resp = test::call_service(&mut app, get("203.0.113.7:50000", spoofed)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = test::call_service(&mut app, get("203.0.113.7:50001", "198.51.100.3")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Behind the proxy, only the address it appended counts.
+        for spoofed in &["198.51.100.1", "198.51.100.2"] {
+            let forwarded_for = format!("{}, 192.0.2.5", spoofed);
+            let resp = test::call_service(&mut app, get("10.0.0.1:50000", &forwarded_for)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = test::call_service(&mut app, get("10.0.0.1:50000", "192.0.2.5")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let resp = test::call_service(&mut app, get("10.0.0.1:50000", "192.0.2.6")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
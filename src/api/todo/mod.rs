@@ -2,60 +2,95 @@ use std::sync::Arc;
 
 use actix_web::{web, HttpResponse};
 
-use crate::database::DatabaseManager;
+use crate::api::{users::api_token::ApiScope, AuthenticatedUser};
+use crate::database::{
+    user_todo::{TodoItemId, TodoItemPatch},
+    DatabaseManager,
+};
 use serde::{Deserialize, Serialize};
 
 use super::*;
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.route("/", web::get().to(get_todo))
-        .route("/add", web::post().to(add_to_todo));
+        .route("/add", web::post().to(add_to_todo))
+        .service(
+            web::resource("/{item_id}")
+                .route(web::get().to(get_todo_item))
+                .route(web::patch().to(update_todo_item))
+                .route(web::delete().to(remove_todo_item)),
+        );
 }
 
 #[derive(Deserialize, Debug, Serialize)]
-pub struct UpdateTodo {
-    pub task: String,
+pub struct NewTodoItem {
+    #[serde(alias = "task")]
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
 }
 
-async fn get_todo(req: HttpRequest, db_mgr: web::Data<Arc<DatabaseManager>>) -> HttpResponse {
-    let session_token = get_session_token(&req);
-
-    println!("{:?}", session_token);
-
-    let user_id = match session_token {
-        Some(session_token) => db_mgr
-            .users
-            .get_session_token(session_token)
-            .await
-            .map(|u| u.id),
-        None => None,
-    };
-
-    if let Some(user_id) = user_id {
-        HttpResponse::Ok().json(
-        db_mgr.todo.get_user_todo(user_id).await
-    )}else {
-        ApiResponse::from(ApiError::InternalServerError)
-    }
+async fn get_todo(
+    user: AuthenticatedUser,
+    db_mgr: web::Data<Arc<DatabaseManager>>,
+) -> Result<HttpResponse, ApiError> {
+    user.require(ApiScope::TodoRead)?;
+    Ok(HttpResponse::Ok().json(db_mgr.todo.get_user_todo(user.id).await?))
 }
 
 async fn add_to_todo(
-    req: HttpRequest, 
+    user: AuthenticatedUser,
     db_mgr: web::Data<Arc<DatabaseManager>>,
-    payload: web::Json<UpdateTodo>
-) -> HttpResponse {
-    let session_token = get_session_token(&req);
-    let user_id = match session_token {
-        Some(session_token) => db_mgr
-            .users
-            .get_session_token(session_token)
-            .await
-            .map(|u| u.id),
-        None => None,
-    };
-    db_mgr.todo.add_to_todo(user_id.unwrap(), payload.0.task).await;
-
-    HttpResponse::Ok().json(
-        db_mgr.todo.get_user_todo(user_id.unwrap()).await
-    )
-}
\ No newline at end of file
+    payload: web::Json<NewTodoItem>
+) -> Result<HttpResponse, ApiError> {
+    user.require(ApiScope::TodoWrite)?;
+    let payload = payload.into_inner();
+    let item = db_mgr
+        .todo
+        .add_to_todo(user.id, payload.title, payload.description)
+        .await?
+        .ok_or(ApiError::TodoListNotFound)?;
+    Ok(HttpResponse::Created().json(item))
+}
+
+async fn get_todo_item(
+    user: AuthenticatedUser,
+    db_mgr: web::Data<Arc<DatabaseManager>>,
+    item_id: web::Path<TodoItemId>,
+) -> Result<HttpResponse, ApiError> {
+    user.require(ApiScope::TodoRead)?;
+    let item = db_mgr
+        .todo
+        .get_todo_item(user.id, &item_id)
+        .await?
+        .ok_or(ApiError::TodoItemNotFound)?;
+    Ok(HttpResponse::Ok().json(item))
+}
+
+async fn update_todo_item(
+    user: AuthenticatedUser,
+    db_mgr: web::Data<Arc<DatabaseManager>>,
+    item_id: web::Path<TodoItemId>,
+    payload: web::Json<TodoItemPatch>,
+) -> Result<HttpResponse, ApiError> {
+    user.require(ApiScope::TodoWrite)?;
+    let item = db_mgr
+        .todo
+        .update_todo_item(user.id, &item_id, payload.into_inner())
+        .await?
+        .ok_or(ApiError::TodoItemNotFound)?;
+    Ok(HttpResponse::Ok().json(item))
+}
+
+async fn remove_todo_item(
+    user: AuthenticatedUser,
+    db_mgr: web::Data<Arc<DatabaseManager>>,
+    item_id: web::Path<TodoItemId>,
+) -> Result<HttpResponse, ApiError> {
+    user.require(ApiScope::TodoWrite)?;
+    if db_mgr.todo.remove_todo_item(user.id, &item_id).await? {
+        Ok(HttpResponse::Ok().json(ApiResponse::new("Todo item removed.")))
+    } else {
+        Err(ApiError::TodoItemNotFound)
+    }
+}
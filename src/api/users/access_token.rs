@@ -0,0 +1,281 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::users::{
+        session_token::{NewSession, SessionToken},
+        user::UserId,
+    },
+    config::env_var,
+    crypto,
+    util::unix_timestamp,
+};
+
+const TTL_SECS_DEFAULT: u64 = 60 * 15;
+const ALGORITHM: &str = "HS256";
+/// Key id of the key derived from `SERVER_SECRET`, used when no keys are configured.
+const DERIVED_KEY_ID: &str = "server";
+/// Shorter secrets could be brute forced from any token they signed.
+const MIN_KEY_LEN: usize = 32;
+
+/// A key access tokens are signed or checked with, named by the `kid` in the token header.
+#[derive(Clone)]
+pub struct SigningKey {
+    pub id: String,
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey").field("id", &self.id).finish()
+    }
+}
+
+/// Login returning short-lived signed access tokens, checked without a database lookup.
+///
+/// The session token becomes the refresh token, see `UserStore::rotate_session_token`.
+/// Ending the session doesn't revoke its access tokens, they stay valid for the rest of
+/// their `ttl`. Only the account endpoints check the session, see
+/// `AuthenticatedUser::require_account`.
+#[derive(Clone, Debug)]
+pub struct AccessTokenConfig {
+    pub enabled: bool,
+    pub ttl: Duration,
+    /// The first key signs, all of them verify. To rotate, put a new key in front and drop
+    /// the old one once the tokens it signed have expired.
+    pub keys: Vec<SigningKey>,
+}
+
+impl AccessTokenConfig {
+    /// Keys come from `ACCESS_TOKEN_KEYS` as comma separated `kid:secret` pairs.
+    pub fn from_env(server_secret: &[u8]) -> AccessTokenConfig {
+        let keys = match std::env::var("ACCESS_TOKEN_KEYS") {
+            Ok(keys) if !keys.is_empty() => parse_keys(&keys)
+                .unwrap_or_else(|err| panic!("Invalid ACCESS_TOKEN_KEYS: {}", err)),
+            _ => vec![SigningKey {
+                id: String::from(DERIVED_KEY_ID),
+                key: crypto::hmac_sha256(server_secret, b"access tokens"),
+            }],
+        };
+        AccessTokenConfig {
+            enabled: env_var("ACCESS_TOKENS_ENABLED", false),
+            ttl: Duration::from_secs(env_var("ACCESS_TOKEN_TTL_SECS", TTL_SECS_DEFAULT)),
+            keys,
+        }
+    }
+
+    /// Signs a token for the user and their session as a JWT.
+    pub fn issue(&self, user_id: UserId, session_id: &str) -> String {
+        let key = &self.keys[0];
+        let now = unix_timestamp();
+        let header = JwtHeader {
+            alg: String::from(ALGORITHM),
+            typ: String::from("JWT"),
+            kid: key.id.clone(),
+        };
+        let claims = AccessClaims {
+            sub: user_id,
+            sid: session_id.to_string(),
+            iat: now,
+            exp: now + self.ttl.as_secs() as i64,
+        };
+        let signed = format!(
+            "{}.{}",
+            encode_json(&header),
+            encode_json(&claims)
+        );
+        let signature = crypto::hmac_sha256(&key.key, signed.as_bytes());
+        format!(
+            "{}.{}",
+            signed,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// The claims of a token made by `issue`, if it's signed by a known key and unexpired.
+    pub fn verify(&self, token: &str) -> Option<AccessClaims> {
+        let (signed, signature) = token.rsplit_once('.')?;
+        let (header, claims) = signed.split_once('.')?;
+        let header: JwtHeader = decode_json(header)?;
+        if header.alg != ALGORITHM {
+            return None;
+        }
+        let key = self.keys.iter().find(|key| key.id == header.kid)?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        let expected = crypto::hmac_sha256(&key.key, signed.as_bytes());
+        if !crypto::constant_time_eq(&expected, &signature) {
+            return None;
+        }
+
+        let claims: AccessClaims = decode_json(claims)?;
+        if claims.exp <= unix_timestamp() {
+            None
+        } else {
+            Some(claims)
+        }
+    }
+}
+
+/// Parses `kid:secret` pairs, rejecting keys that are empty, short or named twice.
+///
+/// Errors name the entry but never include a secret, they end up in logs.
+fn parse_keys(keys: &str) -> Result<Vec<SigningKey>, String> {
+    let mut parsed: Vec<SigningKey> = vec![];
+    for (entry, pair) in keys.split(',').enumerate().map(|(i, pair)| (i + 1, pair)) {
+        let (id, key) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("entry {} is not a kid:secret pair", entry))?;
+        let (id, key) = (id.trim(), key.trim());
+        if id.is_empty() {
+            return Err(format!("entry {} has no kid", entry));
+        }
+        if key.len() < MIN_KEY_LEN {
+            return Err(format!(
+                "the secret of kid {:?} is {} bytes, use at least {}",
+                id,
+                key.len(),
+                MIN_KEY_LEN
+            ));
+        }
+        if parsed.iter().any(|key| key.id == id) {
+            return Err(format!("kid {:?} is used twice", id));
+        }
+        parsed.push(SigningKey {
+            id: id.to_string(),
+            key: key.as_bytes().to_vec(),
+        });
+    }
+    Ok(parsed)
+}
+
+/// Whether a bearer token has the shape of an access token rather than a session token.
+pub fn is_access_token(token: &str) -> bool {
+    token.matches('.').count() == 2
+}
+
+fn encode_json<T: Serialize>(value: &T) -> String {
+    base64::encode_config(
+        serde_json::to_vec(value).expect("JWT parts serialize to JSON"),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(part: &str) -> Option<T> {
+    serde_json::from_slice(&base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok()?).ok()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct JwtHeader {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AccessClaims {
+    pub sub: UserId,
+    /// Public id of the session the token was issued for.
+    pub sid: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/// What login and refresh return when access tokens are enabled.
+#[derive(Debug, Serialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub token_type: &'static str,
+    /// Seconds until the access token expires.
+    pub expires_in: u64,
+    /// Exchanged for a new pair at `/api/users/token/refresh`, and single use.
+    pub refresh_token: SessionToken,
+}
+
+impl TokenPair {
+    pub fn new(config: &AccessTokenConfig, session: NewSession) -> TokenPair {
+        TokenPair {
+            access_token: config.issue(session.user_id, &session.session_id),
+            token_type: "Bearer",
+            expires_in: config.ttl.as_secs(),
+            refresh_token: session.token,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(keys: &[(&str, &str)], ttl_secs: u64) -> AccessTokenConfig {
+        AccessTokenConfig {
+            enabled: true,
+            ttl: Duration::from_secs(ttl_secs),
+            keys: keys
+                .iter()
+                .map(|(id, key)| SigningKey {
+                    id: id.to_string(),
+                    key: key.as_bytes().to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn verifies_own_tokens_and_rejects_tampered_ones() {
+        let config = config(&[("a", "key a")], 60);
+        let user_id = UserId::new();
+        let token = config.issue(user_id, "session");
+        assert!(is_access_token(&token));
+        let claims = config.verify(&token).unwrap();
+        assert_eq!((claims.sub, claims.sid.as_str()), (user_id, "session"));
+
+        let forged_claims = encode_json(&AccessClaims {
+            sub: UserId::new(),
+            sid: String::from("session"),
+            iat: 0,
+            exp: i64::MAX,
+        });
+        let mut parts: Vec<_> = token.split('.').map(str::to_string).collect();
+        parts[1] = forged_claims;
+        assert!(config.verify(&parts.join(".")).is_none());
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let config = config(&[("a", "key a")], 0);
+        assert!(config.verify(&config.issue(UserId::new(), "session")).is_none());
+    }
+
+    #[test]
+    fn old_keys_keep_verifying_after_rotation() {
+        let old = config(&[("a", "key a")], 60);
+        let rotated = config(&[("b", "key b"), ("a", "key a")], 60);
+        let retired = config(&[("b", "key b")], 60);
+
+        let token = old.issue(UserId::new(), "session");
+        assert!(rotated.verify(&token).is_some());
+        assert!(retired.verify(&token).is_none());
+        assert!(old.verify(&rotated.issue(UserId::new(), "session")).is_none());
+    }
+
+    #[test]
+    fn configured_keys_must_be_long_and_named() {
+        let long = "k".repeat(MIN_KEY_LEN);
+        let keys = parse_keys(&format!(" new : {} ,old:{}", long, long)).unwrap();
+        let ids: Vec<_> = keys.iter().map(|key| key.id.as_str()).collect();
+        assert_eq!(ids, vec!["new", "old"]);
+        assert_eq!(keys[0].key, long.as_bytes());
+
+        let short = "k".repeat(MIN_KEY_LEN - 1);
+        for (keys, error) in &[
+            (String::from("new"), "entry 1 is not a kid:secret pair"),
+            (format!("new:{},old:", long), "the secret of kid \"old\" is 0 bytes, use at least 32"),
+            (format!("new:{}", short), "the secret of kid \"new\" is 31 bytes, use at least 32"),
+            (format!(":{}", long), "entry 1 has no kid"),
+            (format!("new:{},new:{}", long, long), "kid \"new\" is used twice"),
+        ] {
+            assert_eq!(parse_keys(keys).err().as_deref(), Some(*error));
+        }
+    }
+}
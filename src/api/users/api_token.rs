@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto, util::unix_timestamp};
+
+/// Tells API tokens apart from session tokens in an `Authorization: Bearer` header.
+pub const API_TOKEN_PREFIX: &str = "todo_pat_";
+const API_TOKEN_LEN: usize = 40;
+const API_TOKEN_ID_LEN: usize = 16;
+pub const MAX_API_TOKEN_NAME_LEN: usize = 64;
+pub const MAX_API_TOKENS_PER_USER: i64 = 50;
+
+/// What an API token may do. Sessions may do everything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+    TodoRead,
+    /// Implies `TodoRead`.
+    TodoWrite,
+    /// The own profile, without the email address and without session, password and
+    /// two-factor management.
+    Account,
+}
+
+impl ApiScope {
+    pub fn allows(self, required: ApiScope) -> bool {
+        self == required || (self == ApiScope::TodoWrite && required == ApiScope::TodoRead)
+    }
+}
+
+/// A freshly minted API token, the only time its plain value is seen.
+pub struct ApiToken {
+    pub id: String,
+    pub token: String,
+}
+
+impl ApiToken {
+    pub fn new() -> ApiToken {
+        ApiToken {
+            id: crypto::random_token(API_TOKEN_ID_LEN),
+            token: format!("{}{}", API_TOKEN_PREFIX, crypto::random_token(API_TOKEN_LEN)),
+        }
+    }
+
+    pub fn token_hash(&self) -> String {
+        hash_api_token(&self.token)
+    }
+}
+
+pub fn hash_api_token(token: &str) -> String {
+    crypto::sha256_hex(token.as_bytes())
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(API_TOKEN_PREFIX)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Tokens without expiry stay valid until revoked.
+    #[serde(default)]
+    pub expires_in_days: Option<u32>,
+}
+
+impl NewApiToken {
+    pub fn expires_at(&self) -> Option<i64> {
+        self.expires_in_days
+            .map(|days| unix_timestamp() + i64::from(days) * 60 * 60 * 24)
+    }
+}
+
+/// An API token as listed to its owner.
+#[derive(Clone, Debug, Serialize)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used: Option<i64>,
+}
+
+/// Returned once on creation, with the token to hand to scripts.
+#[derive(Debug, Serialize)]
+pub struct CreatedApiToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiTokenInfo,
+}
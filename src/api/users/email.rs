@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::users::user::UserId,
+    crypto,
+    mail::Mailer,
+    util::unix_timestamp,
+};
+
+const EMAIL_VERIFICATION_TTL_SECS: i64 = 60 * 60 * 24;
+const EMAIL_VERIFICATION_NONCE_LEN: usize = 24;
+
+pub fn is_valid_email(email: &str) -> bool {
+    email.len() <= 254 && email.parse::<lettre::Address>().is_ok()
+}
+
+/// The form email addresses are compared in.
+pub fn email_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Content of the signed link that confirms an email address.
+///
+/// The nonce is also stored on the user and cleared once the link is used,
+/// which makes each link work only once.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmailVerification {
+    pub user_id: UserId,
+    pub email: String,
+    pub nonce: String,
+    pub expires_at: i64,
+}
+
+impl EmailVerification {
+    pub fn new(user_id: UserId, email: String) -> EmailVerification {
+        EmailVerification {
+            user_id,
+            email,
+            nonce: crypto::random_token(EMAIL_VERIFICATION_NONCE_LEN),
+            expires_at: unix_timestamp() + EMAIL_VERIFICATION_TTL_SECS,
+        }
+    }
+
+    pub fn to_token(&self, secret: &[u8]) -> String {
+        crypto::sign(
+            secret,
+            crypto::TokenPurpose::EmailVerification,
+            &serde_json::to_string(self).expect("EmailVerification serializes to JSON"),
+        )
+    }
+
+    /// Parses a token made by `to_token`, rejecting forged and expired ones.
+    pub fn from_token(secret: &[u8], token: &str) -> Option<EmailVerification> {
+        let payload = crypto::verify(secret, crypto::TokenPurpose::EmailVerification, token)?;
+        let verification: EmailVerification = serde_json::from_str(&payload).ok()?;
+        if verification.expires_at < unix_timestamp() {
+            None
+        } else {
+            Some(verification)
+        }
+    }
+
+    pub async fn send(&self, mailer: &Mailer, secret: &[u8]) -> Result<(), ()> {
+        let link = mailer.link(&format!(
+            "/api/users/email/verify?token={}",
+            self.to_token(secret)
+        ));
+        mailer
+            .send(
+                &self.email,
+                "Confirm your email address",
+                format!(
+                    "Open the following link to confirm your email address:\n\n{}\n\n\
+                     The link expires in 24 hours. If you didn't ask for this, ignore this mail.\n",
+                    link
+                ),
+            )
+            .await
+    }
+}
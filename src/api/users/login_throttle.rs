@@ -0,0 +1,229 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::config::env_var;
+
+const FREE_ATTEMPTS_DEFAULT: u32 = 5;
+const BASE_DELAY_SECS_DEFAULT: u64 = 1;
+const MAX_LOCKOUT_SECS_DEFAULT: u64 = 60 * 15;
+
+/// How failed logins are slowed down.
+#[derive(Clone, Debug)]
+pub struct LoginThrottleConfig {
+    /// Failed logins that don't cause any delay.
+    pub free_attempts: u32,
+    /// Lockout after the first failure beyond the free ones, doubling with every further one.
+    pub base_delay: Duration,
+    /// Upper bound of a lockout. Failures are forgotten once this long has passed without one.
+    pub max_lockout: Duration,
+}
+
+impl LoginThrottleConfig {
+    pub fn from_env() -> LoginThrottleConfig {
+        LoginThrottleConfig {
+            free_attempts: env_var("LOGIN_FREE_ATTEMPTS", FREE_ATTEMPTS_DEFAULT),
+            base_delay: Duration::from_secs(env_var(
+                "LOGIN_BASE_DELAY_SECS",
+                BASE_DELAY_SECS_DEFAULT,
+            )),
+            max_lockout: Duration::from_secs(env_var(
+                "LOGIN_MAX_LOCKOUT_SECS",
+                MAX_LOCKOUT_SECS_DEFAULT,
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Instant,
+}
+
+/// Counts failed logins per account and per IP address, locking either out for
+/// exponentially longer after too many failures.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    config: LoginThrottleConfig,
+    accounts: Arc<DashMap<String, Failures>>,
+    ips: Arc<DashMap<String, Failures>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: LoginThrottleConfig) -> LoginThrottle {
+        LoginThrottle {
+            config,
+            accounts: Arc::new(DashMap::new()),
+            ips: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Counts an attempt as failed before it's checked, or returns the time left until the
+    /// account or the IP address may try again.
+    ///
+    /// Counting first keeps concurrent guesses from all passing before any has failed.
+    /// Attempts that succeed are taken back with `record_success` or `release`.
+    pub fn reserve(&self, account: &str, ip: Option<&str>) -> Result<(), Duration> {
+        self.reserve_one(&self.accounts, account)?;
+        if let Some(ip) = ip {
+            if let Err(wait) = self.reserve_one(&self.ips, ip) {
+                self.release_one(&self.accounts, account);
+                return Err(wait);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes back a reserved attempt that didn't fail, like a correct password that still
+    /// needs its second factor.
+    pub fn release(&self, account: &str, ip: Option<&str>) {
+        self.release_one(&self.accounts, account);
+        if let Some(ip) = ip {
+            self.release_one(&self.ips, ip);
+        }
+    }
+
+    /// Forgets the failures of the account and takes back the attempt of the IP address.
+    /// Its other failures stay, they may belong to guesses at other accounts.
+    pub fn record_success(&self, account: &str, ip: Option<&str>) {
+        self.accounts.remove(account);
+        if let Some(ip) = ip {
+            self.release_one(&self.ips, ip);
+        }
+    }
+
+    /// Drops counters that haven't seen a failure for `max_lockout`.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let keep = |_: &String, failures: &mut Failures| !self.is_forgotten(failures, now);
+        self.accounts.retain(keep);
+        self.ips.retain(keep);
+    }
+
+    fn is_forgotten(&self, failures: &Failures, now: Instant) -> bool {
+        now.duration_since(failures.last) >= self.config.max_lockout
+            && now >= failures.locked_until
+    }
+
+    /// Counts an attempt unless the key is locked, holding the entry's lock throughout.
+    fn reserve_one(&self, counters: &DashMap<String, Failures>, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut failures = counters.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: now,
+        });
+        if failures.locked_until > now {
+            return Err(failures.locked_until - now);
+        }
+        if self.is_forgotten(&failures, now) {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        failures.locked_until = now + self.lockout(failures.count);
+        Ok(())
+    }
+
+    fn release_one(&self, counters: &DashMap<String, Failures>, key: &str) {
+        if let Some(mut failures) = counters.get_mut(key) {
+            failures.count = failures.count.saturating_sub(1);
+            failures.locked_until = failures.last + self.lockout(failures.count);
+        }
+    }
+
+    fn lockout(&self, failures: u32) -> Duration {
+        match failures.checked_sub(self.config.free_attempts + 1) {
+            None => Duration::from_secs(0),
+            Some(doublings) => self
+                .config
+                .base_delay
+                .checked_mul(2u32.saturating_pow(doublings.min(31)))
+                .unwrap_or(self.config.max_lockout)
+                .min(self.config.max_lockout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(free_attempts: u32) -> LoginThrottle {
+        LoginThrottle::new(LoginThrottleConfig {
+            free_attempts,
+            base_delay: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_maximum() {
+        let throttle = throttle(2);
+        let lockouts = (1..=7).map(|failures| throttle.lockout(failures).as_secs());
+        assert_eq!(lockouts.collect::<Vec<_>>(), vec![0, 0, 10, 20, 40, 60, 60]);
+        assert_eq!(throttle.lockout(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn locks_the_account_and_the_ip_address() {
+        let throttle = throttle(1);
+        assert!(throttle.reserve("alice", Some("203.0.113.7")).is_ok());
+        assert!(throttle.reserve("alice", Some("203.0.113.7")).is_ok());
+
+        assert!(throttle.reserve("alice", None).is_err());
+        assert!(throttle.reserve("alice", Some("198.51.100.1")).is_err());
+        assert!(throttle.reserve("bob", Some("203.0.113.7")).is_err());
+        assert!(throttle.reserve("bob", Some("198.51.100.1")).is_ok());
+    }
+
+    #[test]
+    fn a_locked_ip_address_takes_back_the_account_attempt() {
+        let throttle = throttle(0);
+        assert!(throttle.reserve("alice", Some("203.0.113.7")).is_ok());
+        assert!(throttle.reserve("bob", Some("203.0.113.7")).is_err());
+        assert_eq!(throttle.accounts.get("bob").unwrap().count, 0);
+        assert!(throttle.reserve("bob", None).is_ok());
+    }
+
+    #[test]
+    fn success_forgets_the_account_but_not_the_ip_address() {
+        let throttle = throttle(1);
+        assert!(throttle.reserve("bob", Some("203.0.113.7")).is_ok());
+        assert!(throttle.reserve("alice", Some("203.0.113.7")).is_ok());
+        throttle.record_success("alice", Some("203.0.113.7"));
+        assert!(!throttle.accounts.contains_key("alice"));
+        assert_eq!(throttle.ips.get("203.0.113.7").unwrap().count, 1);
+
+        assert!(throttle.reserve("carol", Some("203.0.113.7")).is_ok());
+        assert!(throttle.reserve("dave", Some("203.0.113.7")).is_err());
+    }
+
+    #[test]
+    fn release_takes_back_the_attempt() {
+        let throttle = throttle(0);
+        assert!(throttle.reserve("alice", Some("203.0.113.7")).is_ok());
+        throttle.release("alice", Some("203.0.113.7"));
+        assert!(throttle.reserve("alice", Some("203.0.113.7")).is_ok());
+        assert!(throttle.reserve("alice", Some("203.0.113.7")).is_err());
+    }
+
+    #[test]
+    fn prune_keeps_recent_failures() {
+        let throttle = throttle(0);
+        assert!(throttle.reserve("alice", Some("203.0.113.7")).is_ok());
+        throttle.prune();
+        assert_eq!((throttle.accounts.len(), throttle.ips.len()), (1, 1));
+
+        let past = Instant::now() - Duration::from_secs(61);
+        throttle.accounts.get_mut("alice").unwrap().last = past;
+        throttle.accounts.get_mut("alice").unwrap().locked_until = past;
+        throttle.prune();
+        assert_eq!((throttle.accounts.len(), throttle.ips.len()), (0, 1));
+    }
+}
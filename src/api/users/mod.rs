@@ -1,75 +1,797 @@
+pub mod access_token;
+pub mod api_token;
+pub mod email;
+pub mod login_throttle;
+pub mod oidc;
+pub mod password_reset;
+pub mod policy;
+pub mod session_pruner;
 pub mod session_token;
+pub mod totp;
 pub mod user;
 pub mod user_mgr;
 
 use actix::Addr;
-use actix_web::*;
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    *,
+};
 use HttpResponse as HR;
 use super::*;
+use self::{
+    access_token::TokenPair,
+    api_token::NewApiToken,
+    session_token::{ClientInfo, NewSession, SessionConfig, SessionToken},
+};
+use serde::{Deserialize, Serialize};
+use crate::{config::Config, database::DatabaseManager};
+use std::sync::Arc;
+
+pub const SESSION_COOKIE_NAME: &str = "session_token";
+const OIDC_FLOW_COOKIE_NAME: &str = "oidc_flow";
+const OIDC_PATH: &str = "/api/users/oidc";
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.route("/register", web::post().to(register))
         .route("/login", web::post().to(login))
-        .route("/logout", web::post().to(logout));
+        .route("/logout", web::post().to(logout))
+        .route("/token/refresh", web::post().to(refresh_token))
+        .service(
+            web::resource("/me")
+                .route(web::get().to(get_me))
+                .route(web::patch().to(update_me))
+                .route(web::delete().to(delete_me)),
+        )
+        .route("/me/password", web::post().to(change_password))
+        .service(
+            web::resource("/sessions")
+                .route(web::get().to(list_sessions))
+                .route(web::delete().to(revoke_all_sessions)),
+        )
+        .route("/sessions/{id}", web::delete().to(revoke_session))
+        .service(
+            web::resource("/tokens")
+                .route(web::get().to(list_api_tokens))
+                .route(web::post().to(create_api_token)),
+        )
+        .route("/tokens/{id}", web::delete().to(revoke_api_token))
+        .route("/2fa/enroll", web::post().to(enroll_two_factor))
+        .route("/2fa/confirm", web::post().to(confirm_two_factor))
+        .route("/2fa/verify", web::post().to(verify_two_factor))
+        .route("/2fa", web::delete().to(disable_two_factor))
+        .route("/oidc/login", web::get().to(oidc_login))
+        .route("/oidc/link", web::get().to(oidc_link))
+        .route("/oidc/callback", web::get().to(oidc_callback))
+        .route("/email/verify", web::get().to(verify_email))
+        .route("/password/forgot", web::post().to(forgot_password))
+        .service(
+            web::resource("/password/reset")
+                .route(web::get().to(reset_password_page))
+                .route(web::post().to(reset_password)),
+        );
+}
+
+fn session_cookie_builder(value: String) -> cookie::CookieBuilder<'static> {
+    Cookie::build(SESSION_COOKIE_NAME, value)
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .path("/api")
+}
+
+fn session_cookie(session_token: &SessionToken, session_config: &SessionConfig) -> Cookie<'static> {
+    session_cookie_builder(session_token.to_string())
+        .max_age(time::Duration::seconds(
+            session_config.absolute_timeout.as_secs() as i64,
+        ))
+        .finish()
+}
+
+fn removal_session_cookie() -> Cookie<'static> {
+    session_cookie_builder(String::new())
+        .max_age(time::Duration::zero())
+        .finish()
+}
+
+/// Hands a new session to the client, as a cookie along with the token, or as an
+/// access and refresh token pair when access tokens are enabled.
+fn session_response(new_session: NewSession, config: &Config, message: &str) -> HttpResponse {
+    if config.access_tokens.enabled {
+        HR::Ok().json(ApiResponse::with_content(
+            message,
+            TokenPair::new(&config.access_tokens, new_session),
+        ))
+    } else {
+        HR::Ok()
+            .cookie(session_cookie(&new_session.token, &config.session))
+            .json(ApiResponse::with_content(message, new_session.token))
+    }
+}
+
+/// Carries the OIDC flow through the provider. `Lax`, as the provider sends the user
+/// back with a cross-site navigation.
+fn oidc_flow_cookie(value: String) -> Cookie<'static> {
+    Cookie::build(OIDC_FLOW_COOKIE_NAME, value)
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .path(OIDC_PATH)
+        .max_age(time::Duration::minutes(10))
+        .finish()
+}
+
+fn removal_oidc_flow_cookie() -> Cookie<'static> {
+    Cookie::build(OIDC_FLOW_COOKIE_NAME, "")
+        .path(OIDC_PATH)
+        .max_age(time::Duration::zero())
+        .finish()
+}
+
+/// Stored with new sessions so users can tell them apart when listing them.
+fn client_info(req: &HttpRequest) -> ClientInfo {
+    let user_agent = req
+        .headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    let proxies = req
+        .app_data::<web::Data<Arc<Config>>>()
+        .map(|config| config.trusted_proxies.clone())
+        .unwrap_or_default();
+    let ip = client_ip(req.peer_addr(), req.headers(), &proxies).map(|ip| ip.to_string());
+    ClientInfo::new(user_agent, ip.as_deref())
 }
 
 async fn register(
+    req: HttpRequest,
     user_mgr: web::Data<Addr<user_mgr::UserManager>>,
-    payload: web::Form<user_mgr::UserAuth>,
-) -> HttpResponse {
-    match user_mgr
-        .send(user_mgr::msg::Register(payload.into_inner()))
-        .await
-    {
-        Ok(Ok(session_token)) => HR::Ok().cookie(
-            actix_web::cookie::Cookie::build("", session_token.to_string())
-                .http_only(true)
-                .path("/api")
-                .finish(),
-        ).json(ApiResponse::with_content(
-            "Registration successful.",
-            session_token,
-        )),
-        Ok(Err(api_err)) => ApiResponse::from(api_err),
-        Err(_) => ApiResponse::from(ApiError::InternalServerError),
-    }
+    config: web::Data<Arc<Config>>,
+    payload: web::Form<user_mgr::Registration>,
+) -> Result<HttpResponse, ApiError> {
+    let new_session = user_mgr
+        .send(user_mgr::msg::Register(payload.into_inner(), client_info(&req)))
+        .await??;
+    Ok(session_response(new_session, &config, "Registration successful."))
 }
 
 async fn login(
+    req: HttpRequest,
     user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    config: web::Data<Arc<Config>>,
     payload: web::Form<user_mgr::UserAuth>,
-) -> HttpResponse {
-    match user_mgr
-        .send(user_mgr::msg::Login(payload.into_inner()))
-        .await
-    {
-        Ok(Ok(session_token)) => HR::Ok().cookie(
-            actix_web::cookie::Cookie::build("", session_token.to_string())
-                .http_only(true)
-                .path("/api")
-                .finish(),
-        ).json(ApiResponse::with_content(
-            "Login successful.",
-            session_token,
+) -> Result<HttpResponse, ApiError> {
+    let outcome = user_mgr
+        .send(user_mgr::msg::Login(payload.into_inner(), client_info(&req)))
+        .await??;
+    Ok(match outcome {
+        user_mgr::LoginOutcome::Session(new_session) => {
+            session_response(new_session, &config, "Login successful.")
+        }
+        user_mgr::LoginOutcome::TwoFactorRequired(token) => HR::Ok().json(ApiResponse::with_content(
+            "Two-factor code required.",
+            TwoFactorChallenge { two_factor_token: token },
         )),
-        Ok(Err(api_err)) => ApiResponse::from(api_err),
-        Err(_) => ApiResponse::from(ApiError::InternalServerError),
-    }
+    })
 }
 
-async fn logout(
+#[derive(Serialize)]
+struct TwoFactorChallenge {
+    two_factor_token: String,
+}
+
+async fn verify_two_factor(
+    req: HttpRequest,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    config: web::Data<Arc<Config>>,
+    payload: web::Form<user_mgr::TwoFactorLogin>,
+) -> Result<HttpResponse, ApiError> {
+    let new_session = user_mgr
+        .send(user_mgr::msg::VerifyTwoFactor(payload.into_inner(), client_info(&req)))
+        .await??;
+    Ok(session_response(new_session, &config, "Login successful."))
+}
+
+async fn enroll_two_factor(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+) -> Result<HttpResponse, ApiError> {
+    let setup = user_mgr.send(user_mgr::msg::EnrollTwoFactor(user.id)).await??;
+    Ok(HR::Ok().json(ApiResponse::with_content(
+        "Add the secret to your authenticator app, then confirm with a code.",
+        setup,
+    )))
+}
+
+async fn confirm_two_factor(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    payload: web::Json<user_mgr::TwoFactorCode>,
+) -> Result<HttpResponse, ApiError> {
+    let recovery_codes = user_mgr
+        .send(user_mgr::msg::ConfirmTwoFactor {
+            user_id: user.id,
+            code: payload.into_inner(),
+        })
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::with_content(
+        "Two-factor authentication enabled. Keep the recovery codes somewhere safe, they are shown only once.",
+        recovery_codes,
+    )))
+}
+
+async fn disable_two_factor(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    payload: web::Json<user_mgr::DisableTwoFactor>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr
+        .send(user_mgr::msg::DisableTwoFactorRequest {
+            user_id: user.id,
+            request: payload.into_inner(),
+        })
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::new("Two-factor authentication disabled.")))
+}
+
+async fn start_oidc(
+    user_mgr: &Addr<user_mgr::UserManager>,
+    link_user: Option<user::UserId>,
+) -> Result<HttpResponse, ApiError> {
+    let (url, flow_token) = user_mgr.send(user_mgr::msg::StartOidcLogin(link_user)).await??;
+    Ok(HR::Found()
+        .header(http::header::LOCATION, url)
+        .cookie(oidc_flow_cookie(flow_token))
+        .finish())
+}
+
+async fn oidc_login(user_mgr: web::Data<Addr<user_mgr::UserManager>>) -> Result<HttpResponse, ApiError> {
+    start_oidc(&user_mgr, None).await
+}
+
+/// Like `oidc_login`, but links the identity to the logged in user.
+async fn oidc_link(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+) -> Result<HttpResponse, ApiError> {
+    start_oidc(&user_mgr, Some(user.id)).await
+}
+
+#[derive(Deserialize)]
+struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set by the provider instead of `code` when the user cancelled or was denied.
+    error: Option<String>,
+}
+
+async fn oidc_callback(
     req: HttpRequest,
     user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    config: web::Data<Arc<Config>>,
+    query: web::Query<OidcCallbackQuery>,
 ) -> HttpResponse {
-    match get_session_token(&req) {
-        Some(session_token) => match user_mgr.send(user_mgr::msg::Logout(session_token)).await {
-            Ok(Ok(_)) => HR::Ok().json(ApiResponse::new("Logout successful.")),
-            Ok(Err(api_err)) => ApiResponse::from(api_err),
-            _ => ApiResponse::from(ApiError::InternalServerError),
-        },
-        None => ApiResponse::from(ApiError::MissingSessionToken),
+    let query = query.into_inner();
+    let (code, state) = match (query.code, query.state, query.error) {
+        (Some(code), Some(state), None) => (code, state),
+        _ => return ApiError::OidcLoginFailed.error_response(),
+    };
+    let post_login_url = config
+        .oidc
+        .as_ref()
+        .map_or("/", |oidc| oidc.post_login_url.as_str());
+
+    let res = user_mgr
+        .send(user_mgr::msg::FinishOidcLogin {
+            flow_token: req.cookie(OIDC_FLOW_COOKIE_NAME).map(|cookie| cookie.value().to_owned()),
+            code,
+            state,
+            client: client_info(&req),
+        })
+        .await
+        .map_err(ApiError::from)
+        .and_then(|res| res);
+    let mut response = match res {
+        // A redirect can't hand over a token pair, so this always sets the cookie. With
+        // access tokens enabled, clients exchange it at `/token/refresh`.
+        Ok(user_mgr::OidcOutcome::Session(new_session)) => HR::Found()
+            .header(http::header::LOCATION, post_login_url)
+            .cookie(session_cookie(&new_session.token, &config.session))
+            .finish(),
+        Ok(user_mgr::OidcOutcome::Linked) => HR::Found()
+            .header(http::header::LOCATION, post_login_url)
+            .finish(),
+        Err(api_err) => api_err.error_response(),
+    };
+    // The flow is single use, whatever the outcome.
+    let _ = response.add_cookie(&removal_oidc_flow_cookie());
+    response
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: SessionToken,
+}
+
+async fn refresh_token(
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    config: web::Data<Arc<Config>>,
+    payload: web::Form<RefreshRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let new_session = user_mgr
+        .send(user_mgr::msg::RefreshSession(payload.into_inner().refresh_token))
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::with_content(
+        "Tokens refreshed.",
+        TokenPair::new(&config.access_tokens, new_session),
+    )))
+}
+
+async fn logout(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr.send(user_mgr::msg::Logout(user.session_token)).await??;
+    Ok(HR::Ok()
+        .cookie(removal_session_cookie())
+        .json(ApiResponse::new("Logout successful.")))
+}
+
+async fn list_sessions(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+) -> Result<HttpResponse, ApiError> {
+    let sessions = user_mgr
+        .send(user_mgr::msg::ListSessions {
+            user_id: user.id,
+            session_token: user.session_token,
+        })
+        .await??;
+    Ok(HR::Ok().json(sessions))
+}
+
+async fn revoke_session(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    session_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr
+        .send(user_mgr::msg::RevokeSession {
+            user_id: user.id,
+            session_id: session_id.into_inner(),
+        })
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::new("Session logged out.")))
+}
+
+async fn revoke_all_sessions(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr.send(user_mgr::msg::RevokeAllSessions(user.id)).await??;
+    Ok(HR::Ok()
+        .cookie(removal_session_cookie())
+        .json(ApiResponse::new("All sessions were logged out.")))
+}
+
+async fn list_api_tokens(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+) -> Result<HttpResponse, ApiError> {
+    let tokens = user_mgr.send(user_mgr::msg::ListApiTokens(user.id)).await??;
+    Ok(HR::Ok().json(tokens))
+}
+
+async fn create_api_token(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    payload: web::Json<NewApiToken>,
+) -> Result<HttpResponse, ApiError> {
+    let created = user_mgr
+        .send(user_mgr::msg::CreateApiToken {
+            user_id: user.id,
+            request: payload.into_inner(),
+        })
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::with_content(
+        "API token created. Copy it now, it is shown only once.",
+        created,
+    )))
+}
+
+async fn revoke_api_token(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    token_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr
+        .send(user_mgr::msg::RevokeApiToken {
+            user_id: user.id,
+            token_id: token_id.into_inner(),
+        })
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::new("API token revoked.")))
+}
+
+async fn get_me(
+    user: AuthenticatedUser,
+    db_mgr: web::Data<Arc<DatabaseManager>>,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+) -> Result<HttpResponse, ApiError> {
+    user.require_account(&db_mgr).await?;
+    let me = user_mgr.send(user_mgr::msg::GetMe(user.id)).await??;
+    Ok(HR::Ok().json(me))
+}
+
+async fn update_me(
+    user: AuthenticatedUser,
+    db_mgr: web::Data<Arc<DatabaseManager>>,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    payload: web::Json<user_mgr::ProfileUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    user.require_account(&db_mgr).await?;
+    let update = payload.into_inner();
+    if update.email.is_some() {
+        // Password resets go to the new address, so whoever sets it can take the account.
+        user.require_session()?;
     }
+    user_mgr
+        .send(user_mgr::msg::UpdateProfile {
+            user_id: user.id,
+            update,
+        })
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::new("Profile updated.")))
+}
+
+async fn change_password(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    payload: web::Json<user_mgr::PasswordChange>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr
+        .send(user_mgr::msg::ChangePassword {
+            user_id: user.id,
+            session_token: user.session_token,
+            change: payload.into_inner(),
+        })
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::new(
+        "Password changed, all other sessions were logged out.",
+    )))
+}
+
+async fn delete_me(
+    user: SessionUser,
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr.send(user_mgr::msg::DeleteAccount(user.id)).await??;
+    Ok(HR::Ok()
+        .cookie(removal_session_cookie())
+        .json(ApiResponse::new("Account deleted.")))
+}
+
+#[derive(Deserialize)]
+struct VerifyEmailQuery {
+    token: String,
+}
+
+async fn verify_email(
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    query: web::Query<VerifyEmailQuery>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr
+        .send(user_mgr::msg::VerifyEmail(query.into_inner().token))
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::new("Email address verified.")))
+}
+
+async fn forgot_password(
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    payload: web::Form<user_mgr::ForgotPassword>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr
+        .send(user_mgr::msg::ForgotPasswordRequest(payload.into_inner()))
+        .await??;
+    Ok(HR::Ok().json(ApiResponse::new(
+        "If an account with this verified email address exists, a reset link was sent to it.",
+    )))
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordQuery {
+    token: String,
+}
+
+/// The page the mailed reset link opens.
+async fn reset_password_page(query: web::Query<ResetPasswordQuery>) -> HttpResponse {
+    HR::Ok()
+        .content_type("text/html; charset=utf-8")
+        // The token is in the URL, keep it out of requests the page might make.
+        .header(http::header::REFERRER_POLICY, "no-referrer")
+        .body(password_reset::reset_page(&query.token))
 }
 
+async fn reset_password(
+    user_mgr: web::Data<Addr<user_mgr::UserManager>>,
+    payload: web::Form<user_mgr::ResetPassword>,
+) -> Result<HttpResponse, ApiError> {
+    user_mgr
+        .send(user_mgr::msg::ResetPasswordRequest(payload.into_inner()))
+        .await??;
+    Ok(HR::Ok()
+        .cookie(removal_session_cookie())
+        .json(ApiResponse::new("Password changed, all sessions were logged out.")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::users::user::{pw::HashedPassword, BackendUserMe},
+        database::DatabaseManager,
+        mail::{MailTransport, Mailer},
+    };
+    use actix::Actor;
+    use actix_web::test;
+    use lettre::transport::stub::StubTransport;
+
+    /// What `main` serves, without the middleware.
+    fn api(
+        config: Arc<Config>,
+        db: Arc<DatabaseManager>,
+        user_mgr: Addr<user_mgr::UserManager>,
+    ) -> impl FnOnce(&mut web::ServiceConfig) {
+        move |cfg| {
+            cfg.data(config)
+                .data(db)
+                .data(user_mgr)
+                .service(web::scope("/api").configure(crate::api::config));
+        }
+    }
+
+    async fn user_with_session(db: &DatabaseManager, username: &str) -> (BackendUserMe, NewSession) {
+        let user = BackendUserMe::new(
+            username.to_string(),
+            HashedPassword::hash(String::from("old password 1")).await.unwrap(),
+        );
+        db.users.insert(user.clone()).await.unwrap();
+        db.todo.create_user_todo(user.id).await.unwrap();
+        let session = db
+            .users
+            .create_session(&user.id, ClientInfo::default())
+            .await
+            .unwrap()
+            .unwrap();
+        (user, session)
+    }
+
+    fn login(username: &str, password: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/users/login")
+            .set_form(&[("username", username), ("password", password)])
+    }
+
+    #[actix_rt::test]
+    async fn password_reset_logs_out_every_session() {
+        let config = Arc::new(Config::from_env());
+        let db = Arc::new(DatabaseManager::in_memory(&config));
+        let mut user = BackendUserMe::new(
+            String::from("alice"),
+            HashedPassword::hash(String::from("old password 1")).await.unwrap(),
+        );
+        user.email = Some(String::from("alice@example.com"));
+        user.email_verified = true;
+        db.users.insert(user.clone()).await.unwrap();
+        let session = db
+            .users
+            .create_session(&user.id, ClientInfo::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let stub = StubTransport::new_ok();
+        let mailer = Mailer::new(
+            "TODO <noreply@localhost>".parse().unwrap(),
+            MailTransport::Stub(stub.clone()),
+            String::from("https://todo.example"),
+        );
+        let user_mgr = user_mgr::UserManager::new(db.clone(), config.clone(), mailer).start();
+        let mut app = test::init_service(App::new().configure(api(config, db, user_mgr))).await;
+        let me = |token: &SessionToken| {
+            test::TestRequest::get()
+                .uri("/api/users/me")
+                .cookie(Cookie::new(SESSION_COOKIE_NAME, token.to_string()))
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, me(&session.token)).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/api/users/password/forgot")
+            .set_form(&[("email", "alice@example.com")])
+            .to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::OK);
+        // The mail is sent after the reply.
+        let mut mails = stub.messages();
+        for _ in 0..100 {
+            if !mails.is_empty() {
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+            mails = stub.messages();
+        }
+        assert_eq!(mails.len(), 1);
+        let mail = mails[0].1.replace("=\r\n", "").replace("=3D", "=");
+        let link_start = mail.find("https://todo.example/").unwrap();
+        let path: String = mail[link_start + "https://todo.example".len()..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || "/?=".contains(*c))
+            .collect();
+        let token = path.rsplit('=').next().unwrap().to_string();
 
+        let req = test::TestRequest::get().uri(&path).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let page = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&page).unwrap().contains(&token));
 
+        let reset = |password: &str| {
+            test::TestRequest::post()
+                .uri(password_reset::PASSWORD_RESET_PATH)
+                .set_form(&[("token", token.as_str()), ("password", password)])
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, reset("new password 2")).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let resp = test::call_service(&mut app, reset("another password 3")).await;
+        assert!(resp.status().is_client_error());
+
+        let resp = test::call_service(&mut app, me(&session.token)).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&mut app, login("alice", "old password 1").to_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+        let resp = test::call_service(&mut app, login("alice", "new password 2").to_request()).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn forwarded_for_does_not_reset_the_login_throttle() {
+        let mut config = Config::from_env();
+        config.login_throttle = login_throttle::LoginThrottleConfig {
+            free_attempts: 2,
+            base_delay: std::time::Duration::from_secs(60),
+            max_lockout: std::time::Duration::from_secs(60),
+        };
+        let config = Arc::new(config);
+        let db = Arc::new(DatabaseManager::in_memory(&config));
+        let user_mgr =
+            user_mgr::UserManager::new(db.clone(), config.clone(), Mailer::stub()).start();
+        let mut app = test::init_service(App::new().configure(api(config, db, user_mgr))).await;
+
+        // Guesses at different accounts, each claiming to be forwarded for someone else.
+        for attempt in 0..4 {
+            let req = login(&format!("user{}", attempt), "wrong password 1")
+                .peer_addr("203.0.113.7:50000".parse().unwrap())
+                .header("x-forwarded-for", format!("198.51.100.{}", attempt))
+                .to_request();
+            let status = test::call_service(&mut app, req).await.status();
+            if attempt < 3 {
+                assert_eq!(status, http::StatusCode::FORBIDDEN);
+            } else {
+                assert_eq!(status, http::StatusCode::TOO_MANY_REQUESTS);
+            }
+        }
+        let req = login("user4", "wrong password 1")
+            .peer_addr("192.0.2.5:50000".parse().unwrap())
+            .to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn api_tokens_may_not_change_the_email_address() {
+        let config = Arc::new(Config::from_env());
+        let db = Arc::new(DatabaseManager::in_memory(&config));
+        let (user, session) = user_with_session(&db, "alice").await;
+        let user_mgr =
+            user_mgr::UserManager::new(db.clone(), config.clone(), Mailer::stub()).start();
+        let mut app =
+            test::init_service(App::new().configure(api(config, db.clone(), user_mgr))).await;
+        let cookie = Cookie::new(SESSION_COOKIE_NAME, session.token.to_string());
+
+        let mut tokens = vec![];
+        for scope in &["account", "todo_read"] {
+            let req = test::TestRequest::post()
+                .uri("/api/users/tokens")
+                .cookie(cookie.clone())
+                .set_json(&serde_json::json!({ "name": scope, "scopes": [scope] }))
+                .to_request();
+            let resp: serde_json::Value = test::read_response_json(&mut app, req).await;
+            tokens.push(format!("Bearer {}", resp["content"]["token"].as_str().unwrap()));
+        }
+        let (account, todo_read) = (&tokens[0], &tokens[1]);
+        let update_me = |authorization: &str, update: serde_json::Value| {
+            test::TestRequest::patch()
+                .uri("/api/users/me")
+                .header(http::header::AUTHORIZATION, authorization)
+                .set_json(&update)
+                .to_request()
+        };
+
+        let req = update_me(account, serde_json::json!({ "email": "mallory@example.com" }));
+        assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::FORBIDDEN);
+        let req = update_me(todo_read, serde_json::json!({ "username": "mallory" }));
+        assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::FORBIDDEN);
+        assert_eq!(db.users.get_id(&user.id).await.unwrap().unwrap().email, None);
+
+        let req = update_me(account, serde_json::json!({ "username": "alice2" }));
+        assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::OK);
+        let req = test::TestRequest::patch()
+            .uri("/api/users/me")
+            .cookie(cookie.clone())
+            .set_json(&serde_json::json!({ "email": "alice@example.com" }))
+            .to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::OK);
+        let stored = db.users.get_id(&user.id).await.unwrap().unwrap();
+        assert_eq!(stored.username, "alice2");
+        assert_eq!(stored.email.as_deref(), Some("alice@example.com"));
+    }
+
+    #[actix_rt::test]
+    async fn access_tokens_outlive_their_session_except_for_the_account() {
+        let mut config = Config::from_env();
+        config.access_tokens.enabled = true;
+        let config = Arc::new(config);
+        let db = Arc::new(DatabaseManager::in_memory(&config));
+        let (user, _) = user_with_session(&db, "alice").await;
+        let user_mgr =
+            user_mgr::UserManager::new(db.clone(), config.clone(), Mailer::stub()).start();
+        let mut app =
+            test::init_service(App::new().configure(api(config, db.clone(), user_mgr))).await;
+
+        let resp: serde_json::Value =
+            test::read_response_json(&mut app, login("alice", "old password 1").to_request()).await;
+        let bearer = |token: &serde_json::Value| format!("Bearer {}", token.as_str().unwrap());
+        let access_token = bearer(&resp["content"]["access_token"]);
+        let refresh_token = bearer(&resp["content"]["refresh_token"]);
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .header(http::header::AUTHORIZATION, access_token.as_str())
+                .to_request()
+        };
+        for uri in &["/api/todo/", "/api/users/me"] {
+            assert_eq!(test::call_service(&mut app, get(uri)).await.status(), http::StatusCode::OK);
+        }
+        let req = test::TestRequest::patch()
+            .uri("/api/users/me")
+            .header(http::header::AUTHORIZATION, access_token.as_str())
+            .set_json(&serde_json::json!({ "email": "mallory@example.com" }))
+            .to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/api/users/logout")
+            .header(http::header::AUTHORIZATION, refresh_token)
+            .to_request();
+        assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::OK);
+        // Not looked up per request, the token stays valid for the todo list until it expires.
+        let resp = test::call_service(&mut app, get("/api/todo/")).await;
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let resp = test::call_service(&mut app, get("/api/users/me")).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+        // Nor does it bring back the list of a removed account.
+        let add = || {
+            test::TestRequest::post()
+                .uri("/api/todo/add")
+                .header(http::header::AUTHORIZATION, access_token.as_str())
+                .set_json(&serde_json::json!({ "title": "milk" }))
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, add()).await;
+        assert_eq!(resp.status(), http::StatusCode::CREATED);
+        db.remove_user(user.id).await.unwrap();
+        let resp = test::call_service(&mut app, add()).await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+        assert!(db.todo.get_user_todo(user.id).await.unwrap().list.is_empty());
+    }
+}
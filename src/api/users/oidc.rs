@@ -0,0 +1,645 @@
+use std::{fmt, sync::RwLock, time::Duration};
+
+use actix_web::client::Client;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{api::users::user::UserId, config::env_var, crypto, util::unix_timestamp};
+
+const SCOPES_DEFAULT: &str = "openid email profile";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a user may take at the provider before the login has to start over.
+const FLOW_TTL_SECS: i64 = 60 * 10;
+/// Allowed difference between our clock and the provider's.
+const CLOCK_LEEWAY_SECS: i64 = 60;
+const STATE_LEN: usize = 32;
+const NONCE_LEN: usize = 32;
+const CODE_VERIFIER_LEN: usize = 64;
+
+/// The identity provider to log in with, enabled by setting `OIDC_ISSUER`.
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    /// Issuer URL, the discovery document is read from below it.
+    pub issuer: String,
+    pub client_id: String,
+    /// Sent with the token request for confidential clients, public clients rely on PKCE alone.
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back to, the callback route of this server.
+    pub redirect_url: String,
+    pub scopes: String,
+    /// Where users land after logging in.
+    pub post_login_url: String,
+    /// Whether an unknown identity gets a new account, or is turned away.
+    pub auto_register: bool,
+}
+
+impl OidcConfig {
+    pub fn from_env() -> Option<OidcConfig> {
+        let issuer = std::env::var("OIDC_ISSUER").ok().filter(|issuer| !issuer.is_empty())?;
+        Some(OidcConfig {
+            issuer,
+            client_id: std::env::var("OIDC_CLIENT_ID").expect("OIDC_ISSUER needs OIDC_CLIENT_ID"),
+            client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_url: std::env::var("OIDC_REDIRECT_URL")
+                .expect("OIDC_ISSUER needs OIDC_REDIRECT_URL"),
+            scopes: env_var("OIDC_SCOPES", String::from(SCOPES_DEFAULT)),
+            post_login_url: env_var("OIDC_POST_LOGIN_URL", String::from("/")),
+            auto_register: env_var("OIDC_AUTO_REGISTER", true),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    Http(String),
+    Discovery(String),
+    /// The flow cookie is missing, forged, expired or doesn't match the callback.
+    InvalidFlow,
+    Provider(String),
+    InvalidIdToken(&'static str),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Http(err) => write!(f, "request to the provider failed: {}", err),
+            OidcError::Discovery(err) => write!(f, "discovery failed: {}", err),
+            OidcError::InvalidFlow => f.write_str("login flow invalid or expired"),
+            OidcError::Provider(err) => write!(f, "the provider returned an error: {}", err),
+            OidcError::InvalidIdToken(reason) => write!(f, "ID token rejected: {}", reason),
+        }
+    }
+}
+
+/// The parts of the discovery document that are used.
+#[derive(Clone, Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default, rename = "use")]
+    usage: Option<String>,
+    // RSA
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    // EC
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+impl Jwk {
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> bool {
+        match (alg, self.kty.as_str()) {
+            ("RS256", "RSA") => {
+                let (n, e) = match (decode_b64(&self.n), decode_b64(&self.e)) {
+                    (Some(n), Some(e)) => (n, e),
+                    _ => return false,
+                };
+                RsaPublicKeyComponents {
+                    n: strip_leading_zeros(&n),
+                    e: strip_leading_zeros(&e),
+                }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok()
+            }
+            ("ES256", "EC") if self.crv.as_deref() == Some("P-256") => {
+                let (x, y) = match (decode_b64(&self.x), decode_b64(&self.y)) {
+                    (Some(x), Some(y)) => (x, y),
+                    _ => return false,
+                };
+                let mut point = vec![0x04];
+                point.extend(x);
+                point.extend(y);
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+fn decode_b64(value: &Option<String>) -> Option<Vec<u8>> {
+    base64::decode_config(value.as_ref()?, base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn strip_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    id_token: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    #[serde(default)]
+    azp: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+    #[serde(default)]
+    preferred_username: Option<String>,
+}
+
+/// An account at a provider, linked to a user so it can log in as them.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExternalIdentity {
+    pub issuer: String,
+    pub subject: String,
+}
+
+/// A user as vouched for by the provider.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExternalUser {
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+}
+
+impl ExternalUser {
+    pub fn identity(&self) -> ExternalIdentity {
+        ExternalIdentity {
+            issuer: self.issuer.clone(),
+            subject: self.subject.clone(),
+        }
+    }
+}
+
+/// What has to survive the trip to the provider, kept in a signed cookie.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OidcFlow {
+    oidc_state: String,
+    nonce: String,
+    code_verifier: String,
+    /// Set when a logged in user links the identity to their account instead of logging in.
+    pub link_user: Option<UserId>,
+    expires_at: i64,
+}
+
+impl OidcFlow {
+    pub fn to_token(&self, secret: &[u8]) -> String {
+        crypto::sign(
+            secret,
+            crypto::TokenPurpose::OidcFlow,
+            &serde_json::to_string(self).expect("OidcFlow serializes to JSON"),
+        )
+    }
+
+    pub fn from_token(secret: &[u8], token: &str) -> Option<OidcFlow> {
+        let payload = crypto::verify(secret, crypto::TokenPurpose::OidcFlow, token)?;
+        let flow: OidcFlow = serde_json::from_str(&payload).ok()?;
+        if flow.expires_at < unix_timestamp() {
+            None
+        } else {
+            Some(flow)
+        }
+    }
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(code_verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Logs users in through an OpenID Connect provider, with the authorization code flow
+/// and PKCE. The provider is found through discovery and its ID tokens are checked
+/// against its JWKS. Which user an identity belongs to is up to `user_mgr`.
+pub struct OidcClient {
+    config: OidcConfig,
+    /// Fetched on first use, providers don't move their endpoints while running.
+    metadata: RwLock<Option<ProviderMetadata>>,
+    /// Refetched when a token is signed with an unknown key, for key rotation.
+    jwks: RwLock<Vec<Jwk>>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> OidcClient {
+        OidcClient {
+            config,
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(vec![]),
+        }
+    }
+
+    pub fn config(&self) -> &OidcConfig {
+        &self.config
+    }
+
+    async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, OidcError> {
+        Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .finish()
+            .get(url)
+            .send()
+            .await
+            .map_err(|err| OidcError::Http(err.to_string()))?
+            .json()
+            .await
+            .map_err(|err| OidcError::Http(err.to_string()))
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata, OidcError> {
+        if let Some(metadata) = self.metadata.read().unwrap().clone() {
+            return Ok(metadata);
+        }
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = Self::get_json(&url).await?;
+        if metadata.issuer != self.config.issuer {
+            return Err(OidcError::Discovery(format!(
+                "issuer '{}' doesn't match the configured '{}'",
+                metadata.issuer, self.config.issuer
+            )));
+        }
+        *self.metadata.write().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn key(&self, kid: Option<&str>, alg: &str) -> Result<Jwk, OidcError> {
+        let find = |keys: &[Jwk]| {
+            keys.iter()
+                .filter(|key| key.usage.as_deref().is_none_or(|usage| usage == "sig"))
+                .filter(|key| kid.is_none() || key.kid.as_deref() == kid)
+                .find(|key| matches!((alg, key.kty.as_str()), ("RS256", "RSA") | ("ES256", "EC")))
+                .cloned()
+        };
+        if let Some(key) = find(&self.jwks.read().unwrap()) {
+            return Ok(key);
+        }
+        let jwks: Jwks = Self::get_json(&self.metadata().await?.jwks_uri).await?;
+        let key = find(&jwks.keys);
+        *self.jwks.write().unwrap() = jwks.keys;
+        key.ok_or(OidcError::InvalidIdToken("signed with an unknown key"))
+    }
+
+    /// The URL to send the user to, and the flow to keep until they come back.
+    pub async fn authorization_request(
+        &self,
+        link_user: Option<UserId>,
+    ) -> Result<(String, OidcFlow), OidcError> {
+        let metadata = self.metadata().await?;
+        let flow = OidcFlow {
+            oidc_state: crypto::random_token(STATE_LEN),
+            nonce: crypto::random_token(NONCE_LEN),
+            code_verifier: crypto::random_token(CODE_VERIFIER_LEN),
+            link_user,
+            expires_at: unix_timestamp() + FLOW_TTL_SECS,
+        };
+        let mut url = Url::parse(&metadata.authorization_endpoint)
+            .map_err(|err| OidcError::Discovery(err.to_string()))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", &flow.oidc_state)
+            .append_pair("nonce", &flow.nonce)
+            .append_pair("code_challenge", &code_challenge(&flow.code_verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok((url.into(), flow))
+    }
+
+    /// Redeems the code the provider sent the user back with and checks the ID token.
+    pub async fn finish(&self, flow: &OidcFlow, code: &str, state: &str) -> Result<ExternalUser, OidcError> {
+        if !crypto::constant_time_eq(flow.oidc_state.as_bytes(), state.as_bytes()) {
+            return Err(OidcError::InvalidFlow);
+        }
+        let metadata = self.metadata().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &flow.code_verifier),
+        ];
+        if let Some(client_secret) = &self.config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let response: TokenResponse = Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .finish()
+            .post(&metadata.token_endpoint)
+            .send_form(&form)
+            .await
+            .map_err(|err| OidcError::Http(err.to_string()))?
+            .json()
+            .await
+            .map_err(|err| OidcError::Http(err.to_string()))?;
+        if let Some(error) = response.error {
+            return Err(OidcError::Provider(error));
+        }
+        let id_token = response
+            .id_token
+            .ok_or(OidcError::InvalidIdToken("missing from the token response"))?;
+
+        let claims = self.validate_id_token(&metadata, &id_token, &flow.nonce).await?;
+        Ok(ExternalUser {
+            issuer: claims.iss,
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified.unwrap_or(false),
+            preferred_username: claims.preferred_username,
+        })
+    }
+
+    async fn validate_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let malformed = OidcError::InvalidIdToken("malformed");
+        let mut parts = id_token.split('.');
+        let (header, payload, sig) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(sig), None) => (header, payload, sig),
+            _ => return Err(malformed),
+        };
+        let decode = |part: &str| base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok();
+        let (header_json, payload_json, sig) = match (decode(header), decode(payload), decode(sig)) {
+            (Some(header), Some(payload), Some(sig)) => (header, payload, sig),
+            _ => return Err(malformed),
+        };
+        let signed = &id_token[..header.len() + 1 + payload.len()];
+        let header: JwtHeader = serde_json::from_slice(&header_json).map_err(|_| malformed)?;
+
+        let key = self.key(header.kid.as_deref(), &header.alg).await?;
+        if !key.verify(&header.alg, signed.as_bytes(), &sig) {
+            return Err(OidcError::InvalidIdToken("bad signature"));
+        }
+
+        let claims: IdTokenClaims = serde_json::from_slice(&payload_json)
+            .map_err(|_| OidcError::InvalidIdToken("claims missing"))?;
+        if claims.iss != metadata.issuer {
+            return Err(OidcError::InvalidIdToken("wrong issuer"));
+        }
+        let client_id = &self.config.client_id;
+        let audience_ok = match &claims.aud {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => {
+                auds.contains(client_id)
+                    && (auds.len() == 1 || claims.azp.as_ref() == Some(client_id))
+            }
+        };
+        if !audience_ok {
+            return Err(OidcError::InvalidIdToken("wrong audience"));
+        }
+        if claims.exp + CLOCK_LEEWAY_SECS < unix_timestamp() {
+            return Err(OidcError::InvalidIdToken("expired"));
+        }
+        if !claims
+            .nonce
+            .as_deref()
+            .is_some_and(|claimed| crypto::constant_time_eq(claimed.as_bytes(), nonce.as_bytes()))
+        {
+            return Err(OidcError::InvalidIdToken("wrong nonce"));
+        }
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+    const CLIENT_ID: &str = "todo-test";
+
+    /// What the mock provider learned from the authorization request.
+    #[derive(Default)]
+    struct MockState {
+        issuer: String,
+        code_challenge: String,
+        nonce: String,
+        /// Claims to put into the next ID token, on top of the defaults.
+        overrides: serde_json::Map<String, serde_json::Value>,
+    }
+
+    struct MockIdp {
+        state: Arc<Mutex<MockState>>,
+        server: test::TestServer,
+    }
+
+    fn b64(data: &[u8]) -> String {
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn start_mock_idp() -> MockIdp {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = Arc::new(EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap());
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let server_state = state.clone();
+        let server = test::start(move || {
+            let state = server_state.clone();
+            let key = key.clone();
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to({
+                        let state = state.clone();
+                        move || {
+                            let issuer = state.lock().unwrap().issuer.clone();
+                            HttpResponse::Ok().json(serde_json::json!({
+                                "issuer": issuer,
+                                "authorization_endpoint": format!("{}/authorize", issuer),
+                                "token_endpoint": format!("{}/token", issuer),
+                                "jwks_uri": format!("{}/jwks", issuer),
+                            }))
+                        }
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to({
+                        let key = key.clone();
+                        move || {
+                            let point = key.public_key().as_ref();
+                            HttpResponse::Ok().json(serde_json::json!({ "keys": [{
+                                "kty": "EC", "crv": "P-256", "kid": "test", "use": "sig",
+                                "x": b64(&point[1..33]), "y": b64(&point[33..65]),
+                            }] }))
+                        }
+                    }),
+                )
+                .route(
+                    "/token",
+                    web::post().to(move |form: web::Form<HashMap<String, String>>| {
+                        let state = state.lock().unwrap();
+                        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+                        if form.get("code").map(String::as_str) != Some("good-code")
+                            || code_challenge(&verifier) != state.code_challenge
+                        {
+                            return HttpResponse::BadRequest().json(serde_json::json!({ "error": "invalid_grant" }));
+                        }
+                        let mut claims = serde_json::json!({
+                            "iss": state.issuer, "sub": "alice-123", "aud": CLIENT_ID,
+                            "exp": unix_timestamp() + 300, "iat": unix_timestamp(),
+                            "nonce": state.nonce, "email": "alice@example.com",
+                            "email_verified": true, "preferred_username": "alice",
+                        });
+                        for (claim, value) in &state.overrides {
+                            claims[claim] = value.clone();
+                        }
+                        let signed = format!(
+                            "{}.{}",
+                            b64(br#"{"alg":"ES256","kid":"test"}"#),
+                            b64(claims.to_string().as_bytes())
+                        );
+                        let sig = key.sign(&SystemRandom::new(), signed.as_bytes()).unwrap();
+                        let id_token = format!("{}.{}", signed, b64(sig.as_ref()));
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "access_token": "unused", "token_type": "Bearer", "id_token": id_token,
+                        }))
+                    }),
+                )
+        });
+        state.lock().unwrap().issuer = server.url("").trim_end_matches('/').to_string();
+        MockIdp { state, server }
+    }
+
+    fn client_for(idp: &MockIdp) -> OidcClient {
+        OidcClient::new(OidcConfig {
+            issuer: idp.state.lock().unwrap().issuer.clone(),
+            client_id: String::from(CLIENT_ID),
+            client_secret: None,
+            redirect_url: String::from("https://todo.example/api/users/oidc/callback"),
+            scopes: String::from(SCOPES_DEFAULT),
+            post_login_url: String::from("/"),
+            auto_register: true,
+        })
+    }
+
+    /// Plays the browser: starts the flow and lets the provider learn the PKCE challenge and nonce.
+    async fn authorize(idp: &MockIdp, client: &OidcClient) -> (OidcFlow, String) {
+        let (url, flow) = client.authorization_request(None).await.unwrap();
+        let url = Url::parse(&url).unwrap();
+        assert!(url.as_str().starts_with(&format!("{}/authorize?", idp.server.url("").trim_end_matches('/'))));
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert_eq!(query["client_id"], CLIENT_ID);
+
+        let mut state = idp.state.lock().unwrap();
+        state.code_challenge = query["code_challenge"].clone();
+        state.nonce = query["nonce"].clone();
+        (flow, query["state"].clone())
+    }
+
+    #[actix_rt::test]
+    async fn logs_in_through_mock_provider() {
+        let idp = start_mock_idp();
+        let client = client_for(&idp);
+        let (flow, state) = authorize(&idp, &client).await;
+
+        let user = client.finish(&flow, "good-code", &state).await.unwrap();
+        assert_eq!(user.subject, "alice-123");
+        assert_eq!(user.issuer, idp.state.lock().unwrap().issuer);
+        assert_eq!(user.email.as_deref(), Some("alice@example.com"));
+        assert!(user.email_verified);
+        assert_eq!(user.preferred_username.as_deref(), Some("alice"));
+    }
+
+    #[actix_rt::test]
+    async fn rejects_wrong_state_and_code() {
+        let idp = start_mock_idp();
+        let client = client_for(&idp);
+        let (flow, state) = authorize(&idp, &client).await;
+
+        assert!(matches!(
+            client.finish(&flow, "good-code", "forged").await,
+            Err(OidcError::InvalidFlow)
+        ));
+        assert!(client.finish(&flow, "bad-code", &state).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn rejects_id_tokens_with_wrong_claims() {
+        let idp = start_mock_idp();
+        let client = client_for(&idp);
+        for (claim, value) in [
+            ("aud", serde_json::json!("someone-else")),
+            ("iss", serde_json::json!("https://evil.example")),
+            ("nonce", serde_json::json!("replayed")),
+            ("exp", serde_json::json!(unix_timestamp() - 3600)),
+        ] {
+            let (flow, state) = authorize(&idp, &client).await;
+            idp.state.lock().unwrap().overrides = vec![(claim.to_string(), value)].into_iter().collect();
+            assert!(
+                matches!(client.finish(&flow, "good-code", &state).await, Err(OidcError::InvalidIdToken(_))),
+                "accepted a token with a bad '{}'",
+                claim
+            );
+        }
+    }
+
+    #[test]
+    fn flow_tokens_round_trip_and_reject_other_keys() {
+        let flow = OidcFlow {
+            oidc_state: String::from("state"),
+            nonce: String::from("nonce"),
+            code_verifier: String::from("verifier"),
+            link_user: None,
+            expires_at: unix_timestamp() + 60,
+        };
+        let token = flow.to_token(b"secret");
+        assert!(OidcFlow::from_token(b"secret", &token).is_some());
+        assert!(OidcFlow::from_token(b"other", &token).is_none());
+    }
+}
@@ -0,0 +1,129 @@
+use crate::{crypto, mail::Mailer, util::unix_timestamp};
+
+const PASSWORD_RESET_TTL_SECS: i64 = 60 * 60;
+const PASSWORD_RESET_TOKEN_LEN: usize = 40;
+/// Served with a form for the new password, which posts to the same path.
+pub const PASSWORD_RESET_PATH: &str = "/api/users/password/reset";
+
+/// A password reset token, mailed to the user in plain and stored only as its hash.
+pub struct PasswordReset {
+    pub token: String,
+    pub expires_at: i64,
+}
+
+impl PasswordReset {
+    pub fn new() -> PasswordReset {
+        PasswordReset {
+            token: crypto::random_token(PASSWORD_RESET_TOKEN_LEN),
+            expires_at: unix_timestamp() + PASSWORD_RESET_TTL_SECS,
+        }
+    }
+
+    pub fn token_hash(&self) -> String {
+        hash_token(&self.token)
+    }
+
+    pub async fn send(&self, mailer: &Mailer, email: &str) -> Result<(), ()> {
+        let link = mailer.link(&format!("{}?token={}", PASSWORD_RESET_PATH, self.token));
+        mailer
+            .send(
+                email,
+                "Reset your password",
+                format!(
+                    "Someone asked to reset the password of your account. Open the following \
+                     link to choose a new one:\n\n{}\n\nThe link expires in one hour. If you \
+                     didn't ask for this, ignore this mail, your password stays unchanged.\n",
+                    link
+                ),
+            )
+            .await
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    crypto::sha256_hex(token.as_bytes())
+}
+
+/// The page the mailed link opens: a form for the new password, posted with the token.
+pub fn reset_page(token: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n\
+         <html><head><meta charset=\"utf-8\"><title>Reset your password</title></head><body>\n\
+         <form method=\"post\" action=\"{}\">\n\
+         <input type=\"hidden\" name=\"token\" value=\"{}\">\n\
+         <label>New password <input type=\"password\" name=\"password\" autocomplete=\"new-password\" required></label>\n\
+         <button type=\"submit\">Reset password</button>\n\
+         </form>\n\
+         </body></html>\n",
+        PASSWORD_RESET_PATH,
+        escape_html(token)
+    )
+}
+
+/// The token comes from the query string, anything could be in it.
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => String::from("&amp;"),
+            '<' => String::from("&lt;"),
+            '>' => String::from("&gt;"),
+            '"' => String::from("&quot;"),
+            '\'' => String::from("&#39;"),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mail::MailTransport;
+    use lettre::transport::stub::StubTransport;
+
+    #[actix_rt::test]
+    async fn reset_mail_contains_token_but_hash_does_not() {
+        let stub = StubTransport::new_ok();
+        let mailer = Mailer::new(
+            "TODO <noreply@localhost>".parse().unwrap(),
+            MailTransport::Stub(stub.clone()),
+            String::from("https://todo.example/"),
+        );
+        let reset = PasswordReset::new();
+
+        reset.send(&mailer, "alice@example.com").await.unwrap();
+
+        let messages = stub.messages();
+        assert_eq!(messages.len(), 1);
+        let (envelope, mail) = &messages[0];
+        // Undo the quoted-printable soft line breaks and escapes of the body.
+        let mail = mail.replace("=\r\n", "").replace("=3D", "=");
+        assert_eq!(envelope.to()[0].to_string(), "alice@example.com");
+        assert!(mail.contains(&format!(
+            "https://todo.example/api/users/password/reset?token={}",
+            reset.token
+        )));
+        assert!(!mail.contains(&reset.token_hash()));
+        assert_eq!(reset.token_hash(), hash_token(&reset.token));
+    }
+
+    #[actix_rt::test]
+    async fn reset_mail_to_invalid_address_fails() {
+        let stub = StubTransport::new_ok();
+        let mailer = Mailer::new(
+            "TODO <noreply@localhost>".parse().unwrap(),
+            MailTransport::Stub(stub.clone()),
+            String::from("https://todo.example"),
+        );
+
+        assert!(PasswordReset::new().send(&mailer, "not an address").await.is_err());
+        assert!(stub.messages().is_empty());
+    }
+
+    #[test]
+    fn reset_page_escapes_the_token() {
+        let page = reset_page("\"><script>alert(1)</script>");
+        assert!(page.contains("value=\"&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;\""));
+        assert!(!page.contains("<script>"));
+        assert!(page.contains("action=\"/api/users/password/reset\""));
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::env_var;
+
+const USERNAME_MIN_LENGTH_DEFAULT: usize = 3;
+const USERNAME_MAX_LENGTH_DEFAULT: usize = 32;
+const USERNAME_ALLOWED_SYMBOLS_DEFAULT: &str = "_-.";
+
+/// Always reserved, on top of the comma separated `USERNAME_RESERVED`.
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "api", "root", "system", "support", "help", "login", "logout",
+    "register", "me", "null", "undefined", "todo", "users", "moderator",
+];
+
+const PASSWORD_MIN_LENGTH_DEFAULT: usize = 8;
+const PASSWORD_MAX_LENGTH_DEFAULT: usize = 128;
+
+/// Always rejected, on top of the entries of `PASSWORD_DENYLIST_FILE`.
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "12345678", "123456789", "1234567890", "password", "password1", "password123",
+    "qwerty", "qwerty123", "qwertyuiop", "abc123", "111111", "1q2w3e4r", "iloveyou",
+    "letmein", "welcome", "welcome1", "admin", "admin123", "monkey", "dragon", "sunshine",
+    "football", "baseball", "trustno1", "passw0rd", "changeme",
+];
+
+/// A password rule that was not met, reported to the client as `{"rule": ...}`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum PasswordViolation {
+    TooShort { min_length: usize },
+    TooLong { max_length: usize },
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSpecial,
+    Common,
+    ContainsUsername,
+}
+
+/// The common passwords and those in `file`, one per line, in lowercase.
+fn parse_denylist(file: &str) -> HashSet<String> {
+    COMMON_PASSWORDS
+        .iter()
+        .map(|pw| pw.to_string())
+        .chain(
+            file.lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty()),
+        )
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    /// Requires a character that is neither a letter nor a digit.
+    pub require_special: bool,
+    /// Lowercase passwords that are rejected outright.
+    pub denylist: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> PasswordPolicy {
+        let file = std::env::var("PASSWORD_DENYLIST_FILE").ok().map(|path| {
+            std::fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err))
+        });
+        let denylist = parse_denylist(file.as_deref().unwrap_or_default());
+
+        PasswordPolicy {
+            min_length: env_var("PASSWORD_MIN_LENGTH", PASSWORD_MIN_LENGTH_DEFAULT),
+            max_length: env_var("PASSWORD_MAX_LENGTH", PASSWORD_MAX_LENGTH_DEFAULT),
+            require_lowercase: env_var("PASSWORD_REQUIRE_LOWERCASE", true),
+            require_uppercase: env_var("PASSWORD_REQUIRE_UPPERCASE", false),
+            require_digit: env_var("PASSWORD_REQUIRE_DIGIT", true),
+            require_special: env_var("PASSWORD_REQUIRE_SPECIAL", false),
+            denylist,
+        }
+    }
+
+    /// Checks `password` against every rule and returns all the ones it breaks.
+    pub fn check(&self, username: &str, password: &str) -> Result<(), Vec<PasswordViolation>> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+        let lowercase = password.to_lowercase();
+
+        if length < self.min_length {
+            violations.push(PasswordViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            violations.push(PasswordViolation::TooLong {
+                max_length: self.max_length,
+            });
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            violations.push(PasswordViolation::MissingLowercase);
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            violations.push(PasswordViolation::MissingUppercase);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violations.push(PasswordViolation::MissingDigit);
+        }
+        if self.require_special && !password.chars().any(|c| !c.is_alphanumeric()) {
+            violations.push(PasswordViolation::MissingSpecial);
+        }
+        if self.denylist.contains(&lowercase) {
+            violations.push(PasswordViolation::Common);
+        }
+        let username = username.trim().to_lowercase();
+        if !username.is_empty() && lowercase.contains(&username) {
+            violations.push(PasswordViolation::ContainsUsername);
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+/// The form usernames are compared in: NFKC normalized and lowercased, so that
+/// visually identical names and different casings collide.
+pub fn username_key(username: &str) -> String {
+    username.nfkc().collect::<String>().to_lowercase()
+}
+
+/// A username rule that was not met, reported to the client as `{"rule": ...}`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum UsernameViolation {
+    TooShort { min_length: usize },
+    TooLong { max_length: usize },
+    InvalidCharacters,
+    Reserved,
+}
+
+#[derive(Clone, Debug)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Characters allowed besides letters and digits.
+    pub allowed_symbols: String,
+    /// Username keys that can't be registered.
+    pub reserved: HashSet<String>,
+}
+
+impl UsernamePolicy {
+    pub fn from_env() -> UsernamePolicy {
+        let mut reserved: HashSet<String> =
+            RESERVED_USERNAMES.iter().map(|name| name.to_string()).collect();
+        if let Ok(names) = std::env::var("USERNAME_RESERVED") {
+            reserved.extend(
+                names
+                    .split(',')
+                    .map(|name| username_key(name.trim()))
+                    .filter(|name| !name.is_empty()),
+            );
+        }
+
+        UsernamePolicy {
+            min_length: env_var("USERNAME_MIN_LENGTH", USERNAME_MIN_LENGTH_DEFAULT),
+            max_length: env_var("USERNAME_MAX_LENGTH", USERNAME_MAX_LENGTH_DEFAULT),
+            allowed_symbols: env_var(
+                "USERNAME_ALLOWED_SYMBOLS",
+                USERNAME_ALLOWED_SYMBOLS_DEFAULT.to_string(),
+            ),
+            reserved,
+        }
+    }
+
+    /// Normalizes `username` to NFKC and checks it against every rule.
+    ///
+    /// Returns the normalized username, which is the one to store.
+    pub fn check(&self, username: &str) -> Result<String, Vec<UsernameViolation>> {
+        let username = username.nfkc().collect::<String>();
+        let mut violations = Vec::new();
+        let length = username.chars().count();
+
+        if length < self.min_length {
+            violations.push(UsernameViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            violations.push(UsernameViolation::TooLong {
+                max_length: self.max_length,
+            });
+        }
+        if !username
+            .chars()
+            .all(|c| c.is_alphanumeric() || self.allowed_symbols.contains(c))
+        {
+            violations.push(UsernameViolation::InvalidCharacters);
+        }
+        if self.reserved.contains(&username_key(&username)) {
+            violations.push(UsernameViolation::Reserved);
+        }
+
+        if violations.is_empty() {
+            Ok(username)
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict_policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 16,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_special: true,
+            denylist: COMMON_PASSWORDS.iter().map(|pw| pw.to_string()).collect(),
+        }
+    }
+
+    fn violations(policy: &PasswordPolicy, password: &str) -> Vec<PasswordViolation> {
+        policy.check("alice", password).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn accepts_a_password_meeting_every_rule() {
+        assert_eq!(strict_policy().check("alice", "Tr0ub4dor&3"), Ok(()));
+    }
+
+    #[test]
+    fn reports_each_rule() {
+        let policy = strict_policy();
+        assert_eq!(
+            violations(&policy, "Sh0rt!"),
+            vec![PasswordViolation::TooShort { min_length: 8 }]
+        );
+        assert_eq!(
+            violations(&policy, "Much-too-l0ng-for-this"),
+            vec![PasswordViolation::TooLong { max_length: 16 }]
+        );
+        assert_eq!(violations(&policy, "UPPER-CASE-0"), vec![PasswordViolation::MissingLowercase]);
+        assert_eq!(violations(&policy, "lower-case-0"), vec![PasswordViolation::MissingUppercase]);
+        assert_eq!(violations(&policy, "Without-Digits"), vec![PasswordViolation::MissingDigit]);
+        assert_eq!(violations(&policy, "NoSpecial0"), vec![PasswordViolation::MissingSpecial]);
+        assert_eq!(violations(&policy, "My-Alice-0"), vec![PasswordViolation::ContainsUsername]);
+
+        let lenient = PasswordPolicy {
+            require_uppercase: false,
+            require_special: false,
+            ..strict_policy()
+        };
+        assert_eq!(violations(&lenient, "PassWord123"), vec![PasswordViolation::Common]);
+    }
+
+    #[test]
+    fn reports_every_failed_rule_together() {
+        assert_eq!(
+            violations(&strict_policy(), "alice"),
+            vec![
+                PasswordViolation::TooShort { min_length: 8 },
+                PasswordViolation::MissingUppercase,
+                PasswordViolation::MissingDigit,
+                PasswordViolation::MissingSpecial,
+                PasswordViolation::ContainsUsername,
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_that_are_off_are_not_checked() {
+        let policy = PasswordPolicy {
+            min_length: 0,
+            max_length: usize::MAX,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_special: false,
+            denylist: HashSet::new(),
+        };
+        assert_eq!(policy.check("", "password"), Ok(()));
+    }
+
+    #[test]
+    fn denylist_file_extends_the_common_passwords() {
+        let policy = PasswordPolicy {
+            denylist: parse_denylist("  Correct-Horse-9 \n\nhunter22\n"),
+            ..PasswordPolicy::from_env()
+        };
+
+        assert!(policy.denylist.contains("correct-horse-9"));
+        assert!(policy.denylist.contains("hunter22"));
+        assert!(policy.denylist.contains("password"));
+        assert!(!policy.denylist.contains(""));
+        assert!(violations(&policy, "CORRECT-HORSE-9").contains(&PasswordViolation::Common));
+    }
+}
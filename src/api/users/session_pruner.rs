@@ -0,0 +1,33 @@
+use crate::database::DatabaseManager;
+
+use actix::prelude::*;
+use std::{sync::Arc, time::Duration};
+
+/// Periodically removes expired sessions, so they don't pile up in `users`.
+pub struct SessionPruner {
+    db: Arc<DatabaseManager>,
+    interval: Duration,
+}
+impl SessionPruner {
+    pub fn new(db: Arc<DatabaseManager>, interval: Duration) -> SessionPruner {
+        SessionPruner { db, interval }
+    }
+}
+
+impl Actor for SessionPruner {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |act, ctx| {
+            let db = act.db.clone();
+            ctx.spawn(
+                async move {
+                    if let Err(err) = db.users.prune_expired_sessions().await {
+                        println!("Failed to prune expired sessions: {}", err);
+                    }
+                }
+                .into_actor(act),
+            );
+        });
+    }
+}
@@ -1,8 +1,18 @@
-use std::{fmt, time::SystemTime};
+use std::{fmt, time::{Duration, SystemTime}};
 
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
+use crate::{api::users::user::UserId, config::env_var, crypto};
+
+const ABSOLUTE_TIMEOUT_SECS_DEFAULT: u64 = 60 * 60 * 24 * 30;
+const IDLE_TIMEOUT_SECS_DEFAULT: u64 = 60 * 60 * 24 * 7;
+const MAX_SESSIONS_PER_USER_DEFAULT: usize = 10;
+const PRUNE_INTERVAL_SECS_DEFAULT: u64 = 60 * 60;
+const SESSION_ID_LEN: usize = 16;
+const FINGERPRINT_LEN: usize = 12;
+const MAX_USER_AGENT_LEN: usize = 256;
+
 #[derive(Deserialize, Serialize, Clone, Debug, Hash, PartialEq, Eq)]
 pub struct SessionToken(String);
 
@@ -12,7 +22,6 @@ impl SessionToken {
             thread_rng()
                 .sample_iter(&Alphanumeric)
                 .take(30)
-                .map(char::from)
                 .collect::<String>()
                 + "##"
                 + &SystemTime::now()
@@ -32,9 +41,100 @@ impl SessionToken {
         // }
         SessionToken(text.to_string())
     }
+
+    /// What the database stores instead of the token, keyed so that reading the
+    /// database isn't enough to forge the hash of a guessed token.
+    pub fn hash(&self, secret: &[u8]) -> String {
+        crypto::to_hex(&crypto::hmac_sha256(secret, self.0.as_bytes()))
+    }
 }
 impl fmt::Display for SessionToken {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(&self.0)
     }
-}
\ No newline at end of file
+}
+
+/// A session that was just started, with the user it belongs to.
+#[derive(Debug)]
+pub struct NewSession {
+    pub user_id: UserId,
+    /// The public id of the session, which stays the same when its token is rotated.
+    pub session_id: String,
+    pub token: SessionToken,
+}
+
+/// Public id of a session, used to revoke it without knowing its token.
+pub fn new_session_id() -> String {
+    crypto::random_token(SESSION_ID_LEN)
+}
+
+/// A short prefix of a token hash that identifies the session to its owner.
+pub fn fingerprint(token_hash: &str) -> String {
+    token_hash.chars().take(FINGERPRINT_LEN).collect()
+}
+
+/// Where a session was started from, as reported by the client.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(user_agent: Option<&str>, ip: Option<&str>) -> ClientInfo {
+        ClientInfo {
+            user_agent: user_agent.map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect()),
+            ip: ip.map(str::to_string),
+        }
+    }
+}
+
+/// A session as listed to its owner.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub fingerprint: String,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Whether this is the session the list was requested with.
+    pub current: bool,
+}
+
+/// How long sessions live and how many a user may hold at once.
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    /// Maximum lifetime of a session, counted from login.
+    pub absolute_timeout: Duration,
+    /// A session not used for this long expires, every use renews it.
+    pub idle_timeout: Duration,
+    /// Logging in beyond this many sessions evicts the oldest one.
+    pub max_sessions_per_user: usize,
+    /// How often expired sessions are removed from the database.
+    pub prune_interval: Duration,
+}
+
+impl SessionConfig {
+    pub fn from_env() -> SessionConfig {
+        SessionConfig {
+            absolute_timeout: Duration::from_secs(env_var(
+                "SESSION_ABSOLUTE_TIMEOUT_SECS",
+                ABSOLUTE_TIMEOUT_SECS_DEFAULT,
+            )),
+            idle_timeout: Duration::from_secs(env_var(
+                "SESSION_IDLE_TIMEOUT_SECS",
+                IDLE_TIMEOUT_SECS_DEFAULT,
+            )),
+            max_sessions_per_user: env_var(
+                "SESSION_MAX_PER_USER",
+                MAX_SESSIONS_PER_USER_DEFAULT,
+            )
+            .max(1),
+            prune_interval: Duration::from_secs(env_var(
+                "SESSION_PRUNE_INTERVAL_SECS",
+                PRUNE_INTERVAL_SECS_DEFAULT,
+            )),
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{
+    api::users::user::UserId,
+    crypto::{self, TokenPurpose},
+    util::unix_timestamp,
+};
+
+const SECRET_LEN: usize = 20;
+const DIGITS: u32 = 6;
+const STEP_SECS: i64 = 30;
+/// Codes of the neighbouring steps are accepted too, for clocks that are a little off.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+const PENDING_LOGIN_TTL_SECS: i64 = 60 * 5;
+const ISSUER: &str = "TODO";
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Two-factor state of a user, stored on the user document.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TotpEnrollment {
+    /// The shared secret, encrypted with `crypto::encrypt`.
+    pub secret: String,
+    /// Set once the user proved their authenticator works, only then is the second step required.
+    pub confirmed: bool,
+    /// Hashes of the unused recovery codes, see `hash_recovery_code`.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// The last time step a code was accepted for, codes of it and earlier steps are spent.
+    #[serde(default)]
+    pub last_step: i64,
+}
+
+impl TotpEnrollment {
+    pub fn new(key: &[u8], secret: &[u8]) -> TotpEnrollment {
+        TotpEnrollment {
+            secret: crypto::encrypt(key, secret),
+            confirmed: false,
+            recovery_codes: vec![],
+            last_step: 0,
+        }
+    }
+
+    /// The time step `code` is valid for, if it's valid now and not spent yet.
+    pub fn check_code(&self, key: &[u8], code: &str) -> Option<i64> {
+        let secret = crypto::decrypt(key, &self.secret)?;
+        let code: u32 = code.trim().parse().ok()?;
+        let now = unix_timestamp() / STEP_SECS;
+        (now - ALLOWED_DRIFT_STEPS..=now + ALLOWED_DRIFT_STEPS)
+            .filter(|step| *step > self.last_step)
+            .find(|step| code_at(&secret, *step) == code)
+    }
+}
+
+/// What a user gets when starting enrollment, to set up an authenticator app.
+#[derive(Debug, Serialize)]
+pub struct TotpSetup {
+    /// The secret in base32, for entering it by hand.
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+impl TotpSetup {
+    pub fn new(account: &str, secret: &[u8]) -> TotpSetup {
+        let secret = base32(secret);
+        let otpauth_uri = format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            utf8_percent_encode(ISSUER, NON_ALPHANUMERIC),
+            utf8_percent_encode(account, NON_ALPHANUMERIC),
+            secret,
+            utf8_percent_encode(ISSUER, NON_ALPHANUMERIC),
+            DIGITS,
+            STEP_SECS,
+        );
+        TotpSetup {
+            secret,
+            otpauth_uri,
+        }
+    }
+}
+
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LEN];
+    thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// The code for a time step, as defined by RFC 6238 with HMAC-SHA1.
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]])
+        & 0x7fff_ffff;
+    binary % 10u32.pow(DIGITS)
+}
+
+fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// One-time codes that stand in for a TOTP code when the authenticator is lost.
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| crypto::random_token(RECOVERY_CODE_LEN).to_lowercase())
+        .collect()
+}
+
+/// Recovery codes are short enough to brute force from a plain digest, so they're keyed
+/// with the server secret.
+pub fn hash_recovery_code(key: &[u8], code: &str) -> String {
+    crypto::keyed_hash(key, TokenPurpose::RecoveryCode, normalize_recovery_code(code).as_bytes())
+}
+
+/// How recovery codes were hashed before they were keyed, accepted until they're used.
+pub fn legacy_hash_recovery_code(code: &str) -> String {
+    crypto::sha256_hex(normalize_recovery_code(code).as_bytes())
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_lowercase()
+}
+
+/// Signed token proving the password step of a login, exchanged for a session together
+/// with a TOTP or recovery code.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PendingLogin {
+    pub two_factor_user: UserId,
+    pub expires_at: i64,
+}
+
+impl PendingLogin {
+    pub fn new(user_id: UserId) -> PendingLogin {
+        PendingLogin {
+            two_factor_user: user_id,
+            expires_at: unix_timestamp() + PENDING_LOGIN_TTL_SECS,
+        }
+    }
+
+    pub fn to_token(&self, secret: &[u8]) -> String {
+        crypto::sign(
+            secret,
+            crypto::TokenPurpose::PendingLogin,
+            &serde_json::to_string(self).expect("PendingLogin serializes to JSON"),
+        )
+    }
+
+    /// Parses a token made by `to_token`, rejecting forged and expired ones.
+    pub fn from_token(secret: &[u8], token: &str) -> Option<PendingLogin> {
+        let payload = crypto::verify(secret, crypto::TokenPurpose::PendingLogin, token)?;
+        let pending: PendingLogin = serde_json::from_str(&payload).ok()?;
+        if pending.expires_at < unix_timestamp() {
+            None
+        } else {
+            Some(pending)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_code_hashes_depend_on_the_key() {
+        let hash = hash_recovery_code(b"key", "abc123");
+        assert_eq!(hash_recovery_code(b"key", " ABC123\n"), hash);
+        assert_ne!(hash_recovery_code(b"other key", "abc123"), hash);
+        assert_ne!(legacy_hash_recovery_code("abc123"), hash);
+    }
+
+    #[test]
+    fn codes_match_rfc_6238_test_vectors() {
+        let secret = b"12345678901234567890";
+        // The RFC lists 8 digits, these are their last 6.
+        assert_eq!(code_at(secret, 59 / STEP_SECS), 287082);
+        assert_eq!(code_at(secret, 1111111109 / STEP_SECS), 81804);
+        assert_eq!(code_at(secret, 1234567890 / STEP_SECS), 5924);
+        assert_eq!(code_at(secret, 20000000000 / STEP_SECS), 353130);
+    }
+
+    #[test]
+    fn base32_encodes_rfc_4648_vectors_without_padding() {
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"foob"), "MZXW6YQ");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn only_pending_login_tokens_are_pending_logins() {
+        let secret = b"secret";
+        let pending = PendingLogin::new(UserId::new());
+        assert!(PendingLogin::from_token(secret, &pending.to_token(secret)).is_some());
+
+        // Even a payload that parses as a pending login doesn't count when signed for
+        // something else.
+        let payload = serde_json::to_string(&pending).unwrap();
+        let token = crypto::sign(secret, crypto::TokenPurpose::EmailVerification, &payload);
+        assert!(PendingLogin::from_token(secret, &token).is_none());
+    }
+}
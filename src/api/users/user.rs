@@ -1,19 +1,15 @@
 use rand::{thread_rng, Rng};
+
+use crate::api::users::totp::TotpEnrollment;
 use serde::{de, Deserialize, Serialize, Serializer};
 use std::fmt;
 
 const USER_ID_LEN: usize = 12;
 const VALID_USER_ID_CHARS: &str = "0123456789abcdef";
 
-#[derive(Clone, Copy, Eq, Hash, PartialOrd)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct UserId([char; USER_ID_LEN]);
 
-impl Ord for UserId {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.to_string().cmp(&other.to_string())      
-    }
-}
-
 impl UserId {
     pub fn new() -> UserId {
         //users: &[User]
@@ -75,11 +71,6 @@ impl fmt::Debug for UserId {
         fmt::Result::Ok(())
     }
 }
-impl PartialEq for UserId {
-    fn eq(&self, other: &Self) -> bool {
-        self.to_string() == other.to_string()
-    }
-}
 impl Serialize for UserId {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -98,10 +89,15 @@ impl<'de> Deserialize<'de> for UserId {
     }
 }
 
-const MIN_PASSWORD_LENGTH: usize = 6;
-const MAX_PASSWORD_LENGTH: usize = 15;
-const SPECIAL_CHARS: &str = "0123456789=!<[>]()-/{}~+%$|#';&+€";
-const INVALID_CHARS: &str = "#:\\\"";
+/// What users get to see about their own account.
+#[derive(Debug, Serialize)]
+pub struct UserMe {
+    pub id: UserId,
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub two_factor_enabled: bool,
+}
 
 #[derive(Clone)]
 pub struct BackendUserMe {
@@ -109,69 +105,156 @@ pub struct BackendUserMe {
     pub username: String,
     pub password: HashedPassword,
     pub email: Option<String>,
+    pub email_verified: bool,
+    pub totp: Option<TotpEnrollment>,
 }
 
 impl BackendUserMe {
-    pub fn new(username: String, password: String) -> BackendUserMe {
+    pub fn new(username: String, password: HashedPassword) -> BackendUserMe {
         BackendUserMe {
             id: UserId::new(),
             username,
-            password: HashedPassword::new(password),
+            password,
             email: None,
+            email_verified: false,
+            totp: None,
         }
     }
 
-    pub fn check_password(password: &str) -> bool {
-        password.len() > MIN_PASSWORD_LENGTH
-            && password.chars().any(|c| SPECIAL_CHARS.contains(c))
-            && password.len() < MAX_PASSWORD_LENGTH
-            && !password.chars().any(|c| INVALID_CHARS.contains(c))
+    /// The confirmed two-factor enrollment, which makes logins take a second step.
+    pub fn two_factor(&self) -> Option<&TotpEnrollment> {
+        self.totp.as_ref().filter(|totp| totp.confirmed)
     }
 
     pub fn gen_new_id(&mut self) {
         self.id = UserId::new();
     }
+
+    pub fn to_user_me(&self) -> UserMe {
+        UserMe {
+            id: self.id,
+            username: self.username.clone(),
+            email: self.email.clone(),
+            email_verified: self.email_verified,
+            two_factor_enabled: self.two_factor().is_some(),
+        }
+    }
 }
 
 pub use pw::*;
 
 pub mod pw {
+    use actix_web::web;
+    use argon2::{
+        password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+        Argon2, Params,
+    };
     use serde::{de, Deserialize, Serialize, Serializer};
     use sha3::{Digest, Keccak256};
     use std::fmt;
 
+    use crate::api::ApiError;
+
     #[derive(Debug, Clone, PartialEq)]
-    pub struct HashedPassword(Vec<u8>);
+    pub enum HashedPassword {
+        /// Salted Argon2id hash in PHC string format, parameters included.
+        Phc(String),
+        /// Unsalted Keccak256 digest stored by earlier versions, replaced on the next login.
+        Keccak(Vec<u8>),
+    }
     impl HashedPassword {
         pub fn new(password: String) -> HashedPassword {
-            HashedPassword(Self::hash(&password))
+            let salt = SaltString::generate(&mut OsRng);
+            let hash = Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .expect("Argon2 hashing with default parameters failed")
+                .to_string();
+            HashedPassword::Phc(hash)
         }
-        fn hash(string: &str) -> Vec<u8> {
+        /// `new` on the blocking thread pool, Argon2 is slow on purpose and would stall
+        /// the worker and actor it runs on.
+        pub async fn hash(password: String) -> Result<HashedPassword, ApiError> {
+            blocking(move || HashedPassword::new(password)).await
+        }
+        /// `matches` on the blocking thread pool.
+        pub async fn verify(&self, password: String) -> Result<bool, ApiError> {
+            let hash = self.clone();
+            blocking(move || hash.matches(&password)).await
+        }
+        fn keccak(string: &str) -> Vec<u8> {
             Keccak256::digest(string.as_bytes()).into_iter().collect()
         }
         pub fn matches(&self, password: &str) -> bool {
-            self.0 == Self::hash(password)
+            match self {
+                HashedPassword::Phc(hash) => PasswordHash::new(hash)
+                    .map(|hash| {
+                        Argon2::default()
+                            .verify_password(password.as_bytes(), &hash)
+                            .is_ok()
+                    })
+                    .unwrap_or(false),
+                HashedPassword::Keccak(digest) => *digest == Self::keccak(password),
+            }
+        }
+        /// Whether the hash is a legacy digest or uses weaker parameters than new hashes.
+        pub fn needs_rehash(&self) -> bool {
+            match self {
+                HashedPassword::Phc(hash) => match PasswordHash::new(hash) {
+                    Ok(hash) => {
+                        let current = Argon2::default();
+                        let current = current.params();
+                        hash.algorithm != argon2::ARGON2ID_IDENT
+                            || Params::try_from(&hash).map_or(true, |params| {
+                                // The output length isn't part of the string, only compare costs.
+                                (params.m_cost(), params.t_cost(), params.p_cost())
+                                    != (current.m_cost(), current.t_cost(), current.p_cost())
+                            })
+                    }
+                    Err(_) => true,
+                },
+                HashedPassword::Keccak(_) => true,
+            }
         }
 
         fn from_str(string: &str) -> Result<HashedPassword, &str> {
+            if string.starts_with('$') {
+                return PasswordHash::new(string)
+                    .map(|_| HashedPassword::Phc(string.to_string()))
+                    .map_err(|_| "Invalid PHC string");
+            }
             let mut vec = Vec::new();
             for i in (0..string.len()).step_by(2) {
-                if let Ok(b) = u8::from_str_radix(&string[i..i + 2], 16) {
+                if let Ok(b) = u8::from_str_radix(string.get(i..i + 2).unwrap_or(""), 16) {
                     vec.push(b);
                 } else {
                     return Err("Invalid hex byte");
                 }
             }
-            Ok(HashedPassword(vec))
+            Ok(HashedPassword::Keccak(vec))
         }
     }
 
+    async fn blocking<T, F>(f: F) -> Result<T, ApiError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        web::block(move || Ok::<_, ()>(f()))
+            .await
+            .map_err(|_| ApiError::InternalServerError)
+    }
+
     impl fmt::Display for HashedPassword {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            for h in self.0.iter() {
-                write!(f, "{:02x}", h)?;
+            match self {
+                HashedPassword::Phc(hash) => f.write_str(hash),
+                HashedPassword::Keccak(digest) => {
+                    for h in digest.iter() {
+                        write!(f, "{:02x}", h)?;
+                    }
+                    fmt::Result::Ok(())
+                }
             }
-            fmt::Result::Ok(())
         }
     }
 
@@ -192,4 +275,56 @@ pub mod pw {
             HashedPassword::from_str(&s).map_err(de::Error::custom)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{
+        password_hash::{rand_core::OsRng, SaltString},
+        Algorithm, Argon2, Params, PasswordHasher, Version,
+    };
+
+    fn parsed(hash: &str) -> Result<HashedPassword, serde_json::Error> {
+        serde_json::from_value(serde_json::Value::String(hash.to_string()))
+    }
+
+    #[test]
+    fn new_hashes_are_argon2id_phc_strings() {
+        let hash = HashedPassword::new(String::from("hunter2"));
+        assert!(hash.to_string().starts_with("$argon2id$"));
+        assert!(hash.matches("hunter2"));
+        assert!(!hash.matches("hunter3"));
+        assert!(!hash.needs_rehash());
+        assert_eq!(parsed(&hash.to_string()).unwrap(), hash);
+    }
+
+    #[test]
+    fn invalid_stored_hashes_are_rejected() {
+        assert!(parsed("$argon2id$v=19$m=19456,t=2,p=1$not a salt$").is_err());
+        assert!(parsed("not hex").is_err());
+    }
+
+    #[test]
+    fn keccak_digests_verify_and_need_a_rehash() {
+        // Keccak256 of "password", as stored by earlier versions.
+        let stored = "b68fe43f0d1a0d7aef123722670be50268e15365401c442f8806ef83b612976b";
+        let hash = parsed(stored).unwrap();
+        assert!(matches!(hash, HashedPassword::Keccak(_)));
+        assert_eq!(hash.to_string(), stored);
+        assert!(hash.matches("password"));
+        assert!(!hash.matches("Password"));
+        assert!(hash.needs_rehash());
+    }
+
+    #[test]
+    fn weaker_argon2_parameters_need_a_rehash() {
+        let params = Params::new(1024, 1, 1, None).unwrap();
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = weak.hash_password(b"hunter2", &salt).unwrap().to_string();
+        let hash = parsed(&hash).unwrap();
+        assert!(hash.matches("hunter2"));
+        assert!(hash.needs_rehash());
+    }
+}
@@ -1,5 +1,28 @@
-use crate::api::{users::user::*, ApiError};
-use crate::database::DatabaseManager;
+use crate::api::{
+    users::{
+        api_token::{
+            ApiToken, ApiTokenInfo, CreatedApiToken, NewApiToken, MAX_API_TOKENS_PER_USER,
+            MAX_API_TOKEN_NAME_LEN,
+        },
+        email::{is_valid_email, EmailVerification},
+        login_throttle::LoginThrottle,
+        oidc::{ExternalUser, OidcClient, OidcFlow},
+        password_reset::{hash_token, PasswordReset},
+        policy::username_key,
+        totp::{
+            hash_recovery_code, legacy_hash_recovery_code, new_recovery_codes, new_secret, PendingLogin,
+            TotpEnrollment, TotpSetup,
+        },
+        user::*,
+    },
+    ApiError,
+};
+use crate::config::Config;
+use crate::database::{store::Rotation, DatabaseManager, DbError};
+use crate::crypto;
+use crate::mail::Mailer;
+use crate::util::unix_timestamp;
+use crate::api::users::session_token::{ClientInfo, NewSession, SessionToken};
 
 use actix::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -7,17 +30,30 @@ use std::sync::Arc;
 
 pub struct UserManager {
     db: Arc<DatabaseManager>,
+    config: Arc<Config>,
+    mailer: Mailer,
+    login_throttle: LoginThrottle,
+    oidc: Option<Arc<OidcClient>>,
 }
 impl UserManager {
-    pub fn new(db: Arc<DatabaseManager>) -> UserManager {
+    pub fn new(db: Arc<DatabaseManager>, config: Arc<Config>, mailer: Mailer) -> UserManager {
         UserManager {
-            db
+            login_throttle: LoginThrottle::new(config.login_throttle.clone()),
+            oidc: config.oidc.clone().map(|oidc| Arc::new(OidcClient::new(oidc))),
+            db,
+            config,
+            mailer,
         }
     }
 }
 
 impl Actor for UserManager {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = self.config.login_throttle.max_lockout;
+        ctx.run_interval(interval, |act, _ctx| act.login_throttle.prune());
+    }
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -26,44 +62,283 @@ pub struct UserAuth {
     pub password: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct Registration {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+/// Changes to the own account, fields left out stay unchanged.
+#[derive(Deserialize, Debug, Default)]
+pub struct ProfileUpdate {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Checks the address and starts its verification, used when it's set at registration
+/// and when it's changed later.
+async fn change_email(
+    db: &DatabaseManager,
+    config: &Config,
+    mailer: &Mailer,
+    user_id: UserId,
+    email: String,
+) -> Result<(), ApiError> {
+    let verification = EmailVerification::new(user_id, email);
+    db.users
+        .set_email(&user_id, &verification.email, &verification.nonce)
+        .await?;
+    if verification.send(mailer, &config.secret).await.is_err() {
+        println!("Failed to send verification mail to user {}", user_id);
+    }
+    Ok(())
+}
+
+/// The user logging in as `username`, which may also be a verified email address.
+async fn find_login_user(db: &DatabaseManager, username: &str) -> Result<Option<BackendUserMe>, ApiError> {
+    match db.users.get_username(username).await? {
+        Some(user) => Ok(Some(user)),
+        None => Ok(db.users.get_verified_email(username).await?),
+    }
+}
+
+/// The login throttle counter of an account. Logins by username and by email address
+/// share the one of the user they found.
+fn throttle_key(user: Option<&BackendUserMe>, username: &str) -> String {
+    match user {
+        Some(user) => format!("id:{}", user.id),
+        None => format!("name:{}", username_key(username.trim())),
+    }
+}
+
+/// Checks the password of the user found by `find_login_user`.
+async fn authenticate(
+    db: &DatabaseManager,
+    mut user: BackendUserMe,
+    password: String,
+) -> Result<Option<BackendUserMe>, ApiError> {
+    if !user.password.verify(password.clone()).await? {
+        return Ok(None);
+    }
+    if user.password.needs_rehash() {
+        // Replaces a legacy or outdated hash now that the plain password is known.
+        let password = HashedPassword::hash(password).await?;
+        match db.users.set_password_hash(&user.id, &password).await {
+            Ok(()) => user.password = password,
+            Err(_) => println!("Failed to rehash password of user {}", user.id),
+        }
+    }
+    Ok(Some(user))
+}
+
+/// Stores a registered user logged in with a first session and their empty todo list,
+/// then sends the verification mail of `email`. Without one, `user.email` is kept as it is.
+///
+/// The list lives in another collection, which MongoDB can't write in the same atomic
+/// step. When creating it fails the user is removed again, so either all of it is
+/// stored or nothing.
+async fn insert_registered_user(
+    db: &DatabaseManager,
+    config: &Config,
+    mailer: &Mailer,
+    mut user: BackendUserMe,
+    email: Option<String>,
+    client: ClientInfo,
+) -> Result<NewSession, ApiError> {
+    loop {
+        let verification = email.clone().map(|email| EmailVerification::new(user.id, email));
+        if let Some(verification) = &verification {
+            user.email = Some(verification.email.clone());
+        }
+        let nonce = verification.as_ref().map(|verification| verification.nonce.as_str());
+        match db.users.insert_with_session(user.clone(), nonce, client.clone()).await {
+            Ok(session) => {
+                if let Err(err) = db.todo.create_user_todo(user.id).await {
+                    if db.remove_user(user.id).await.is_err() {
+                        println!("Failed to remove user {} after a failed registration", user.id);
+                    }
+                    return Err(err.into());
+                }
+                if let Some(verification) = verification {
+                    if verification.send(mailer, &config.secret).await.is_err() {
+                        println!("Failed to send verification mail to user {}", user.id);
+                    }
+                }
+                return Ok(session);
+            }
+            // Another user has the random id.
+            Err(DbError::DuplicateKey(index)) if index == "_id_" => user.gen_new_id(),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The result of checking the password, a session unless a second factor is needed.
+#[derive(Debug)]
+pub enum LoginOutcome {
+    Session(NewSession),
+    /// A `PendingLogin` token, see `msg::VerifyTwoFactor`.
+    TwoFactorRequired(String),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+/// The second step of a login, `code` may also be a recovery code.
+#[derive(Deserialize, Debug)]
+pub struct TwoFactorLogin {
+    pub token: String,
+    pub code: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DisableTwoFactor {
+    pub password: String,
+}
+
+/// The result of coming back from the identity provider.
+#[derive(Debug)]
+pub enum OidcOutcome {
+    Session(NewSession),
+    /// The identity was linked to the user who started the flow.
+    Linked,
+}
+
+/// Creates an account for an identity that logs in for the first time, logged in with
+/// a first session.
+///
+/// The username is taken from the provider where possible, the password is random
+/// so the account can only log in through the provider until it's reset.
+async fn register_external_user(
+    db: &DatabaseManager,
+    config: &Config,
+    mailer: &Mailer,
+    external: &ExternalUser,
+    client: ClientInfo,
+) -> Result<NewSession, ApiError> {
+    const ATTEMPTS: usize = 5;
+    let wanted = external
+        .preferred_username
+        .clone()
+        .or_else(|| external.email.as_ref().and_then(|email| email.split('@').next().map(str::to_string)))
+        .unwrap_or_default();
+    let mut candidates = vec![wanted.clone()];
+    for _ in 0..ATTEMPTS {
+        candidates.push(format!("{}{}", wanted, crypto::random_digits(4)));
+    }
+    candidates.push(format!("user{}", crypto::random_digits(8)));
+
+    // The provider vouches for the address, unless another account already has it.
+    let mut email = external
+        .email
+        .as_deref()
+        .filter(|_| external.email_verified)
+        .and_then(|email| check_email(email).ok());
+    let password = HashedPassword::hash(crypto::random_token(32)).await?;
+    for candidate in candidates {
+        let username = match config.username_policy.check(&candidate) {
+            Ok(username) => username,
+            Err(_) => continue,
+        };
+        loop {
+            let mut user = BackendUserMe::new(username.clone(), password.clone());
+            user.email = email.clone();
+            user.email_verified = email.is_some();
+            match insert_registered_user(db, config, mailer, user, None, client.clone()).await {
+                Ok(session) => return Ok(session),
+                Err(ApiError::EmailInUse) if email.is_some() => email = None,
+                // Taken, possibly in the meantime.
+                Err(ApiError::UsernameInUse) => break,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Err(ApiError::UsernameInUse)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+fn check_email(email: &str) -> Result<String, ApiError> {
+    let email = email.trim();
+    if is_valid_email(email) {
+        Ok(email.to_string())
+    } else {
+        Err(ApiError::InvalidEmail)
+    }
+}
+
 pub mod msg {
     use super::*;
-    use crate::api::users::session_token::SessionToken;
+    use crate::api::users::session_token::SessionInfo;
 
-    pub struct Register(pub UserAuth);
+    pub struct Register(pub Registration, pub ClientInfo);
     impl Message for Register {
-        type Result = Result<SessionToken, ApiError>;
+        type Result = Result<NewSession, ApiError>;
     }
     impl Handler<Register> for UserManager {
-        type Result = ResponseActFuture<Self, Result<SessionToken, ApiError>>;
+        type Result = ResponseActFuture<Self, Result<NewSession, ApiError>>;
 
         fn handle(&mut self, msg: Register, _ctx: &mut Self::Context) -> Self::Result {
-            let auth = msg.0;
+            let Register(registration, client) = msg;
             let db = self.db.clone();
+            let config = self.config.clone();
+            let mailer = self.mailer.clone();
 
             Box::pin(
                 async move {
+                    let username = config
+                        .username_policy
+                        .check(&registration.username)
+                        .map_err(ApiError::InvalidUsername)?;
+                    let email = registration.email.as_deref().map(check_email).transpose()?;
+                    let auth = UserAuth {
+                        username,
+                        password: registration.password,
+                    };
+
+                    if let Some(email) = &email {
+                        if db.users.get_verified_email(email).await?.is_some() {
+                            return Err(ApiError::EmailInUse);
+                        }
+                    }
+
                     let username_is_in_use = db
                         .users
                         .get_username(&auth.username)
-                        .await
+                        .await?
                         .is_some();
 
-                    if !BackendUserMe::check_password(&auth.password) {
-                        Err(ApiError::PasswordInsufficient)
+                    if let Err(violations) =
+                        config.password_policy.check(&auth.username, &auth.password)
+                    {
+                        Err(ApiError::PasswordInsufficient(violations))
                     } else if username_is_in_use {
                         Err(ApiError::UsernameInUse)
                     } else {
-                        let mut user =
-                            BackendUserMe::new(auth.username.clone(), auth.password.clone());
-                        while db.users.get_id(&user.id).await.is_some() {
-                            user.gen_new_id();
-                        }
-                        db.users.insert(user.clone()).await;
-                        db.users
-                            .create_session_token(auth)
-                            .await
-                            .ok_or(ApiError::IncorrectCredentials)
+                        // The checks above give the usual errors, the unique indexes
+                        // decide between concurrent registrations of the same name.
+                        let password = HashedPassword::hash(auth.password).await?;
+                        let user = BackendUserMe::new(auth.username, password);
+                        insert_registered_user(&db, &config, &mailer, user, email, client).await
                     }
                 }
                 .into_actor(self),
@@ -72,21 +347,331 @@ pub mod msg {
             //.boxed_local(ctx)
         }
     }
-    pub struct Login(pub UserAuth);
+    pub struct Login(pub UserAuth, pub ClientInfo);
     impl Message for Login {
-        type Result = Result<SessionToken, ApiError>;
+        type Result = Result<LoginOutcome, ApiError>;
     }
     impl Handler<Login> for UserManager {
-        type Result = ResponseActFuture<Self, Result<SessionToken, ApiError>>;
+        type Result = ResponseActFuture<Self, Result<LoginOutcome, ApiError>>;
 
         fn handle(&mut self, msg: Login, _ctx: &mut Self::Context) -> Self::Result {
             let db = self.db.clone();
+            let config = self.config.clone();
+            let throttle = self.login_throttle.clone();
+            Box::pin(
+                async move {
+                    let Login(auth, client) = msg;
+                    let user = find_login_user(&db, &auth.username).await?;
+                    let account = throttle_key(user.as_ref(), &auth.username);
+                    let ip = client.ip.clone();
+                    throttle
+                        .reserve(&account, ip.as_deref())
+                        .map_err(ApiError::TooManyRequests)?;
+
+                    let user = match user {
+                        Some(user) => authenticate(&db, user, auth.password).await?,
+                        None => None,
+                    };
+                    let user = user.ok_or(ApiError::IncorrectCredentials)?;
+                    if user.two_factor().is_some() {
+                        // Failures are only forgotten after the second step, or knowing the
+                        // password would allow guessing codes without end.
+                        throttle.release(&account, ip.as_deref());
+                        let pending = PendingLogin::new(user.id);
+                        return Ok(LoginOutcome::TwoFactorRequired(pending.to_token(&config.secret)));
+                    }
+                    throttle.record_success(&account, ip.as_deref());
+                    db.users
+                        .create_session(&user.id, client)
+                        .await?
+                        .map(LoginOutcome::Session)
+                        .ok_or(ApiError::InternalServerError)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Exchanges the token of a login that passed the password step for a session.
+    pub struct VerifyTwoFactor(pub TwoFactorLogin, pub ClientInfo);
+    impl Message for VerifyTwoFactor {
+        type Result = Result<NewSession, ApiError>;
+    }
+    impl Handler<VerifyTwoFactor> for UserManager {
+        type Result = ResponseActFuture<Self, Result<NewSession, ApiError>>;
+
+        fn handle(&mut self, msg: VerifyTwoFactor, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let config = self.config.clone();
+            let throttle = self.login_throttle.clone();
+            Box::pin(
+                async move {
+                    let VerifyTwoFactor(login, client) = msg;
+                    let pending = PendingLogin::from_token(&config.secret, &login.token)
+                        .ok_or(ApiError::TwoFactorLoginExpired)?;
+                    let user = db
+                        .users
+                        .get_id(&pending.two_factor_user)
+                        .await?
+                        .ok_or(ApiError::TwoFactorLoginExpired)?;
+                    let totp = user.two_factor().ok_or(ApiError::TwoFactorLoginExpired)?;
+
+                    let account = throttle_key(Some(&user), &user.username);
+                    let ip = client.ip.clone();
+                    throttle
+                        .reserve(&account, ip.as_deref())
+                        .map_err(ApiError::TooManyRequests)?;
+
+                    let accepted = match totp.check_code(&config.secret, &login.code) {
+                        Some(step) => db.users.use_totp_step(&user.id, step).await?,
+                        None => {
+                            let hash = hash_recovery_code(&config.secret, &login.code);
+                            let legacy_hash = legacy_hash_recovery_code(&login.code);
+                            db.users.use_recovery_code(&user.id, &hash).await?
+                                || db.users.use_recovery_code(&user.id, &legacy_hash).await?
+                        }
+                    };
+                    if !accepted {
+                        return Err(ApiError::InvalidTwoFactorCode);
+                    }
+                    throttle.record_success(&account, ip.as_deref());
+                    db.users
+                        .create_session(&user.id, client)
+                        .await?
+                        .ok_or(ApiError::InternalServerError)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Starts two-factor enrollment with a new secret, replacing an unconfirmed one.
+    pub struct EnrollTwoFactor(pub UserId);
+    impl Message for EnrollTwoFactor {
+        type Result = Result<TotpSetup, ApiError>;
+    }
+    impl Handler<EnrollTwoFactor> for UserManager {
+        type Result = ResponseActFuture<Self, Result<TotpSetup, ApiError>>;
+
+        fn handle(&mut self, msg: EnrollTwoFactor, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let config = self.config.clone();
+            Box::pin(
+                async move {
+                    let user = db
+                        .users
+                        .get_id(&msg.0)
+                        .await?
+                        .ok_or(ApiError::InvalidSessionToken)?;
+                    let secret = new_secret();
+                    let enrolling = db
+                        .users
+                        .set_pending_totp(&user.id, &TotpEnrollment::new(&config.secret, &secret))
+                        .await?;
+                    if !enrolling {
+                        return Err(ApiError::TwoFactorAlreadyEnabled);
+                    }
+                    Ok(TotpSetup::new(&user.username, &secret))
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Enables two-factor authentication once the first code checks out, returning the
+    /// recovery codes. They are shown only this once.
+    pub struct ConfirmTwoFactor {
+        pub user_id: UserId,
+        pub code: TwoFactorCode,
+    }
+    impl Message for ConfirmTwoFactor {
+        type Result = Result<Vec<String>, ApiError>;
+    }
+    impl Handler<ConfirmTwoFactor> for UserManager {
+        type Result = ResponseActFuture<Self, Result<Vec<String>, ApiError>>;
+
+        fn handle(&mut self, msg: ConfirmTwoFactor, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let config = self.config.clone();
             Box::pin(
                 async move {
+                    let user = db
+                        .users
+                        .get_id(&msg.user_id)
+                        .await?
+                        .ok_or(ApiError::InvalidSessionToken)?;
+                    let totp = match user.totp {
+                        Some(totp) if totp.confirmed => return Err(ApiError::TwoFactorAlreadyEnabled),
+                        Some(totp) => totp,
+                        None => return Err(ApiError::TwoFactorNotEnrolled),
+                    };
+                    let step = totp
+                        .check_code(&config.secret, &msg.code.code)
+                        .ok_or(ApiError::InvalidTwoFactorCode)?;
+
+                    let recovery_codes = new_recovery_codes();
+                    let hashes = recovery_codes
+                        .iter()
+                        .map(|code| hash_recovery_code(&config.secret, code))
+                        .collect();
+                    if db.users.confirm_totp(&user.id, step, hashes).await? {
+                        Ok(recovery_codes)
+                    } else {
+                        Err(ApiError::TwoFactorNotEnrolled)
+                    }
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    pub struct DisableTwoFactorRequest {
+        pub user_id: UserId,
+        pub request: DisableTwoFactor,
+    }
+    impl Message for DisableTwoFactorRequest {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<DisableTwoFactorRequest> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: DisableTwoFactorRequest, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    let user = db
+                        .users
+                        .get_id(&msg.user_id)
+                        .await?
+                        .ok_or(ApiError::InvalidSessionToken)?;
+                    if !user.password.verify(msg.request.password).await? {
+                        return Err(ApiError::IncorrectCredentials);
+                    }
+                    if user.totp.is_none() {
+                        return Err(ApiError::TwoFactorNotEnrolled);
+                    }
                     db.users
-                        .create_session_token(msg.0)
+                        .remove_totp(&user.id)
                         .await
-                        .ok_or(ApiError::IncorrectCredentials)
+                        .map_err(ApiError::from)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Starts a login through the identity provider, or linking it when a user is given.
+    /// Returns where to send the browser and the flow token to keep in a cookie.
+    pub struct StartOidcLogin(pub Option<UserId>);
+    impl Message for StartOidcLogin {
+        type Result = Result<(String, String), ApiError>;
+    }
+    impl Handler<StartOidcLogin> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(String, String), ApiError>>;
+
+        fn handle(&mut self, msg: StartOidcLogin, _ctx: &mut Self::Context) -> Self::Result {
+            let oidc = self.oidc.clone();
+            let config = self.config.clone();
+            Box::pin(
+                async move {
+                    let oidc = oidc.ok_or(ApiError::OidcNotConfigured)?;
+                    let (url, flow) = oidc.authorization_request(msg.0).await.map_err(|err| {
+                        println!("OIDC login failed: {}", err);
+                        ApiError::OidcLoginFailed
+                    })?;
+                    Ok((url, flow.to_token(&config.secret)))
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Finishes a login through the identity provider. Two-factor authentication is
+    /// left to the provider, its logins don't need a TOTP code here.
+    pub struct FinishOidcLogin {
+        pub flow_token: Option<String>,
+        pub code: String,
+        pub state: String,
+        pub client: ClientInfo,
+    }
+    impl Message for FinishOidcLogin {
+        type Result = Result<OidcOutcome, ApiError>;
+    }
+    impl Handler<FinishOidcLogin> for UserManager {
+        type Result = ResponseActFuture<Self, Result<OidcOutcome, ApiError>>;
+
+        fn handle(&mut self, msg: FinishOidcLogin, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let oidc = self.oidc.clone();
+            let config = self.config.clone();
+            let mailer = self.mailer.clone();
+            Box::pin(
+                async move {
+                    let oidc = oidc.ok_or(ApiError::OidcNotConfigured)?;
+                    let flow = msg
+                        .flow_token
+                        .and_then(|token| OidcFlow::from_token(&config.secret, &token))
+                        .ok_or(ApiError::OidcLoginFailed)?;
+                    let external = oidc.finish(&flow, &msg.code, &msg.state).await.map_err(|err| {
+                        println!("OIDC login failed: {}", err);
+                        ApiError::OidcLoginFailed
+                    })?;
+                    let identity = external.identity();
+
+                    if let Some(user_id) = flow.link_user {
+                        db.users.link_identity(&user_id, &identity).await?;
+                        return Ok(OidcOutcome::Linked);
+                    }
+                    let user = match db.users.get_identity(&identity).await? {
+                        Some(user) => user,
+                        None if oidc.config().auto_register => {
+                            let session =
+                                register_external_user(&db, &config, &mailer, &external, msg.client)
+                                    .await?;
+                            if let Err(err) = db.users.link_identity(&session.user_id, &identity).await {
+                                // Another login of the same identity won the race.
+                                let _ = db.remove_user(session.user_id).await;
+                                return Err(err.into());
+                            }
+                            return Ok(OidcOutcome::Session(session));
+                        }
+                        None => return Err(ApiError::IdentityNotLinked),
+                    };
+                    db.users
+                        .create_session(&user.id, msg.client)
+                        .await?
+                        .map(OidcOutcome::Session)
+                        .ok_or(ApiError::InternalServerError)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Exchanges a refresh token for a new one, see `UserStore::rotate_session_token`.
+    pub struct RefreshSession(pub SessionToken);
+    impl Message for RefreshSession {
+        type Result = Result<NewSession, ApiError>;
+    }
+    impl Handler<RefreshSession> for UserManager {
+        type Result = ResponseActFuture<Self, Result<NewSession, ApiError>>;
+
+        fn handle(&mut self, msg: RefreshSession, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let enabled = self.config.access_tokens.enabled;
+            Box::pin(
+                async move {
+                    if !enabled {
+                        return Err(ApiError::AccessTokensDisabled);
+                    }
+                    match db.users.rotate_session_token(&msg.0).await? {
+                        Rotation::Rotated(new_session) => Ok(new_session),
+                        Rotation::Reused => {
+                            println!("A rotated refresh token was used again, its session was ended");
+                            Err(ApiError::RefreshTokenReused)
+                        }
+                        Rotation::Unknown => Err(ApiError::InvalidSessionToken),
+                    }
                 }
                 .into_actor(self),
             )
@@ -107,10 +692,639 @@ pub mod msg {
                     db.users
                         .remove_session_token(msg.0)
                         .await
-                        .map_err(|_| ApiError::InternalServerError)
+                        .map_err(ApiError::from)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    pub struct ListSessions {
+        pub user_id: UserId,
+        pub session_token: SessionToken,
+    }
+    impl Message for ListSessions {
+        type Result = Result<Vec<SessionInfo>, ApiError>;
+    }
+    impl Handler<ListSessions> for UserManager {
+        type Result = ResponseActFuture<Self, Result<Vec<SessionInfo>, ApiError>>;
+
+        fn handle(&mut self, msg: ListSessions, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    db.users
+                        .list_sessions(&msg.user_id, &msg.session_token)
+                        .await
+                        .map_err(ApiError::from)
                 }
                 .into_actor(self),
             )
         }
     }
-}
\ No newline at end of file
+
+    pub struct RevokeSession {
+        pub user_id: UserId,
+        pub session_id: String,
+    }
+    impl Message for RevokeSession {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<RevokeSession> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: RevokeSession, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    if db.users.remove_session(&msg.user_id, &msg.session_id).await? {
+                        Ok(())
+                    } else {
+                        Err(ApiError::SessionNotFound)
+                    }
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Logs out everywhere, including the session that asked for it.
+    pub struct RevokeAllSessions(pub UserId);
+    impl Message for RevokeAllSessions {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<RevokeAllSessions> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: RevokeAllSessions, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    db.users
+                        .remove_all_sessions(&msg.0)
+                        .await
+                        .map_err(ApiError::from)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    pub struct CreateApiToken {
+        pub user_id: UserId,
+        pub request: NewApiToken,
+    }
+    impl Message for CreateApiToken {
+        type Result = Result<CreatedApiToken, ApiError>;
+    }
+    impl Handler<CreateApiToken> for UserManager {
+        type Result = ResponseActFuture<Self, Result<CreatedApiToken, ApiError>>;
+
+        fn handle(&mut self, msg: CreateApiToken, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    let name = msg.request.name.trim().to_string();
+                    if name.is_empty() || name.chars().count() > MAX_API_TOKEN_NAME_LEN {
+                        return Err(ApiError::InvalidApiTokenName);
+                    }
+                    if msg.request.scopes.is_empty() {
+                        return Err(ApiError::MissingApiTokenScope);
+                    }
+                    let count = db
+                        .api_tokens
+                        .count(&msg.user_id)
+                        .await?;
+                    if count >= MAX_API_TOKENS_PER_USER {
+                        return Err(ApiError::TooManyApiTokens);
+                    }
+
+                    let api_token = ApiToken::new();
+                    let mut scopes = Vec::new();
+                    for scope in msg.request.scopes.iter().copied() {
+                        if !scopes.contains(&scope) {
+                            scopes.push(scope);
+                        }
+                    }
+                    let info = ApiTokenInfo {
+                        id: api_token.id.clone(),
+                        name,
+                        scopes,
+                        created_at: unix_timestamp(),
+                        expires_at: msg.request.expires_at(),
+                        last_used: None,
+                    };
+                    db.api_tokens
+                        .insert(&msg.user_id, api_token.token_hash(), &info)
+                        .await?;
+                    Ok(CreatedApiToken {
+                        token: api_token.token,
+                        info,
+                    })
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    pub struct ListApiTokens(pub UserId);
+    impl Message for ListApiTokens {
+        type Result = Result<Vec<ApiTokenInfo>, ApiError>;
+    }
+    impl Handler<ListApiTokens> for UserManager {
+        type Result = ResponseActFuture<Self, Result<Vec<ApiTokenInfo>, ApiError>>;
+
+        fn handle(&mut self, msg: ListApiTokens, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    db.api_tokens
+                        .list(&msg.0)
+                        .await
+                        .map_err(ApiError::from)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    pub struct RevokeApiToken {
+        pub user_id: UserId,
+        pub token_id: String,
+    }
+    impl Message for RevokeApiToken {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<RevokeApiToken> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: RevokeApiToken, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    if db.api_tokens.remove(&msg.user_id, &msg.token_id).await? {
+                        Ok(())
+                    } else {
+                        Err(ApiError::ApiTokenNotFound)
+                    }
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    pub struct UpdateProfile {
+        pub user_id: UserId,
+        pub update: ProfileUpdate,
+    }
+    impl Message for UpdateProfile {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<UpdateProfile> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: UpdateProfile, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let config = self.config.clone();
+            let mailer = self.mailer.clone();
+            Box::pin(
+                async move {
+                    if let Some(username) = msg.update.username {
+                        let username = config
+                            .username_policy
+                            .check(&username)
+                            .map_err(ApiError::InvalidUsername)?;
+                        let owner = db.users.get_username(&username).await?;
+                        if owner.as_ref().is_some_and(|user| user.id != msg.user_id) {
+                            return Err(ApiError::UsernameInUse);
+                        }
+                        db.users.set_username(&msg.user_id, &username).await?;
+                    }
+                    if let Some(email) = msg.update.email {
+                        let email = check_email(&email)?;
+                        let owner = db.users.get_verified_email(&email).await?;
+                        if owner.as_ref().is_some_and(|user| user.id != msg.user_id) {
+                            return Err(ApiError::EmailInUse);
+                        }
+                        // Setting the already verified address again changes nothing.
+                        let unchanged = owner.is_some_and(|user| {
+                            user.email_verified && user.email.as_deref() == Some(email.as_str())
+                        });
+                        if !unchanged {
+                            change_email(&db, &config, &mailer, msg.user_id, email).await?;
+                        }
+                    }
+                    Ok(())
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Confirms an email address with the token from the verification link.
+    pub struct VerifyEmail(pub String);
+    impl Message for VerifyEmail {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<VerifyEmail> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: VerifyEmail, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let config = self.config.clone();
+            Box::pin(
+                async move {
+                    let verification = EmailVerification::from_token(&config.secret, &msg.0)
+                        .ok_or(ApiError::InvalidToken)?;
+                    if db
+                        .users
+                        .verify_email(
+                            &verification.user_id,
+                            &verification.email,
+                            &verification.nonce,
+                        )
+                        .await?
+                    {
+                        Ok(())
+                    } else {
+                        Err(ApiError::InvalidToken)
+                    }
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Mails a reset link if a verified address matches. The work happens after replying,
+    /// so neither the reply nor its timing tells whether the account exists.
+    pub struct ForgotPasswordRequest(pub ForgotPassword);
+    impl Message for ForgotPasswordRequest {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<ForgotPasswordRequest> for UserManager {
+        type Result = Result<(), ApiError>;
+
+        fn handle(&mut self, msg: ForgotPasswordRequest, ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let mailer = self.mailer.clone();
+            ctx.spawn(
+                async move {
+                    let user = match db.users.get_verified_email(msg.0.email.trim()).await {
+                        Ok(Some(user)) => user,
+                        Ok(None) => return,
+                        Err(err) => return println!("Failed to look up account for password reset: {}", err),
+                    };
+                    let email = match user.email {
+                        Some(email) => email,
+                        None => return,
+                    };
+                    let reset = PasswordReset::new();
+                    if db
+                        .users
+                        .set_password_reset(&user.id, &reset.token_hash(), reset.expires_at)
+                        .await
+                        .is_err()
+                        || reset.send(&mailer, &email).await.is_err()
+                    {
+                        println!("Failed to send password reset mail to user {}", user.id);
+                    }
+                }
+                .into_actor(self),
+            );
+            Ok(())
+        }
+    }
+
+    pub struct ResetPasswordRequest(pub ResetPassword);
+    impl Message for ResetPasswordRequest {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<ResetPasswordRequest> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: ResetPasswordRequest, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let config = self.config.clone();
+            Box::pin(
+                async move {
+                    let token_hash = hash_token(&msg.0.token);
+                    let user = db
+                        .users
+                        .get_password_reset(&token_hash)
+                        .await?
+                        .ok_or(ApiError::InvalidToken)?;
+                    config
+                        .password_policy
+                        .check(&user.username, &msg.0.password)
+                        .map_err(ApiError::PasswordInsufficient)?;
+
+                    let password = HashedPassword::hash(msg.0.password).await?;
+                    if db.users.reset_password(&user.id, &token_hash, password).await? {
+                        Ok(())
+                    } else {
+                        Err(ApiError::InvalidToken)
+                    }
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    pub struct GetMe(pub UserId);
+    impl Message for GetMe {
+        type Result = Result<UserMe, ApiError>;
+    }
+    impl Handler<GetMe> for UserManager {
+        type Result = ResponseActFuture<Self, Result<UserMe, ApiError>>;
+
+        fn handle(&mut self, msg: GetMe, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    db.users
+                        .get_id(&msg.0)
+                        .await?
+                        .map(|user| user.to_user_me())
+                        .ok_or(ApiError::InvalidSessionToken)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    /// Changes the password after checking the current one, every other session ends.
+    pub struct ChangePassword {
+        pub user_id: UserId,
+        pub session_token: SessionToken,
+        pub change: PasswordChange,
+    }
+    impl Message for ChangePassword {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<ChangePassword> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: ChangePassword, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            let config = self.config.clone();
+            Box::pin(
+                async move {
+                    let user = db
+                        .users
+                        .get_id(&msg.user_id)
+                        .await?
+                        .ok_or(ApiError::InvalidSessionToken)?;
+                    if !user.password.verify(msg.change.current_password).await? {
+                        return Err(ApiError::IncorrectCredentials);
+                    }
+                    config
+                        .password_policy
+                        .check(&user.username, &msg.change.new_password)
+                        .map_err(ApiError::PasswordInsufficient)?;
+
+                    let password = HashedPassword::hash(msg.change.new_password).await?;
+                    db.users
+                        .set_password(&user.id, password, &msg.session_token)
+                        .await
+                        .map_err(ApiError::from)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+
+    pub struct DeleteAccount(pub UserId);
+    impl Message for DeleteAccount {
+        type Result = Result<(), ApiError>;
+    }
+    impl Handler<DeleteAccount> for UserManager {
+        type Result = ResponseActFuture<Self, Result<(), ApiError>>;
+
+        fn handle(&mut self, msg: DeleteAccount, _ctx: &mut Self::Context) -> Self::Result {
+            let db = self.db.clone();
+            Box::pin(
+                async move {
+                    db.remove_user(msg.0)
+                        .await
+                        .map_err(ApiError::from)
+                }
+                .into_actor(self),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::users::login_throttle::LoginThrottleConfig;
+    use futures::future::join_all;
+    use std::time::Duration;
+
+    /// The stores to run against: in memory, and SQLite where it's built in.
+    async fn databases(config: &Config) -> Vec<DatabaseManager> {
+        #[allow(unused_mut)]
+        let mut databases = vec![DatabaseManager::in_memory(config)];
+        #[cfg(feature = "sqlite")]
+        {
+            let db = crate::database::sql::SqlDatabase::open_sqlite(":memory:").await.unwrap();
+            databases.push(DatabaseManager::sql(db, config));
+        }
+        databases
+    }
+
+    /// Without the checks `Register` does first, so every registration but one is stopped
+    /// by a unique index.
+    #[actix_rt::test]
+    async fn unique_indexes_decide_between_concurrent_registrations() {
+        let config = Config::from_env();
+        let mailer = Mailer::stub();
+        for db in databases(&config).await {
+            let username = format!("user{}", crypto::random_digits(8));
+            let registrations = (0..8).map(|_| {
+                let password = HashedPassword::new(String::from("password"));
+                let user = BackendUserMe::new(username.clone(), password);
+                let email = Some(format!("{}@example.com", username));
+                insert_registered_user(&db, &config, &mailer, user, email, ClientInfo::default())
+            });
+            let (registered, failed): (Vec<_>, Vec<_>) = join_all(registrations)
+                .await
+                .into_iter()
+                .partition(Result::is_ok);
+            assert_eq!(registered.len(), 1);
+            assert_eq!(failed.len(), 7);
+            assert!(failed.iter().all(|res| matches!(res, Err(ApiError::UsernameInUse))));
+
+            let session = registered.into_iter().next().unwrap().unwrap();
+            let user = db.users.get_session_token(session.token).await.unwrap().unwrap();
+            assert_eq!(user.username, username);
+            assert!(db.todo.get_user_todo(user.id).await.unwrap().list.is_empty());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn external_users_get_a_free_username_and_a_list() {
+        let config = Config::from_env();
+        let mailer = Mailer::stub();
+        for db in databases(&config).await {
+            let mut alice = BackendUserMe::new(
+                String::from("alice"),
+                HashedPassword::new(String::from("password")),
+            );
+            alice.email = Some(String::from("alice@example.com"));
+            alice.email_verified = true;
+            db.users.insert(alice).await.unwrap();
+
+            let external = ExternalUser {
+                issuer: String::from("https://id.example.com"),
+                subject: String::from("1"),
+                email: Some(String::from("alice@example.com")),
+                email_verified: true,
+                preferred_username: Some(String::from("alice")),
+            };
+            let client = ClientInfo::default();
+            let session = register_external_user(&db, &config, &mailer, &external, client)
+                .await
+                .unwrap();
+            let user = db.users.get_session_token(session.token).await.unwrap().unwrap();
+            assert!(user.username.starts_with("alice") && user.username != "alice");
+            assert_eq!(user.email, None);
+            assert!(db.todo.get_user_todo(user.id).await.unwrap().list.is_empty());
+
+            // With a free address, the provider's word verifies it.
+            let external = ExternalUser {
+                subject: String::from("2"),
+                email: Some(String::from("bob@example.com")),
+                preferred_username: None,
+                ..external
+            };
+            let client = ClientInfo::default();
+            let session = register_external_user(&db, &config, &mailer, &external, client)
+                .await
+                .unwrap();
+            let bob = db.users.get_verified_email("bob@example.com").await.unwrap().unwrap();
+            assert_eq!((bob.id, bob.username.as_str()), (session.user_id, "bob"));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn concurrent_registrations_log_in_one_user() {
+        let config = Arc::new(Config::from_env());
+        let db = Arc::new(DatabaseManager::in_memory(&config));
+        let users = UserManager::new(db.clone(), config, Mailer::stub()).start();
+
+        let username = format!("user{}", crypto::random_digits(8));
+        let registrations = (0..8).map(|_| {
+            let registration = Registration {
+                username: username.clone(),
+                password: String::from("correct horse battery staple 42"),
+                email: Some(format!("{}@example.com", username)),
+            };
+            users.send(msg::Register(registration, ClientInfo::default()))
+        });
+        let (registered, failed): (Vec<_>, Vec<_>) = join_all(registrations)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .partition(Result::is_ok);
+        assert_eq!(registered.len(), 1);
+        assert!(failed
+            .iter()
+            .all(|res| matches!(res, Err(ApiError::UsernameInUse | ApiError::EmailInUse))));
+
+        let session = registered.into_iter().next().unwrap().unwrap();
+        let user = db.users.get_session_token(session.token).await.unwrap().unwrap();
+        assert_eq!(user.username, username);
+    }
+
+    #[actix_rt::test]
+    async fn login_replaces_a_keccak_hash() {
+        let config = Arc::new(Config::from_env());
+        let db = Arc::new(DatabaseManager::in_memory(&config));
+        // Keccak256 of "password", as stored by earlier versions.
+        let legacy = serde_json::from_value(serde_json::Value::String(String::from(
+            "b68fe43f0d1a0d7aef123722670be50268e15365401c442f8806ef83b612976b",
+        )))
+        .unwrap();
+        let user = BackendUserMe::new(String::from("legacy"), legacy);
+        db.users.insert(user.clone()).await.unwrap();
+        let users = UserManager::new(db.clone(), config, Mailer::stub()).start();
+
+        let auth = UserAuth {
+            username: String::from("legacy"),
+            password: String::from("password"),
+        };
+        let outcome = users.send(msg::Login(auth, ClientInfo::default())).await.unwrap();
+        assert!(matches!(outcome, Ok(LoginOutcome::Session(_))));
+        let stored = db.users.get_id(&user.id).await.unwrap().unwrap().password;
+        assert!(matches!(stored, HashedPassword::Phc(_)));
+        assert!(stored.matches("password") && !stored.needs_rehash());
+    }
+
+    #[actix_rt::test]
+    async fn concurrent_bad_logins_are_throttled() {
+        let mut config = Config::from_env();
+        config.login_throttle = LoginThrottleConfig {
+            free_attempts: 2,
+            base_delay: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(60),
+        };
+        let config = Arc::new(config);
+        let db = Arc::new(DatabaseManager::in_memory(&config));
+        let mut user = BackendUserMe::new(
+            String::from("alice"),
+            HashedPassword::hash(String::from("password 1")).await.unwrap(),
+        );
+        user.email = Some(String::from("alice@example.com"));
+        user.email_verified = true;
+        db.users.insert(user).await.unwrap();
+        let users = UserManager::new(db, config, Mailer::stub()).start();
+
+        let login = |username: &str, password: &str| {
+            let auth = UserAuth {
+                username: username.to_string(),
+                password: password.to_string(),
+            };
+            users.send(msg::Login(auth, ClientInfo::default()))
+        };
+        // By username and by email address, both count against the account.
+        let guesses = (0..6).map(|i| {
+            let username = if i % 2 == 0 { "alice" } else { "alice@example.com" };
+            login(username, &format!("guess {}", i))
+        });
+        let results: Vec<_> = join_all(guesses).await.into_iter().map(Result::unwrap).collect();
+        let throttled = results
+            .iter()
+            .filter(|res| matches!(res, Err(ApiError::TooManyRequests(_))))
+            .count();
+        assert_eq!(throttled, 3);
+        assert!(matches!(
+            login("alice", "password 1").await.unwrap(),
+            Err(ApiError::TooManyRequests(_))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn refreshing_twice_with_one_token_ends_the_session() {
+        let mut config = Config::from_env();
+        config.access_tokens.enabled = true;
+        let config = Arc::new(config);
+        let db = Arc::new(DatabaseManager::in_memory(&config));
+        let user = BackendUserMe::new(
+            String::from("alice"),
+            HashedPassword::hash(String::from("password 1")).await.unwrap(),
+        );
+        db.users.insert(user.clone()).await.unwrap();
+        let first = db.users.create_session(&user.id, ClientInfo::default()).await.unwrap().unwrap();
+        let users = UserManager::new(db, config, Mailer::stub()).start();
+
+        let refresh = |token: &SessionToken| users.send(msg::RefreshSession(token.clone()));
+        let second = refresh(&first.token).await.unwrap().unwrap();
+        assert!(matches!(refresh(&first.token).await.unwrap(), Err(ApiError::RefreshTokenReused)));
+        assert!(matches!(
+            refresh(&second.token).await.unwrap(),
+            Err(ApiError::InvalidSessionToken)
+        ));
+    }
+}
+
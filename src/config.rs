@@ -0,0 +1,66 @@
+use std::{fmt::Debug, str::FromStr};
+
+use crate::crypto;
+use crate::database::DatabaseConfig;
+use crate::api::{
+    rate_limit::RateLimitConfig,
+    TrustedProxies,
+    users::{
+        access_token::AccessTokenConfig,
+        login_throttle::LoginThrottleConfig,
+        oidc::OidcConfig,
+        policy::{PasswordPolicy, UsernamePolicy},
+        session_token::SessionConfig,
+    },
+};
+
+/// Server settings, read from the environment (and `.env`) once at startup.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub session: SessionConfig,
+    pub password_policy: PasswordPolicy,
+    pub username_policy: UsernamePolicy,
+    pub login_throttle: LoginThrottleConfig,
+    pub rate_limit: RateLimitConfig,
+    pub trusted_proxies: TrustedProxies,
+    pub access_tokens: AccessTokenConfig,
+    /// Login through an identity provider, off unless `OIDC_ISSUER` is set.
+    pub oidc: Option<OidcConfig>,
+    /// Key for signed tokens, see `crypto::secret_from_env`.
+    pub secret: Vec<u8>,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let secret = crypto::secret_from_env();
+        Config {
+            database: DatabaseConfig::from_env(),
+            session: SessionConfig::from_env(),
+            password_policy: PasswordPolicy::from_env(),
+            username_policy: UsernamePolicy::from_env(),
+            login_throttle: LoginThrottleConfig::from_env(),
+            rate_limit: RateLimitConfig::from_env(),
+            trusted_proxies: TrustedProxies::from_env(),
+            access_tokens: AccessTokenConfig::from_env(&secret),
+            oidc: OidcConfig::from_env(),
+            secret,
+        }
+    }
+}
+
+/// Parses the environment variable `key`, falling back to `default` when it is unset.
+///
+/// Panics on values that don't parse, a typo in the configuration should stop the server.
+pub fn env_var<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|err| panic!("Invalid value for {}: {:?}", key, err)),
+        Err(_) => default,
+    }
+}
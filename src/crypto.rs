@@ -0,0 +1,158 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng},
+    Aes256Gcm, Nonce,
+};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const GENERATED_SECRET_LEN: usize = 64;
+const NONCE_LEN: usize = 12;
+
+/// The key for everything the server signs, from `SERVER_SECRET`.
+///
+/// It also keys the stored session token hashes and encrypts two-factor secrets, so it
+/// must stay the same across restarts: the server doesn't start without it. Only tests
+/// use a random one.
+pub fn secret_from_env() -> Vec<u8> {
+    match std::env::var("SERVER_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+        _ if cfg!(test) => random_token(GENERATED_SECRET_LEN).into_bytes(),
+        _ => panic!(
+            "SERVER_SECRET is not set. Set it to a long random string, like the output of \
+            `openssl rand -hex 32`, and keep it: sessions and two-factor secrets depend on it"
+        ),
+    }
+}
+
+/// A random alphanumeric string, for tokens handed out to clients.
+pub fn random_token(len: usize) -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(len).collect()
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Tokens that only need to be recognized again are stored as this digest, never in plain.
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// What a token made by [`sign`] is for. Each purpose signs with its own key, so a token
+/// is only accepted for the purpose it was made for, whatever its payload looks like.
+#[derive(Clone, Copy, Debug)]
+pub enum TokenPurpose {
+    EmailVerification,
+    PendingLogin,
+    OidcFlow,
+    RecoveryCode,
+}
+
+impl TokenPurpose {
+    fn signing_key(self, key: &[u8]) -> Vec<u8> {
+        let label: &[u8] = match self {
+            TokenPurpose::EmailVerification => b"signed email verification",
+            TokenPurpose::PendingLogin => b"signed pending login",
+            TokenPurpose::OidcFlow => b"signed oidc flow",
+            TokenPurpose::RecoveryCode => b"hashed recovery code",
+        };
+        hmac_sha256(key, label)
+    }
+}
+
+/// Appends an HMAC of `payload` so it can be handed to a client and checked when it comes back.
+pub fn sign(key: &[u8], purpose: TokenPurpose, payload: &str) -> String {
+    let signature = hmac_sha256(&purpose.signing_key(key), payload.as_bytes());
+    format!(
+        "{}.{}",
+        base64::encode_config(payload, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Digest of a secret that only needs to be recognized again, keyed so a leaked digest
+/// can't be brute forced without the key.
+pub fn keyed_hash(key: &[u8], purpose: TokenPurpose, data: &[u8]) -> String {
+    to_hex(&hmac_sha256(&purpose.signing_key(key), data))
+}
+
+/// Returns the payload of a token made by [`sign`] for `purpose`, if the signature matches.
+pub fn verify(key: &[u8], purpose: TokenPurpose, token: &str) -> Option<String> {
+    let mut parts = token.splitn(2, '.');
+    let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+
+    let mut mac = HmacSha256::new_from_slice(&purpose.signing_key(key))
+        .expect("HMAC accepts keys of any length");
+    mac.update(&payload);
+    mac.verify_slice(&signature).ok()?;
+    String::from_utf8(payload).ok()
+}
+
+fn encryption_cipher(key: &[u8]) -> Aes256Gcm {
+    // Imported here, in the module it would make `new_from_slice` ambiguous for HMACs.
+    use aes_gcm::KeyInit;
+
+    // Derived, so the signing key never doubles as an encryption key.
+    let key = hmac_sha256(key, b"encryption");
+    Aes256Gcm::new_from_slice(&key).expect("HMAC-SHA256 output is a valid AES-256 key")
+}
+
+/// Encrypts secrets that the server needs to read again, like two-factor keys.
+pub fn encrypt(key: &[u8], plaintext: &[u8]) -> String {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        encryption_cipher(key)
+            .encrypt(&nonce, plaintext)
+            .expect("AES-GCM encryption of a small buffer failed"),
+    );
+    base64::encode(sealed)
+}
+
+/// Reverses [`encrypt`], failing for other keys and tampered data.
+pub fn decrypt(key: &[u8], sealed: &str) -> Option<Vec<u8>> {
+    let sealed = base64::decode(sealed).ok()?;
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    encryption_cipher(key)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()
+}
+
+/// Compares secrets without leaking through timing how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    ring::constant_time::verify_slices_are_equal(a, b).is_ok()
+}
+
+/// A random string of decimal digits, for suffixes that should look like numbers.
+pub fn random_digits(len: usize) -> String {
+    (0..len)
+        .map(|_| char::from(b'0' + thread_rng().gen_range(0, 10)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_only_verify_for_their_purpose() {
+        let token = sign(b"secret", TokenPurpose::PendingLogin, "{}");
+        assert_eq!(verify(b"secret", TokenPurpose::PendingLogin, &token).as_deref(), Some("{}"));
+        assert!(verify(b"other", TokenPurpose::PendingLogin, &token).is_none());
+        assert!(verify(b"secret", TokenPurpose::OidcFlow, &token).is_none());
+        assert!(verify(b"secret", TokenPurpose::EmailVerification, &token).is_none());
+    }
+}
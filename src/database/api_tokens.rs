@@ -0,0 +1,159 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    options::FindOptions,
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::users::{
+        api_token::{hash_api_token, ApiScope, ApiTokenInfo},
+        user::UserId,
+    },
+    database::{store::ApiTokenStore, DbResult},
+    util::unix_timestamp,
+};
+
+/// Personal API tokens, stored only as hashes.
+pub struct ApiTokenCollection {
+    collection: Collection<DbApiToken>,
+}
+
+/// The owner and permissions of an API token that was presented.
+pub struct ApiTokenAccess {
+    pub user_id: UserId,
+    pub scopes: Vec<ApiScope>,
+}
+
+impl ApiTokenCollection {
+    pub fn new(db: &Database) -> Self {
+        ApiTokenCollection {
+            collection: db.collection_with_type("api_tokens"),
+        }
+    }
+
+    pub async fn create_indexes(db: &Database) -> mongodb::error::Result<Document> {
+        db.run_command(
+            doc! {
+                "createIndexes": "api_tokens",
+                "indexes": [
+                    { "key": { "token_hash": 1 }, "name": "token_hash_unique", "unique": true },
+                    { "key": { "user_id": 1 }, "name": "user_id" },
+                ],
+            },
+            None,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl ApiTokenStore for ApiTokenCollection {
+    async fn insert(&self, user_id: &UserId, token_hash: String, info: &ApiTokenInfo) -> DbResult<()> {
+        self.collection
+            .insert_one(
+                DbApiToken {
+                    id: info.id.clone(),
+                    user_id: *user_id,
+                    name: info.name.clone(),
+                    token_hash,
+                    scopes: info.scopes.clone(),
+                    created_at: info.created_at,
+                    expires_at: info.expires_at,
+                    last_used: None,
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn count(&self, user_id: &UserId) -> DbResult<i64> {
+        Ok(self
+            .collection
+            .count_documents(doc! { "user_id": user_id.to_string() }, None)
+            .await?)
+    }
+
+    async fn list(&self, user_id: &UserId) -> DbResult<Vec<ApiTokenInfo>> {
+        let cursor = self
+            .collection
+            .find(
+                doc! { "user_id": user_id.to_string() },
+                FindOptions::builder().sort(Some(doc! { "created_at": 1 })).build(),
+            )
+            .await?;
+        Ok(cursor
+            .filter_map(|token| async move { token.ok() })
+            .map(DbApiToken::into_info)
+            .collect()
+            .await)
+    }
+
+    async fn get_access(&self, token: &str) -> DbResult<Option<ApiTokenAccess>> {
+        let now = unix_timestamp();
+        let token = self
+            .collection
+            .find_one_and_update(
+                doc! {
+                    "token_hash": hash_api_token(token),
+                    "$or": [
+                        { "expires_at": null },
+                        { "expires_at": { "$gt": now } },
+                    ],
+                },
+                doc! { "$set": { "last_used": now } },
+                None,
+            )
+            .await?;
+        Ok(token.map(|token| ApiTokenAccess {
+            user_id: token.user_id,
+            scopes: token.scopes,
+        }))
+    }
+
+    async fn remove(&self, user_id: &UserId, id: &str) -> DbResult<bool> {
+        let res = self
+            .collection
+            .delete_one(doc! { "_id": id, "user_id": user_id.to_string() }, None)
+            .await?;
+        Ok(res.deleted_count > 0)
+    }
+
+    async fn remove_all(&self, user_id: &UserId) -> DbResult<()> {
+        self.collection
+            .delete_many(doc! { "user_id": user_id.to_string() }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DbApiToken {
+    #[serde(rename = "_id")]
+    id: String,
+    user_id: UserId,
+    name: String,
+    token_hash: String,
+    scopes: Vec<ApiScope>,
+    created_at: i64,
+    #[serde(default)]
+    expires_at: Option<i64>,
+    #[serde(default)]
+    last_used: Option<i64>,
+}
+
+impl DbApiToken {
+    fn into_info(self) -> ApiTokenInfo {
+        ApiTokenInfo {
+            id: self.id,
+            name: self.name,
+            scopes: self.scopes,
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            last_used: self.last_used,
+        }
+    }
+}
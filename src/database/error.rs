@@ -0,0 +1,56 @@
+use mongodb::{
+    bson,
+    error::{Error, ErrorKind, WriteFailure},
+};
+use thiserror::Error;
+
+pub type DbResult<T> = Result<T, DbError>;
+
+/// Why a database operation failed. Not finding anything is not an error, lookups
+/// return `Ok(None)` or `Ok(false)` for that.
+#[derive(Debug, Error)]
+pub enum DbError {
+    /// A write was rejected by the unique index with this name.
+    #[error("duplicate key in index {0}")]
+    DuplicateKey(String),
+    #[error("mongodb error: {0}")]
+    Mongo(Error),
+    #[error("failed to convert to bson: {0}")]
+    Bson(#[from] bson::ser::Error),
+    #[cfg(any(feature = "sqlite", feature = "postgres"))]
+    #[error("sql error: {0}")]
+    Sql(String),
+    #[cfg(any(feature = "sqlite", feature = "postgres"))]
+    #[error("failed to convert to json: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl From<Error> for DbError {
+    fn from(err: Error) -> Self {
+        match duplicate_key_index(&err) {
+            Some(index) => DbError::DuplicateKey(index),
+            None => DbError::Mongo(err),
+        }
+    }
+}
+
+/// The index named in the server message of a write rejected by a unique index.
+fn duplicate_key_index(err: &Error) -> Option<String> {
+    const DUPLICATE_KEY: i32 = 11000;
+    let message = match err.kind.as_ref() {
+        ErrorKind::WriteError(WriteFailure::WriteError(err)) if err.code == DUPLICATE_KEY => {
+            &err.message
+        }
+        ErrorKind::CommandError(err) if err.code == DUPLICATE_KEY => &err.message,
+        _ => return None,
+    };
+    // "E11000 duplicate key error collection: TODO.users index: email_key_unique dup key: ..."
+    Some(
+        message
+            .split("index: ")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .unwrap_or_default()
+            .to_string(),
+    )
+}
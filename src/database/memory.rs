@@ -0,0 +1,657 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+
+use super::{
+    api_tokens::ApiTokenAccess,
+    store::{ApiTokenStore, Rotation, TodoStore, UserStore},
+    user_todo::{Todo, TodoItem, TodoItemId, TodoItemPatch},
+    users::MAX_ROTATED_HASHES,
+    DbError, DbResult,
+};
+use crate::{
+    api::users::{
+        api_token::{hash_api_token, ApiTokenInfo},
+        email::email_key,
+        oidc::ExternalIdentity,
+        policy::username_key,
+        session_token::{
+            fingerprint, new_session_id, ClientInfo, NewSession, SessionConfig, SessionInfo,
+            SessionToken,
+        },
+        totp::TotpEnrollment,
+        user::{BackendUserMe, HashedPassword, UserId},
+    },
+    util::unix_timestamp,
+};
+
+/// Keeps users in memory, with the same behaviour as `UserCollection`.
+///
+/// A single lock guards all users, so uniqueness checks and writes happen at once.
+pub struct MemoryUserStore {
+    users: Mutex<HashMap<UserId, MemoryUser>>,
+    session_config: SessionConfig,
+    session_secret: Vec<u8>,
+}
+
+struct MemoryUser {
+    user: BackendUserMe,
+    email_verification_nonce: Option<String>,
+    /// Token hash and expiry of the pending password reset.
+    password_reset: Option<(String, i64)>,
+    identities: Vec<ExternalIdentity>,
+    /// Oldest first.
+    sessions: Vec<MemorySession>,
+}
+
+struct MemorySession {
+    id: String,
+    token_hash: String,
+    created_at: i64,
+    last_seen: i64,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    rotated_hashes: Vec<String>,
+}
+
+impl MemoryUserStore {
+    pub fn new(session_config: SessionConfig, session_secret: Vec<u8>) -> Self {
+        MemoryUserStore {
+            users: Mutex::new(HashMap::new()),
+            session_config,
+            session_secret,
+        }
+    }
+
+    fn users(&self) -> MutexGuard<'_, HashMap<UserId, MemoryUser>> {
+        self.users.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn is_live(&self, session: &MemorySession, now: i64) -> bool {
+        session.created_at > now - self.session_config.absolute_timeout.as_secs() as i64
+            && session.last_seen > now - self.session_config.idle_timeout.as_secs() as i64
+    }
+
+    fn find(&self, matches: impl Fn(&MemoryUser) -> bool) -> Option<BackendUserMe> {
+        self.users()
+            .values()
+            .find(|user| matches(user))
+            .map(|user| user.user.clone())
+    }
+
+    fn insert_user(
+        &self,
+        user: BackendUserMe,
+        email_verification_nonce: Option<String>,
+        sessions: Vec<MemorySession>,
+    ) -> DbResult<()> {
+        let mut users = self.users();
+        if users.contains_key(&user.id) {
+            return Err(DbError::DuplicateKey(String::from("_id_")));
+        }
+        let verified_email = user.email.as_deref().filter(|_| user.email_verified);
+        check_unique(&users, &user.id, Some(&user.username), verified_email, None)?;
+        users.insert(
+            user.id,
+            MemoryUser {
+                user,
+                email_verification_nonce,
+                password_reset: None,
+                identities: vec![],
+                sessions,
+            },
+        );
+        Ok(())
+    }
+
+    fn new_session(&self, client: ClientInfo) -> (SessionToken, MemorySession) {
+        let now = unix_timestamp();
+        let session_token = SessionToken::new();
+        let session = MemorySession {
+            id: new_session_id(),
+            token_hash: session_token.hash(&self.session_secret),
+            created_at: now,
+            last_seen: now,
+            user_agent: client.user_agent,
+            ip: client.ip,
+            rotated_hashes: vec![],
+        };
+        (session_token, session)
+    }
+
+    /// Applies `update` to the user, `None` if it doesn't exist.
+    fn update<T>(&self, id: &UserId, update: impl FnOnce(&mut MemoryUser) -> T) -> Option<T> {
+        self.users().get_mut(id).map(update)
+    }
+}
+
+/// Fails like the unique index of `UserCollection` that another user than `id` would break.
+/// Only verified email addresses are unique.
+fn check_unique(
+    users: &HashMap<UserId, MemoryUser>,
+    id: &UserId,
+    username: Option<&str>,
+    email: Option<&str>,
+    identity: Option<&ExternalIdentity>,
+) -> DbResult<()> {
+    let username = username.map(username_key);
+    let email = email.map(email_key);
+    for other in users.values().filter(|other| other.user.id != *id) {
+        if username.is_some() && username == Some(username_key(&other.user.username)) {
+            return Err(DbError::DuplicateKey(String::from("username_key_unique")));
+        }
+        if email.is_some()
+            && other.user.email_verified
+            && email == other.user.email.as_deref().map(email_key)
+        {
+            return Err(DbError::DuplicateKey(String::from("email_key_unique")));
+        }
+        if identity.is_some_and(|identity| other.identities.contains(identity)) {
+            return Err(DbError::DuplicateKey(String::from("identity_unique")));
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn get_id(&self, id: &UserId) -> DbResult<Option<BackendUserMe>> {
+        Ok(self.users().get(id).map(|user| user.user.clone()))
+    }
+
+    async fn get_verified_email(&self, email: &str) -> DbResult<Option<BackendUserMe>> {
+        let key = email_key(email);
+        Ok(self.find(|user| {
+            user.user.email_verified && user.user.email.as_deref().map(email_key).as_ref() == Some(&key)
+        }))
+    }
+
+    async fn get_username(&self, username: &str) -> DbResult<Option<BackendUserMe>> {
+        let key = username_key(username);
+        Ok(self.find(|user| username_key(&user.user.username) == key))
+    }
+
+    async fn get_identity(&self, identity: &ExternalIdentity) -> DbResult<Option<BackendUserMe>> {
+        Ok(self.find(|user| user.identities.contains(identity)))
+    }
+
+    async fn link_identity(&self, id: &UserId, identity: &ExternalIdentity) -> DbResult<()> {
+        let mut users = self.users();
+        check_unique(&users, id, None, None, Some(identity))?;
+        if let Some(user) = users.get_mut(id) {
+            if !user.identities.contains(identity) {
+                user.identities.push(identity.clone());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    async fn insert(&self, user: BackendUserMe) -> DbResult<()> {
+        self.insert_user(user, None, vec![])
+    }
+
+    async fn insert_with_session(
+        &self,
+        user: BackendUserMe,
+        email_nonce: Option<&str>,
+        client: ClientInfo,
+    ) -> DbResult<NewSession> {
+        let user_id = user.id;
+        let (token, session) = self.new_session(client);
+        let session_id = session.id.clone();
+        self.insert_user(user, email_nonce.map(str::to_string), vec![session])?;
+        Ok(NewSession {
+            user_id,
+            session_id,
+            token,
+        })
+    }
+
+    async fn remove(&self, id: &UserId) -> DbResult<()> {
+        self.users().remove(id);
+        Ok(())
+    }
+
+    async fn set_email(&self, id: &UserId, email: &str, nonce: &str) -> DbResult<()> {
+        if let Some(user) = self.users().get_mut(id) {
+            user.user.email = Some(email.to_string());
+            user.user.email_verified = false;
+            user.email_verification_nonce = Some(nonce.to_string());
+        }
+        Ok(())
+    }
+
+    async fn verify_email(&self, id: &UserId, email: &str, nonce: &str) -> DbResult<bool> {
+        let mut users = self.users();
+        let current = users.get(id).is_some_and(|user| {
+            user.user.email.as_deref() == Some(email)
+                && user.email_verification_nonce.as_deref() == Some(nonce)
+        });
+        if !current {
+            return Ok(false);
+        }
+        check_unique(&users, id, None, Some(email), None)?;
+        if let Some(user) = users.get_mut(id) {
+            user.user.email_verified = true;
+            user.email_verification_nonce = None;
+        }
+        Ok(true)
+    }
+
+    async fn set_username(&self, id: &UserId, username: &str) -> DbResult<()> {
+        let mut users = self.users();
+        check_unique(&users, id, Some(username), None, None)?;
+        if let Some(user) = users.get_mut(id) {
+            user.user.username = username.to_string();
+        }
+        Ok(())
+    }
+
+    async fn set_password_hash(&self, id: &UserId, password: &HashedPassword) -> DbResult<()> {
+        self.update(id, |user| user.user.password = password.clone());
+        Ok(())
+    }
+
+    async fn set_password(&self, id: &UserId, password: HashedPassword, keep: &SessionToken) -> DbResult<()> {
+        let keep = keep.hash(&self.session_secret);
+        self.update(id, |user| {
+            user.user.password = password;
+            user.sessions.retain(|session| session.token_hash == keep);
+        });
+        Ok(())
+    }
+
+    async fn set_password_reset(&self, id: &UserId, token_hash: &str, expires_at: i64) -> DbResult<()> {
+        self.update(id, |user| user.password_reset = Some((token_hash.to_string(), expires_at)));
+        Ok(())
+    }
+
+    async fn get_password_reset(&self, token_hash: &str) -> DbResult<Option<BackendUserMe>> {
+        let now = unix_timestamp();
+        Ok(self.find(|user| {
+            user.password_reset
+                .as_ref()
+                .is_some_and(|(hash, expires_at)| hash == token_hash && *expires_at > now)
+        }))
+    }
+
+    async fn reset_password(&self, id: &UserId, token_hash: &str, password: HashedPassword) -> DbResult<bool> {
+        let now = unix_timestamp();
+        let reset = self.update(id, |user| {
+            let valid = user
+                .password_reset
+                .as_ref()
+                .is_some_and(|(hash, expires_at)| hash == token_hash && *expires_at > now);
+            if valid {
+                user.user.password = password;
+                user.sessions.clear();
+                user.password_reset = None;
+            }
+            valid
+        });
+        Ok(reset.unwrap_or(false))
+    }
+
+    async fn get_session_token(&self, session_token: SessionToken) -> DbResult<Option<BackendUserMe>> {
+        let now = unix_timestamp();
+        let token_hash = session_token.hash(&self.session_secret);
+        let mut users = self.users();
+        for user in users.values_mut() {
+            let session = user
+                .sessions
+                .iter_mut()
+                .find(|session| session.token_hash == token_hash);
+            if let Some(session) = session {
+                if !self.is_live(session, now) {
+                    return Ok(None);
+                }
+                session.last_seen = now;
+                return Ok(Some(user.user.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn create_session(&self, id: &UserId, client: ClientInfo) -> DbResult<Option<NewSession>> {
+        let (session_token, session) = self.new_session(client);
+        let session_id = session.id.clone();
+        let max_sessions = self.session_config.max_sessions_per_user;
+        let created = self.update(id, |user| {
+            user.sessions.push(session);
+            user.sessions.sort_by_key(|session| session.created_at);
+            let evicted = user.sessions.len().saturating_sub(max_sessions);
+            user.sessions.drain(..evicted);
+        });
+        Ok(created.map(|()| NewSession {
+            user_id: *id,
+            session_id,
+            token: session_token,
+        }))
+    }
+
+    async fn rotate_session_token(&self, session_token: &SessionToken) -> DbResult<Rotation> {
+        let now = unix_timestamp();
+        let old_hash = session_token.hash(&self.session_secret);
+        let new_token = SessionToken::new();
+        let mut users = self.users();
+        for user in users.values_mut() {
+            if let Some(session) = user.sessions.iter_mut().find(|session| session.token_hash == old_hash) {
+                if !self.is_live(session, now) {
+                    break;
+                }
+                session.token_hash = new_token.hash(&self.session_secret);
+                session.last_seen = now;
+                session.rotated_hashes.push(old_hash);
+                let forgotten = session.rotated_hashes.len().saturating_sub(MAX_ROTATED_HASHES as usize);
+                session.rotated_hashes.drain(..forgotten);
+                return Ok(Rotation::Rotated(NewSession {
+                    user_id: user.user.id,
+                    session_id: session.id.clone(),
+                    token: new_token,
+                }));
+            }
+        }
+
+        for user in users.values_mut() {
+            let sessions = user.sessions.len();
+            user.sessions
+                .retain(|session| !session.rotated_hashes.contains(&old_hash));
+            if user.sessions.len() < sessions {
+                return Ok(Rotation::Reused);
+            }
+        }
+        Ok(Rotation::Unknown)
+    }
+
+    async fn remove_session_token(&self, session_token: SessionToken) -> DbResult<()> {
+        let token_hash = session_token.hash(&self.session_secret);
+        for user in self.users().values_mut() {
+            user.sessions.retain(|session| session.token_hash != token_hash);
+        }
+        Ok(())
+    }
+
+    async fn list_sessions(&self, id: &UserId, current: &SessionToken) -> DbResult<Vec<SessionInfo>> {
+        let now = unix_timestamp();
+        let current_hash = current.hash(&self.session_secret);
+        Ok(self
+            .users()
+            .get(id)
+            .map(|user| {
+                user.sessions
+                    .iter()
+                    .filter(|session| self.is_live(session, now))
+                    .map(|session| SessionInfo {
+                        id: session.id.clone(),
+                        fingerprint: fingerprint(&session.token_hash),
+                        created_at: session.created_at,
+                        last_seen: session.last_seen,
+                        user_agent: session.user_agent.clone(),
+                        ip: session.ip.clone(),
+                        current: session.token_hash == current_hash,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn has_session(&self, id: &UserId, session_id: &str) -> DbResult<bool> {
+        let now = unix_timestamp();
+        Ok(self.users().get(id).is_some_and(|user| {
+            user.sessions
+                .iter()
+                .any(|session| session.id == session_id && self.is_live(session, now))
+        }))
+    }
+
+    async fn remove_session(&self, id: &UserId, session_id: &str) -> DbResult<bool> {
+        let removed = self.update(id, |user| {
+            let sessions = user.sessions.len();
+            user.sessions.retain(|session| session.id != session_id);
+            user.sessions.len() < sessions
+        });
+        Ok(removed.unwrap_or(false))
+    }
+
+    async fn remove_all_sessions(&self, id: &UserId) -> DbResult<()> {
+        self.update(id, |user| user.sessions.clear());
+        Ok(())
+    }
+
+    async fn prune_expired_sessions(&self) -> DbResult<()> {
+        let now = unix_timestamp();
+        for user in self.users().values_mut() {
+            user.sessions.retain(|session| self.is_live(session, now));
+        }
+        Ok(())
+    }
+
+    async fn set_pending_totp(&self, id: &UserId, totp: &TotpEnrollment) -> DbResult<bool> {
+        let set = self.update(id, |user| {
+            if user.user.totp.as_ref().is_some_and(|totp| totp.confirmed) {
+                return false;
+            }
+            user.user.totp = Some(totp.clone());
+            true
+        });
+        Ok(set.unwrap_or(false))
+    }
+
+    async fn confirm_totp(&self, id: &UserId, step: i64, recovery_code_hashes: Vec<String>) -> DbResult<bool> {
+        let confirmed = self.update(id, |user| match &mut user.user.totp {
+            Some(totp) if !totp.confirmed => {
+                totp.confirmed = true;
+                totp.last_step = step;
+                totp.recovery_codes = recovery_code_hashes;
+                true
+            }
+            _ => false,
+        });
+        Ok(confirmed.unwrap_or(false))
+    }
+
+    async fn use_totp_step(&self, id: &UserId, step: i64) -> DbResult<bool> {
+        let used = self.update(id, |user| match &mut user.user.totp {
+            Some(totp) if totp.last_step < step => {
+                totp.last_step = step;
+                true
+            }
+            _ => false,
+        });
+        Ok(used.unwrap_or(false))
+    }
+
+    async fn use_recovery_code(&self, id: &UserId, code_hash: &str) -> DbResult<bool> {
+        let used = self.update(id, |user| {
+            let totp = match &mut user.user.totp {
+                Some(totp) => totp,
+                None => return false,
+            };
+            match totp.recovery_codes.iter().position(|hash| hash == code_hash) {
+                Some(index) => {
+                    totp.recovery_codes.remove(index);
+                    true
+                }
+                None => false,
+            }
+        });
+        Ok(used.unwrap_or(false))
+    }
+
+    async fn remove_totp(&self, id: &UserId) -> DbResult<()> {
+        self.update(id, |user| user.user.totp = None);
+        Ok(())
+    }
+}
+
+/// Keeps todo lists in memory, like `UserTodo`.
+#[derive(Default)]
+pub struct MemoryTodoStore {
+    lists: DashMap<UserId, Vec<TodoItem>>,
+}
+
+#[async_trait]
+impl TodoStore for MemoryTodoStore {
+    async fn get_user_todo(&self, user_id: UserId) -> DbResult<Todo> {
+        let list = self
+            .lists
+            .get(&user_id)
+            .map(|list| list.clone())
+            .unwrap_or_default();
+        Ok(Todo { list })
+    }
+
+    async fn create_user_todo(&self, user_id: UserId) -> DbResult<()> {
+        self.lists.entry(user_id).or_default();
+        Ok(())
+    }
+
+    async fn add_to_todo(
+        &self,
+        user_id: UserId,
+        title: String,
+        description: Option<String>,
+    ) -> DbResult<Option<TodoItem>> {
+        let item = TodoItem::new(title, description);
+        let mut list = match self.lists.get_mut(&user_id) {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        list.push(item.clone());
+        Ok(Some(item))
+    }
+
+    async fn get_todo_item(&self, user_id: UserId, item_id: &TodoItemId) -> DbResult<Option<TodoItem>> {
+        Ok(self
+            .lists
+            .get(&user_id)
+            .and_then(|list| list.iter().find(|item| item.id == *item_id).cloned()))
+    }
+
+    async fn update_todo_item(
+        &self,
+        user_id: UserId,
+        item_id: &TodoItemId,
+        patch: TodoItemPatch,
+    ) -> DbResult<Option<TodoItem>> {
+        let now = unix_timestamp();
+        let mut list = match self.lists.get_mut(&user_id) {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        let item = match list.iter_mut().find(|item| item.id == *item_id) {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        item.updated_at = now;
+        if let Some(title) = patch.title {
+            item.title = title;
+        }
+        if let Some(description) = patch.description {
+            item.description = description;
+        }
+        if let Some(completed) = patch.completed {
+            item.completed = completed;
+            item.completed_at = if completed { Some(now) } else { None };
+        }
+        Ok(Some(item.clone()))
+    }
+
+    async fn remove_todo_item(&self, user_id: UserId, item_id: &TodoItemId) -> DbResult<bool> {
+        Ok(self.lists.get_mut(&user_id).is_some_and(|mut list| {
+            let items = list.len();
+            list.retain(|item| item.id != *item_id);
+            list.len() < items
+        }))
+    }
+
+    async fn remove_user_todo(&self, user_id: UserId) -> DbResult<()> {
+        self.lists.remove(&user_id);
+        Ok(())
+    }
+}
+
+/// Keeps API tokens in memory, like `ApiTokenCollection`.
+#[derive(Default)]
+pub struct MemoryApiTokenStore {
+    /// By token id.
+    tokens: DashMap<String, MemoryApiToken>,
+}
+
+struct MemoryApiToken {
+    user_id: UserId,
+    token_hash: String,
+    info: ApiTokenInfo,
+}
+
+#[async_trait]
+impl ApiTokenStore for MemoryApiTokenStore {
+    async fn insert(&self, user_id: &UserId, token_hash: String, info: &ApiTokenInfo) -> DbResult<()> {
+        self.tokens.insert(
+            info.id.clone(),
+            MemoryApiToken {
+                user_id: *user_id,
+                token_hash,
+                info: info.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn count(&self, user_id: &UserId) -> DbResult<i64> {
+        Ok(self
+            .tokens
+            .iter()
+            .filter(|token| token.user_id == *user_id)
+            .count() as i64)
+    }
+
+    async fn list(&self, user_id: &UserId) -> DbResult<Vec<ApiTokenInfo>> {
+        let mut tokens: Vec<_> = self
+            .tokens
+            .iter()
+            .filter(|token| token.user_id == *user_id)
+            .map(|token| token.info.clone())
+            .collect();
+        tokens.sort_by_key(|info| info.created_at);
+        Ok(tokens)
+    }
+
+    async fn get_access(&self, token: &str) -> DbResult<Option<ApiTokenAccess>> {
+        let now = unix_timestamp();
+        let token_hash = hash_api_token(token);
+        let mut token = match self
+            .tokens
+            .iter_mut()
+            .find(|stored| stored.token_hash == token_hash)
+        {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        if token.info.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Ok(None);
+        }
+        token.info.last_used = Some(now);
+        Ok(Some(ApiTokenAccess {
+            user_id: token.user_id,
+            scopes: token.info.scopes.clone(),
+        }))
+    }
+
+    async fn remove(&self, user_id: &UserId, id: &str) -> DbResult<bool> {
+        Ok(self
+            .tokens
+            .remove_if(id, |_, token| token.user_id == *user_id)
+            .is_some())
+    }
+
+    async fn remove_all(&self, user_id: &UserId) -> DbResult<()> {
+        self.tokens.retain(|_, token| token.user_id != *user_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use futures::{future::BoxFuture, StreamExt};
+use mongodb::{bson::doc, options::FindOptions, Database};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    store::TodoStore, user_todo::UserTodo, users::UserCollection, DbError, DbResult,
+};
+use crate::{api::users::user::UserId, config::Config, util::unix_timestamp};
+
+/// Changes to stored documents, applied once each and in order when the server starts.
+///
+/// Document shapes live in serde structs like `DbUser` and `TodoStorage`. When one changes
+/// in a way old documents don't deserialize into, add a migration that rewrites them at
+/// the end of this list. Released migrations must never change or move, and each must be
+/// safe to run twice: a server stopped part way repeats it on the next start.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "backfill username keys",
+        run: backfill_username_keys,
+    },
+    Migration {
+        version: 2,
+        name: "hash plain session tokens",
+        run: hash_plain_session_tokens,
+    },
+    Migration {
+        version: 3,
+        name: "release unverified email addresses",
+        run: release_unverified_emails,
+    },
+    Migration {
+        version: 4,
+        name: "create missing todo lists",
+        run: create_missing_todo_lists,
+    },
+];
+
+/// The collection recording which migrations were applied.
+const COLLECTION: &str = "_migrations";
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    run: for<'a> fn(&'a Database, &'a Config) -> BoxFuture<'a, DbResult<()>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppliedMigration {
+    #[serde(rename = "_id")]
+    version: i64,
+    name: String,
+    applied_at: i64,
+}
+
+/// The migrations not applied to `db` yet, in the order they will run.
+pub async fn pending(db: &Database) -> DbResult<Vec<&'static Migration>> {
+    let mut cursor = db
+        .collection_with_type::<AppliedMigration>(COLLECTION)
+        .find(None, None)
+        .await?;
+    let mut applied = vec![];
+    while let Some(migration) = cursor.next().await {
+        applied.push(migration?.version);
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+/// Applies the pending migrations, stopping at the first that fails so it's retried
+/// on the next start.
+pub async fn run(db: &Database, config: &Config) -> DbResult<()> {
+    let migrations = db.collection_with_type::<AppliedMigration>(COLLECTION);
+    for migration in pending(db).await? {
+        println!("Applying migration {}: {}", migration.version, migration.name);
+        (migration.run)(db, config).await?;
+        let applied = AppliedMigration {
+            version: migration.version,
+            name: migration.name.to_string(),
+            applied_at: unix_timestamp(),
+        };
+        match migrations.insert_one(applied, None).await.map_err(DbError::from) {
+            // Another server applied it at the same time.
+            Ok(_) | Err(DbError::DuplicateKey(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn users(db: &Database, config: &Config) -> UserCollection {
+    UserCollection::new(db, config.session.clone(), config.secret.clone())
+}
+
+fn backfill_username_keys<'a>(db: &'a Database, config: &'a Config) -> BoxFuture<'a, DbResult<()>> {
+    Box::pin(async move { users(db, config).backfill_username_keys().await })
+}
+
+fn hash_plain_session_tokens<'a>(db: &'a Database, config: &'a Config) -> BoxFuture<'a, DbResult<()>> {
+    Box::pin(async move { users(db, config).hash_plain_session_tokens().await })
+}
+
+fn release_unverified_emails<'a>(db: &'a Database, config: &'a Config) -> BoxFuture<'a, DbResult<()>> {
+    Box::pin(async move { users(db, config).release_unverified_emails().await })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UserIdOnly {
+    #[serde(rename = "_id")]
+    id: UserId,
+}
+
+/// Lists used to be created by the first item, adding items now needs one.
+fn create_missing_todo_lists<'a>(db: &'a Database, _config: &'a Config) -> BoxFuture<'a, DbResult<()>> {
+    Box::pin(async move {
+        let todo = UserTodo::new(db);
+        let mut users = db
+            .collection_with_type::<UserIdOnly>("users")
+            .find(
+                None,
+                FindOptions::builder().projection(Some(doc! { "_id": 1 })).build(),
+            )
+            .await?;
+        while let Some(user) = users.next().await {
+            todo.create_user_todo(user?.id).await?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::mongodb_test_database;
+
+    #[test]
+    fn versions_increase_by_one() {
+        for (version, migration) in (1..).zip(MIGRATIONS) {
+            assert_eq!(migration.version, version, "{}", migration.name);
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs MongoDB at MONGO_URL"]
+    async fn applies_each_migration_once() {
+        let config = Config::from_env();
+        let db = mongodb_test_database().await;
+
+        assert_eq!(pending(&db).await.unwrap().len(), MIGRATIONS.len());
+        run(&db, &config).await.unwrap();
+        assert!(pending(&db).await.unwrap().is_empty());
+        run(&db, &config).await.unwrap();
+        db.drop(None).await.unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use async_trait::async_trait;
+
+use super::{params, SqlDatabase};
+use crate::{
+    api::users::{
+        api_token::{hash_api_token, ApiTokenInfo},
+        user::UserId,
+    },
+    database::{api_tokens::ApiTokenAccess, store::ApiTokenStore, DbResult},
+    util::unix_timestamp,
+};
+
+/// Personal API tokens in the `api_tokens` table, stored only as hashes.
+pub struct SqlApiTokenStore {
+    db: SqlDatabase,
+}
+
+impl SqlApiTokenStore {
+    pub fn new(db: SqlDatabase) -> Self {
+        SqlApiTokenStore { db }
+    }
+}
+
+#[async_trait]
+impl ApiTokenStore for SqlApiTokenStore {
+    async fn insert(&self, user_id: &UserId, token_hash: String, info: &ApiTokenInfo) -> DbResult<()> {
+        let user_id = *user_id;
+        let info = info.clone();
+        self.db
+            .transaction(move |conn| {
+                conn.execute(
+                    "INSERT INTO api_tokens (id, user_id, token_hash, name, scopes, created_at, expires_at)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)",
+                    params![
+                        info.id,
+                        user_id.to_string(),
+                        token_hash,
+                        info.name,
+                        serde_json::to_string(&info.scopes)?,
+                        info.created_at,
+                        info.expires_at,
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn count(&self, user_id: &UserId) -> DbResult<i64> {
+        let user_id = *user_id;
+        self.db
+            .transaction(move |conn| {
+                match conn.query_one(
+                    "SELECT COUNT(*) FROM api_tokens WHERE user_id = ?",
+                    params![user_id.to_string()],
+                )? {
+                    Some(row) => row.int(0),
+                    None => Ok(0),
+                }
+            })
+            .await
+    }
+
+    async fn list(&self, user_id: &UserId) -> DbResult<Vec<ApiTokenInfo>> {
+        let user_id = *user_id;
+        self.db
+            .transaction(move |conn| {
+                conn.query(
+                    "SELECT id, name, scopes, created_at, expires_at, last_used FROM api_tokens
+                    WHERE user_id = ? ORDER BY created_at",
+                    params![user_id.to_string()],
+                )?
+                .iter()
+                .map(|row| {
+                    Ok(ApiTokenInfo {
+                        id: row.text(0)?,
+                        name: row.text(1)?,
+                        scopes: row.json(2)?,
+                        created_at: row.int(3)?,
+                        expires_at: row.opt_int(4)?,
+                        last_used: row.opt_int(5)?,
+                    })
+                })
+                .collect()
+            })
+            .await
+    }
+
+    async fn get_access(&self, token: &str) -> DbResult<Option<ApiTokenAccess>> {
+        let now = unix_timestamp();
+        let token_hash = hash_api_token(token);
+        self.db
+            .transaction(move |conn| {
+                let token = conn.query_one(
+                    "SELECT id, user_id, scopes FROM api_tokens
+                    WHERE token_hash = ? AND (expires_at IS NULL OR expires_at > ?)",
+                    params![token_hash, now],
+                )?;
+                let token = match token {
+                    Some(token) => token,
+                    None => return Ok(None),
+                };
+                conn.execute(
+                    "UPDATE api_tokens SET last_used = ? WHERE id = ?",
+                    params![now, token.text(0)?],
+                )?;
+                Ok(Some(ApiTokenAccess {
+                    user_id: token.parse(1)?,
+                    scopes: token.json(2)?,
+                }))
+            })
+            .await
+    }
+
+    async fn remove(&self, user_id: &UserId, id: &str) -> DbResult<bool> {
+        let user_id = *user_id;
+        let id = id.to_string();
+        self.db
+            .transaction(move |conn| {
+                Ok(conn.execute(
+                    "DELETE FROM api_tokens WHERE id = ? AND user_id = ?",
+                    params![id, user_id.to_string()],
+                )? > 0)
+            })
+            .await
+    }
+
+    async fn remove_all(&self, user_id: &UserId) -> DbResult<()> {
+        let user_id = *user_id;
+        self.db
+            .transaction(move |conn| {
+                conn.execute(
+                    "DELETE FROM api_tokens WHERE user_id = ?",
+                    params![user_id.to_string()],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
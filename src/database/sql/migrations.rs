@@ -0,0 +1,107 @@
+use super::{params, SqlConnection};
+use crate::{database::DbResult, util::unix_timestamp};
+
+/// Schema changes in order, version `n` is at index `n - 1`. Each runs once per database,
+/// so released ones must never change, add a new one instead.
+///
+/// Constraints are named like the MongoDB indexes, see `DbError::DuplicateKey`.
+const MIGRATIONS: &[&str] = &[
+    // 1: the initial schema
+    "
+    CREATE TABLE users (
+        id TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        username_key TEXT NOT NULL CONSTRAINT username_key_unique UNIQUE,
+        password TEXT NOT NULL,
+        email TEXT,
+        email_key TEXT CONSTRAINT email_key_unique UNIQUE,
+        email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+        email_verification_nonce TEXT,
+        password_reset_hash TEXT,
+        password_reset_expires_at BIGINT,
+        totp_secret TEXT,
+        totp_confirmed BOOLEAN,
+        totp_last_step BIGINT
+    );
+    CREATE TABLE totp_recovery_codes (
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        code_hash TEXT NOT NULL
+    );
+    CREATE INDEX totp_recovery_codes_user_id ON totp_recovery_codes (user_id);
+    CREATE TABLE user_identities (
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        issuer TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        CONSTRAINT identity_unique UNIQUE (issuer, subject)
+    );
+    CREATE TABLE sessions (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        seq BIGINT NOT NULL,
+        token_hash TEXT NOT NULL CONSTRAINT session_token_hash_unique UNIQUE,
+        created_at BIGINT NOT NULL,
+        last_seen BIGINT NOT NULL,
+        user_agent TEXT,
+        ip TEXT
+    );
+    CREATE INDEX sessions_user_id ON sessions (user_id, seq);
+    CREATE TABLE rotated_session_hashes (
+        session_id TEXT NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+        seq BIGINT NOT NULL,
+        token_hash TEXT NOT NULL
+    );
+    CREATE INDEX rotated_session_hashes_token_hash ON rotated_session_hashes (token_hash);
+    CREATE INDEX rotated_session_hashes_session_id ON rotated_session_hashes (session_id, seq);
+    CREATE TABLE todo_items (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        seq BIGINT NOT NULL,
+        title TEXT NOT NULL,
+        description TEXT,
+        completed BOOLEAN NOT NULL,
+        created_at BIGINT NOT NULL,
+        updated_at BIGINT NOT NULL,
+        completed_at BIGINT
+    );
+    CREATE INDEX todo_items_user_id ON todo_items (user_id, seq);
+    CREATE TABLE api_tokens (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        token_hash TEXT NOT NULL CONSTRAINT token_hash_unique UNIQUE,
+        name TEXT NOT NULL,
+        scopes TEXT NOT NULL,
+        created_at BIGINT NOT NULL,
+        expires_at BIGINT,
+        last_used BIGINT
+    );
+    CREATE INDEX api_tokens_user_id ON api_tokens (user_id);
+    ",
+    // 2: unverified email addresses no longer reserve the address
+    "
+    UPDATE users SET email_key = NULL WHERE NOT email_verified;
+    ",
+];
+
+/// Brings the schema up to date.
+pub fn run(conn: &mut dyn SqlConnection) -> DbResult<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            applied_at BIGINT NOT NULL
+        )",
+    )?;
+    let current = match conn.query_one("SELECT MAX(version) FROM schema_migrations", &[])? {
+        Some(row) => row.opt_int(0)?.unwrap_or(0),
+        None => 0,
+    };
+
+    for (version, migration) in (1_i64..).zip(MIGRATIONS).skip(current as usize) {
+        println!("Migrating the database schema to version {}", version);
+        conn.execute_batch(migration)?;
+        conn.execute(
+            "INSERT INTO schema_migrations (version, applied_at) VALUES (?, ?)",
+            params![version, unix_timestamp()],
+        )?;
+    }
+    Ok(())
+}
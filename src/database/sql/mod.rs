@@ -0,0 +1,391 @@
+//! Storage in an SQL database, for deployments that can't run MongoDB.
+//!
+//! The stores write SQL that SQLite and PostgreSQL both understand, with `?` placeholders.
+//! Each backend only provides a `SqlConnection`.
+
+mod api_tokens;
+mod migrations;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod users;
+mod user_todo;
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use actix_web::{error::BlockingError, web};
+use serde::de::DeserializeOwned;
+
+pub use self::{api_tokens::SqlApiTokenStore, users::SqlUserStore, user_todo::SqlTodoStore};
+use super::{DbError, DbResult};
+
+/// A value bound to a `?` placeholder. Nulls are typed, Postgres checks them against the column.
+#[derive(Clone, Debug)]
+pub enum SqlParam {
+    Int(Option<i64>),
+    Text(Option<String>),
+    Bool(bool),
+}
+
+impl From<i64> for SqlParam {
+    fn from(value: i64) -> Self {
+        SqlParam::Int(Some(value))
+    }
+}
+
+impl From<Option<i64>> for SqlParam {
+    fn from(value: Option<i64>) -> Self {
+        SqlParam::Int(value)
+    }
+}
+
+impl From<bool> for SqlParam {
+    fn from(value: bool) -> Self {
+        SqlParam::Bool(value)
+    }
+}
+
+impl From<String> for SqlParam {
+    fn from(value: String) -> Self {
+        SqlParam::Text(Some(value))
+    }
+}
+
+impl From<&str> for SqlParam {
+    fn from(value: &str) -> Self {
+        SqlParam::Text(Some(value.to_string()))
+    }
+}
+
+impl From<Option<String>> for SqlParam {
+    fn from(value: Option<String>) -> Self {
+        SqlParam::Text(value)
+    }
+}
+
+/// Collects the parameters of a statement, in placeholder order.
+macro_rules! params {
+    ($($param:expr),* $(,)?) => {
+        &[$($crate::database::sql::SqlParam::from($param)),*]
+    };
+}
+pub(crate) use params;
+
+/// A value read from a result row.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Int(i64),
+    Text(String),
+}
+
+pub struct SqlRow(Vec<SqlValue>);
+
+impl SqlRow {
+    fn get(&self, column: usize) -> DbResult<&SqlValue> {
+        self.0
+            .get(column)
+            .ok_or_else(|| DbError::Sql(format!("no column {} in the result", column)))
+    }
+
+    fn unexpected(&self, column: usize) -> DbError {
+        DbError::Sql(format!("unexpected value {:?} in column {}", self.0[column], column))
+    }
+
+    pub fn opt_text(&self, column: usize) -> DbResult<Option<String>> {
+        match self.get(column)? {
+            SqlValue::Null => Ok(None),
+            SqlValue::Text(text) => Ok(Some(text.clone())),
+            _ => Err(self.unexpected(column)),
+        }
+    }
+
+    pub fn text(&self, column: usize) -> DbResult<String> {
+        self.opt_text(column)?.ok_or_else(|| self.unexpected(column))
+    }
+
+    pub fn opt_int(&self, column: usize) -> DbResult<Option<i64>> {
+        match self.get(column)? {
+            SqlValue::Null => Ok(None),
+            SqlValue::Int(int) => Ok(Some(*int)),
+            _ => Err(self.unexpected(column)),
+        }
+    }
+
+    pub fn int(&self, column: usize) -> DbResult<i64> {
+        self.opt_int(column)?.ok_or_else(|| self.unexpected(column))
+    }
+
+    /// Booleans are read as 0 or 1, SQLite has no other kind.
+    pub fn bool(&self, column: usize) -> DbResult<bool> {
+        Ok(self.int(column)? != 0)
+    }
+
+    /// A text column holding a value that serializes to a string, like `UserId`.
+    pub fn parse<T: DeserializeOwned>(&self, column: usize) -> DbResult<T> {
+        Ok(serde_json::from_value(serde_json::Value::String(self.text(column)?))?)
+    }
+
+    /// A text column holding JSON.
+    pub fn json<T: DeserializeOwned>(&self, column: usize) -> DbResult<T> {
+        Ok(serde_json::from_str(&self.text(column)?)?)
+    }
+}
+
+/// A connection to the database, the only part that differs between backends.
+///
+/// Writes rejected by a unique constraint fail with `DbError::DuplicateKey` naming the
+/// constraint like the MongoDB index.
+pub trait SqlConnection: Send {
+    /// Runs one statement, returning the number of changed rows.
+    fn execute(&mut self, sql: &str, params: &[SqlParam]) -> DbResult<u64>;
+
+    fn query(&mut self, sql: &str, params: &[SqlParam]) -> DbResult<Vec<SqlRow>>;
+
+    /// Runs statements separated by semicolons, without parameters.
+    fn execute_batch(&mut self, sql: &str) -> DbResult<()>;
+
+    fn query_one(&mut self, sql: &str, params: &[SqlParam]) -> DbResult<Option<SqlRow>> {
+        Ok(self.query(sql, params)?.into_iter().next())
+    }
+}
+
+/// The next value of a `seq` column, which orders rows created in the same second.
+fn next_seq(conn: &mut dyn SqlConnection, table: &str) -> DbResult<i64> {
+    let sql = format!("SELECT MAX(seq) FROM {}", table);
+    match conn.query_one(&sql, &[])? {
+        Some(row) => Ok(row.opt_int(0)?.unwrap_or(0) + 1),
+        None => Ok(1),
+    }
+}
+
+type Connect = Box<dyn Fn() -> DbResult<Box<dyn SqlConnection>> + Send + Sync>;
+
+/// Connections opened on demand, up to `max_size`. Taking one blocks while all are in use.
+struct Pool {
+    connect: Connect,
+    max_size: usize,
+    state: Mutex<PoolState>,
+    released: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Box<dyn SqlConnection>>,
+    /// Idle ones and those in use.
+    open: usize,
+}
+
+impl Pool {
+    fn new(connect: Connect, max_size: usize) -> Pool {
+        Pool {
+            connect,
+            max_size: max_size.max(1),
+            state: Mutex::new(PoolState { idle: vec![], open: 0 }),
+            released: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn take(&self) -> DbResult<Box<dyn SqlConnection>> {
+        let mut state = self.state();
+        loop {
+            if let Some(conn) = state.idle.pop() {
+                return Ok(conn);
+            }
+            if state.open < self.max_size {
+                state.open += 1;
+                drop(state);
+                return (self.connect)().inspect_err(|_| self.discard());
+            }
+            state = self
+                .released
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn put_back(&self, conn: Box<dyn SqlConnection>) {
+        self.state().idle.push(conn);
+        self.released.notify_one();
+    }
+
+    /// Forgets a connection that broke, making room for a new one.
+    fn discard(&self) {
+        self.state().open -= 1;
+        self.released.notify_one();
+    }
+}
+
+/// The connections shared by the stores. Statements block, so they run on the thread pool.
+#[derive(Clone)]
+pub struct SqlDatabase {
+    pool: Arc<Pool>,
+}
+
+impl SqlDatabase {
+    /// Opens the file at `path`, creating it if needed.
+    ///
+    /// With a single connection: SQLite writes one transaction at a time anyway, and every
+    /// connection to `:memory:` would open a database of its own.
+    #[cfg(feature = "sqlite")]
+    pub async fn open_sqlite(path: &str) -> DbResult<SqlDatabase> {
+        let path = path.to_string();
+        let connect: Connect = Box::new(move || {
+            Ok(Box::new(sqlite::SqliteConnection::open(&path)?) as Box<dyn SqlConnection>)
+        });
+        SqlDatabase::migrated(Pool::new(connect, 1)).await
+    }
+
+    /// Connects to the database at `url`, with up to `max_connections` at once.
+    #[cfg(feature = "postgres")]
+    pub async fn connect_postgres(url: &str, max_connections: usize) -> DbResult<SqlDatabase> {
+        let url = url.to_string();
+        let connect: Connect = Box::new(move || {
+            Ok(Box::new(postgres::PostgresConnection::connect(&url)?) as Box<dyn SqlConnection>)
+        });
+        SqlDatabase::migrated(Pool::new(connect, max_connections)).await
+    }
+
+    /// Running the migrations opens the first connection, so a database that can't be
+    /// reached fails at startup.
+    async fn migrated(pool: Pool) -> DbResult<SqlDatabase> {
+        let db = SqlDatabase {
+            pool: Arc::new(pool),
+        };
+        db.transaction(migrations::run).await?;
+        Ok(db)
+    }
+
+    /// Runs `f` in a transaction, which is rolled back if it fails.
+    pub async fn transaction<T, F>(&self, f: F) -> DbResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn SqlConnection) -> DbResult<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        web::block(move || {
+            let mut conn = pool.take()?;
+            // A connection that can't begin or end a transaction is broken, it's dropped.
+            if let Err(err) = conn.execute_batch("BEGIN") {
+                pool.discard();
+                return Err(err);
+            }
+            let result = f(conn.as_mut());
+            let end = match &result {
+                Ok(_) => conn.execute_batch("COMMIT"),
+                Err(_) => conn.execute_batch("ROLLBACK").inspect_err(|rollback_err| {
+                    println!("Failed to roll back a transaction: {}", rollback_err);
+                }),
+            };
+            match end {
+                Ok(()) => {
+                    pool.put_back(conn);
+                    result
+                }
+                Err(end_err) => {
+                    pool.discard();
+                    result.and(Err(end_err))
+                }
+            }
+        })
+        .await
+        .map_err(from_blocking)
+    }
+}
+
+fn from_blocking(err: BlockingError<DbError>) -> DbError {
+    match err {
+        BlockingError::Error(err) => err,
+        BlockingError::Canceled => DbError::Sql(String::from("the database thread pool is gone")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use futures::future::join_all;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counters {
+        opened: AtomicUsize,
+        busy: AtomicUsize,
+        most_busy: AtomicUsize,
+    }
+
+    /// Accepts every statement, until told to `break`.
+    struct FakeConnection {
+        broken: bool,
+    }
+
+    impl SqlConnection for FakeConnection {
+        fn execute(&mut self, sql: &str, _params: &[SqlParam]) -> DbResult<u64> {
+            self.broken |= sql == "break";
+            Ok(0)
+        }
+
+        fn query(&mut self, _sql: &str, _params: &[SqlParam]) -> DbResult<Vec<SqlRow>> {
+            Ok(vec![])
+        }
+
+        fn execute_batch(&mut self, _sql: &str) -> DbResult<()> {
+            if self.broken {
+                Err(DbError::Sql(String::from("connection closed")))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn database(max_size: usize, counters: &Arc<Counters>) -> SqlDatabase {
+        let counters = counters.clone();
+        let connect: Connect = Box::new(move || {
+            counters.opened.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(FakeConnection { broken: false }) as Box<dyn SqlConnection>)
+        });
+        SqlDatabase {
+            pool: Arc::new(Pool::new(connect, max_size)),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn pool_shares_at_most_max_size_connections() {
+        let counters = Arc::new(Counters::default());
+        let db = database(2, &counters);
+        let transactions = (0..8).map(|_| {
+            let counters = counters.clone();
+            db.transaction(move |_| {
+                let busy = counters.busy.fetch_add(1, Ordering::SeqCst) + 1;
+                counters.most_busy.fetch_max(busy, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                counters.busy.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        });
+        for result in join_all(transactions).await {
+            result.unwrap();
+        }
+        assert_eq!(counters.most_busy.load(Ordering::SeqCst), 2);
+        assert_eq!(counters.opened.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn pool_replaces_broken_connections() {
+        let counters = Arc::new(Counters::default());
+        let db = database(1, &counters);
+        let broken = db.transaction(|conn| conn.execute("break", &[]).map(drop)).await;
+        assert!(matches!(broken, Err(DbError::Sql(_))));
+        db.transaction(|_| Ok(())).await.unwrap();
+        db.transaction(|_| Ok(())).await.unwrap();
+        assert_eq!(counters.opened.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,104 @@
+use postgres::{
+    error::SqlState,
+    types::{ToSql, Type},
+    Client, NoTls, Row,
+};
+
+use super::{SqlConnection, SqlParam, SqlRow, SqlValue};
+use crate::database::{DbError, DbResult};
+
+pub struct PostgresConnection(Client);
+
+impl PostgresConnection {
+    pub fn connect(url: &str) -> DbResult<PostgresConnection> {
+        Ok(PostgresConnection(
+            Client::connect(url, NoTls).map_err(from_postgres)?,
+        ))
+    }
+}
+
+impl SqlConnection for PostgresConnection {
+    fn execute(&mut self, sql: &str, params: &[SqlParam]) -> DbResult<u64> {
+        let params = to_sql(params);
+        self.0
+            .execute(numbered_placeholders(sql).as_str(), &as_refs(&params))
+            .map_err(from_postgres)
+    }
+
+    fn query(&mut self, sql: &str, params: &[SqlParam]) -> DbResult<Vec<SqlRow>> {
+        let params = to_sql(params);
+        self.0
+            .query(numbered_placeholders(sql).as_str(), &as_refs(&params))
+            .map_err(from_postgres)?
+            .iter()
+            .map(from_row)
+            .collect()
+    }
+
+    fn execute_batch(&mut self, sql: &str) -> DbResult<()> {
+        self.0.batch_execute(sql).map_err(from_postgres)
+    }
+}
+
+/// Postgres numbers its placeholders: `?` becomes `$1`, `$2`, ...
+fn numbered_placeholders(sql: &str) -> String {
+    let mut numbered = String::with_capacity(sql.len());
+    for (n, part) in sql.split('?').enumerate() {
+        if n > 0 {
+            numbered.push_str(&format!("${}", n));
+        }
+        numbered.push_str(part);
+    }
+    numbered
+}
+
+fn to_sql(params: &[SqlParam]) -> Vec<Box<dyn ToSql + Sync>> {
+    params
+        .iter()
+        .map(|param| -> Box<dyn ToSql + Sync> {
+            match param {
+                SqlParam::Int(int) => Box::new(*int),
+                SqlParam::Text(text) => Box::new(text.clone()),
+                SqlParam::Bool(value) => Box::new(*value),
+            }
+        })
+        .collect()
+}
+
+fn as_refs(params: &[Box<dyn ToSql + Sync>]) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter().map(|param| param.as_ref()).collect()
+}
+
+fn from_row(row: &Row) -> DbResult<SqlRow> {
+    let values = row
+        .columns()
+        .iter()
+        .enumerate()
+        .map(|(column, info)| {
+            let value = match *info.type_() {
+                Type::INT8 => row.try_get::<_, Option<i64>>(column)?.map(SqlValue::Int),
+                Type::INT4 => row
+                    .try_get::<_, Option<i32>>(column)?
+                    .map(|int| SqlValue::Int(int.into())),
+                Type::BOOL => row
+                    .try_get::<_, Option<bool>>(column)?
+                    .map(|value| SqlValue::Int(value.into())),
+                _ => row.try_get::<_, Option<String>>(column)?.map(SqlValue::Text),
+            };
+            Ok(value.unwrap_or(SqlValue::Null))
+        })
+        .collect::<Result<_, postgres::Error>>()
+        .map_err(from_postgres)?;
+    Ok(SqlRow(values))
+}
+
+fn from_postgres(err: postgres::Error) -> DbError {
+    if err.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+        if let Some(constraint) = err.as_db_error().and_then(|err| err.constraint()) {
+            // Primary keys are named `<table>_pkey`, MongoDB calls them `_id_`.
+            let index = if constraint.ends_with("_pkey") { "_id_" } else { constraint };
+            return DbError::DuplicateKey(index.to_string());
+        }
+    }
+    DbError::Sql(err.to_string())
+}
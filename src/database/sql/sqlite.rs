@@ -0,0 +1,93 @@
+use rusqlite::{
+    ffi,
+    params_from_iter,
+    types::{Value, ValueRef},
+    Connection,
+};
+
+use super::{SqlConnection, SqlParam, SqlRow, SqlValue};
+use crate::database::{DbError, DbResult};
+
+/// SQLite names the columns of a violated unique constraint, not the constraint.
+const UNIQUE_CONSTRAINTS: &[(&str, &str)] = &[
+    ("users.id", "_id_"),
+    ("users.username_key", "username_key_unique"),
+    ("users.email_key", "email_key_unique"),
+    ("user_identities.issuer, user_identities.subject", "identity_unique"),
+    ("sessions.token_hash", "session_token_hash_unique"),
+    ("api_tokens.token_hash", "token_hash_unique"),
+];
+
+pub struct SqliteConnection(Connection);
+
+impl SqliteConnection {
+    pub fn open(path: &str) -> DbResult<SqliteConnection> {
+        let conn = Connection::open(path).map_err(from_sqlite)?;
+        // Off by default, and can't be changed inside a transaction.
+        conn.execute_batch("PRAGMA foreign_keys = ON")
+            .map_err(from_sqlite)?;
+        Ok(SqliteConnection(conn))
+    }
+}
+
+impl SqlConnection for SqliteConnection {
+    fn execute(&mut self, sql: &str, params: &[SqlParam]) -> DbResult<u64> {
+        let changed = self
+            .0
+            .execute(sql, params_from_iter(params.iter().map(to_value)))
+            .map_err(from_sqlite)?;
+        Ok(changed as u64)
+    }
+
+    fn query(&mut self, sql: &str, params: &[SqlParam]) -> DbResult<Vec<SqlRow>> {
+        let mut statement = self.0.prepare(sql).map_err(from_sqlite)?;
+        let columns = statement.column_count();
+        let mut rows = statement
+            .query(params_from_iter(params.iter().map(to_value)))
+            .map_err(from_sqlite)?;
+
+        let mut result = vec![];
+        while let Some(row) = rows.next().map_err(from_sqlite)? {
+            let values = (0..columns)
+                .map(|column| match row.get_ref(column).map_err(from_sqlite)? {
+                    ValueRef::Null => Ok(SqlValue::Null),
+                    ValueRef::Integer(int) => Ok(SqlValue::Int(int)),
+                    ValueRef::Text(text) => std::str::from_utf8(text)
+                        .map(|text| SqlValue::Text(text.to_string()))
+                        .map_err(|err| DbError::Sql(err.to_string())),
+                    value => Err(DbError::Sql(format!("unexpected {:?} in column {}", value, column))),
+                })
+                .collect::<DbResult<_>>()?;
+            result.push(SqlRow(values));
+        }
+        Ok(result)
+    }
+
+    fn execute_batch(&mut self, sql: &str) -> DbResult<()> {
+        self.0.execute_batch(sql).map_err(from_sqlite)
+    }
+}
+
+fn to_value(param: &SqlParam) -> Value {
+    match param {
+        SqlParam::Int(int) => int.map_or(Value::Null, Value::Integer),
+        SqlParam::Text(text) => text.clone().map_or(Value::Null, Value::Text),
+        SqlParam::Bool(value) => Value::Integer(*value as i64),
+    }
+}
+
+fn from_sqlite(err: rusqlite::Error) -> DbError {
+    if let rusqlite::Error::SqliteFailure(failure, Some(message)) = &err {
+        let unique = failure.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE
+            || failure.extended_code == ffi::SQLITE_CONSTRAINT_PRIMARYKEY;
+        // "UNIQUE constraint failed: users.email_key"
+        if let (true, Some(columns)) = (unique, message.strip_prefix("UNIQUE constraint failed: ")) {
+            let index = UNIQUE_CONSTRAINTS
+                .iter()
+                .find(|(constrained, _)| *constrained == columns)
+                .map_or(columns, |(_, index)| index);
+            return DbError::DuplicateKey(index.to_string());
+        }
+    }
+    DbError::Sql(err.to_string())
+}
@@ -0,0 +1,181 @@
+use async_trait::async_trait;
+
+use super::{next_seq, params, SqlDatabase, SqlRow};
+use crate::{
+    api::users::user::UserId,
+    database::{
+        store::TodoStore,
+        user_todo::{Todo, TodoItem, TodoItemId, TodoItemPatch},
+        DbResult,
+    },
+    util::unix_timestamp,
+};
+
+const ITEM_COLUMNS: &str = "id, title, description, completed, created_at, updated_at, completed_at";
+
+/// Todo items in the `todo_items` table, in the order they were added.
+pub struct SqlTodoStore {
+    db: SqlDatabase,
+}
+
+impl SqlTodoStore {
+    pub fn new(db: SqlDatabase) -> Self {
+        SqlTodoStore { db }
+    }
+}
+
+fn item_from_row(row: &SqlRow) -> DbResult<TodoItem> {
+    Ok(TodoItem {
+        id: row.parse(0)?,
+        title: row.text(1)?,
+        description: row.opt_text(2)?,
+        completed: row.bool(3)?,
+        created_at: row.int(4)?,
+        updated_at: row.int(5)?,
+        completed_at: row.opt_int(6)?,
+    })
+}
+
+#[async_trait]
+impl TodoStore for SqlTodoStore {
+    async fn get_user_todo(&self, user_id: UserId) -> DbResult<Todo> {
+        self.db
+            .transaction(move |conn| {
+                let sql = format!(
+                    "SELECT {} FROM todo_items WHERE user_id = ? ORDER BY seq",
+                    ITEM_COLUMNS
+                );
+                let list = conn
+                    .query(&sql, params![user_id.to_string()])?
+                    .iter()
+                    .map(item_from_row)
+                    .collect::<DbResult<_>>()?;
+                Ok(Todo { list })
+            })
+            .await
+    }
+
+    /// Lists have no row of their own, a user without items has an empty one.
+    async fn create_user_todo(&self, _user_id: UserId) -> DbResult<()> {
+        Ok(())
+    }
+
+    /// The list of a user is there as long as the user is.
+    async fn add_to_todo(
+        &self,
+        user_id: UserId,
+        title: String,
+        description: Option<String>,
+    ) -> DbResult<Option<TodoItem>> {
+        let item = TodoItem::new(title, description);
+        self.db
+            .transaction(move |conn| {
+                let seq = next_seq(conn, "todo_items")?;
+                let added = conn.execute(
+                    "INSERT INTO todo_items (id, user_id, seq, title, description, completed,
+                        created_at, updated_at, completed_at)
+                    SELECT ?, id, ?, ?, ?, ?, ?, ?, ? FROM users WHERE id = ?",
+                    params![
+                        item.id.to_string(),
+                        seq,
+                        item.title.as_str(),
+                        item.description.clone(),
+                        item.completed,
+                        item.created_at,
+                        item.updated_at,
+                        item.completed_at,
+                        user_id.to_string(),
+                    ],
+                )?;
+                Ok(Some(item).filter(|_| added > 0))
+            })
+            .await
+    }
+
+    async fn get_todo_item(&self, user_id: UserId, item_id: &TodoItemId) -> DbResult<Option<TodoItem>> {
+        let item_id = item_id.to_string();
+        self.db
+            .transaction(move |conn| {
+                let sql = format!(
+                    "SELECT {} FROM todo_items WHERE user_id = ? AND id = ?",
+                    ITEM_COLUMNS
+                );
+                conn.query_one(&sql, params![user_id.to_string(), item_id])?
+                    .map(|row| item_from_row(&row))
+                    .transpose()
+            })
+            .await
+    }
+
+    async fn update_todo_item(
+        &self,
+        user_id: UserId,
+        item_id: &TodoItemId,
+        patch: TodoItemPatch,
+    ) -> DbResult<Option<TodoItem>> {
+        let now = unix_timestamp();
+        let item_id = item_id.to_string();
+        self.db
+            .transaction(move |conn| {
+                let sql = format!(
+                    "SELECT {} FROM todo_items WHERE user_id = ? AND id = ?",
+                    ITEM_COLUMNS
+                );
+                let mut item = match conn.query_one(&sql, params![user_id.to_string(), item_id.as_str()])? {
+                    Some(row) => item_from_row(&row)?,
+                    None => return Ok(None),
+                };
+
+                item.updated_at = now;
+                if let Some(title) = patch.title {
+                    item.title = title;
+                }
+                if let Some(description) = patch.description {
+                    item.description = description;
+                }
+                if let Some(completed) = patch.completed {
+                    item.completed = completed;
+                    item.completed_at = if completed { Some(now) } else { None };
+                }
+                conn.execute(
+                    "UPDATE todo_items
+                    SET title = ?, description = ?, completed = ?, updated_at = ?, completed_at = ?
+                    WHERE id = ?",
+                    params![
+                        item.title.as_str(),
+                        item.description.clone(),
+                        item.completed,
+                        item.updated_at,
+                        item.completed_at,
+                        item_id,
+                    ],
+                )?;
+                Ok(Some(item))
+            })
+            .await
+    }
+
+    async fn remove_todo_item(&self, user_id: UserId, item_id: &TodoItemId) -> DbResult<bool> {
+        let item_id = item_id.to_string();
+        self.db
+            .transaction(move |conn| {
+                Ok(conn.execute(
+                    "DELETE FROM todo_items WHERE user_id = ? AND id = ?",
+                    params![user_id.to_string(), item_id],
+                )? > 0)
+            })
+            .await
+    }
+
+    async fn remove_user_todo(&self, user_id: UserId) -> DbResult<()> {
+        self.db
+            .transaction(move |conn| {
+                conn.execute(
+                    "DELETE FROM todo_items WHERE user_id = ?",
+                    params![user_id.to_string()],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
@@ -0,0 +1,639 @@
+use async_trait::async_trait;
+
+use super::{next_seq, params, SqlConnection, SqlDatabase, SqlParam, SqlRow};
+use crate::{
+    api::users::{
+        email::email_key,
+        oidc::ExternalIdentity,
+        policy::username_key,
+        session_token::{
+            fingerprint, new_session_id, ClientInfo, NewSession, SessionConfig, SessionInfo,
+            SessionToken,
+        },
+        totp::TotpEnrollment,
+        user::{BackendUserMe, HashedPassword, UserId},
+    },
+    database::{
+        store::{Rotation, UserStore},
+        users::MAX_ROTATED_HASHES,
+        DbResult,
+    },
+    util::unix_timestamp,
+};
+
+const USER_COLUMNS: &str =
+    "id, username, password, email, email_verified, totp_secret, totp_confirmed, totp_last_step";
+
+/// Users in the `users` table, their sessions in `sessions`.
+pub struct SqlUserStore {
+    db: SqlDatabase,
+    session_config: SessionConfig,
+    /// Key of the stored session token hashes, see `SessionToken::hash`.
+    session_secret: Vec<u8>,
+}
+
+impl SqlUserStore {
+    pub fn new(db: SqlDatabase, session_config: SessionConfig, session_secret: Vec<u8>) -> Self {
+        SqlUserStore {
+            db,
+            session_config,
+            session_secret,
+        }
+    }
+
+    /// Sessions created before this timestamp have exceeded the absolute timeout.
+    fn session_created_after(&self, now: i64) -> i64 {
+        now - self.session_config.absolute_timeout.as_secs() as i64
+    }
+
+    /// Sessions not seen since this timestamp have exceeded the idle timeout.
+    fn session_seen_after(&self, now: i64) -> i64 {
+        now - self.session_config.idle_timeout.as_secs() as i64
+    }
+
+    /// The user matching `condition`, the `WHERE` clause of a query on `users`.
+    async fn find_user(
+        &self,
+        condition: &'static str,
+        params: Vec<SqlParam>,
+    ) -> DbResult<Option<BackendUserMe>> {
+        self.db
+            .transaction(move |conn| {
+                let sql = format!("SELECT {} FROM users WHERE {}", USER_COLUMNS, condition);
+                match conn.query_one(&sql, &params)? {
+                    Some(row) => Ok(Some(user_from_row(conn, &row)?)),
+                    None => Ok(None),
+                }
+            })
+            .await
+    }
+
+    /// Runs a statement, returning whether it changed anything.
+    async fn execute(&self, sql: &'static str, params: Vec<SqlParam>) -> DbResult<bool> {
+        self.db
+            .transaction(move |conn| Ok(conn.execute(sql, &params)? > 0))
+            .await
+    }
+}
+
+fn user_from_row(conn: &mut dyn SqlConnection, row: &SqlRow) -> DbResult<BackendUserMe> {
+    let id: UserId = row.parse(0)?;
+    let totp = match row.opt_text(5)? {
+        Some(secret) => Some(TotpEnrollment {
+            secret,
+            confirmed: row.bool(6)?,
+            recovery_codes: recovery_codes(conn, &id)?,
+            last_step: row.opt_int(7)?.unwrap_or(0),
+        }),
+        None => None,
+    };
+    Ok(BackendUserMe {
+        id,
+        username: row.text(1)?,
+        password: row.parse(2)?,
+        email: row.opt_text(3)?,
+        email_verified: row.bool(4)?,
+        totp,
+    })
+}
+
+fn recovery_codes(conn: &mut dyn SqlConnection, id: &UserId) -> DbResult<Vec<String>> {
+    conn.query(
+        "SELECT code_hash FROM totp_recovery_codes WHERE user_id = ?",
+        params![id.to_string()],
+    )?
+    .iter()
+    .map(|row| row.text(0))
+    .collect()
+}
+
+fn set_recovery_codes(conn: &mut dyn SqlConnection, id: &UserId, code_hashes: &[String]) -> DbResult<()> {
+    conn.execute(
+        "DELETE FROM totp_recovery_codes WHERE user_id = ?",
+        params![id.to_string()],
+    )?;
+    for code_hash in code_hashes {
+        conn.execute(
+            "INSERT INTO totp_recovery_codes (user_id, code_hash) VALUES (?, ?)",
+            params![id.to_string(), code_hash.as_str()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Stores a new user, `email_nonce` awaiting the verification link of their email.
+fn insert_user(conn: &mut dyn SqlConnection, user: &BackendUserMe, email_nonce: Option<&str>) -> DbResult<()> {
+    let totp = user.totp.as_ref();
+    conn.execute(
+        "INSERT INTO users (id, username, username_key, password, email, email_key,
+            email_verified, email_verification_nonce, totp_secret, totp_confirmed, totp_last_step)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            user.id.to_string(),
+            user.username.as_str(),
+            username_key(&user.username),
+            user.password.to_string(),
+            user.email.clone(),
+            user.email.as_deref().filter(|_| user.email_verified).map(email_key),
+            user.email_verified,
+            email_nonce.map(str::to_string),
+            totp.map(|totp| totp.secret.clone()),
+            totp.is_some_and(|totp| totp.confirmed),
+            totp.map(|totp| totp.last_step),
+        ],
+    )?;
+    if let Some(totp) = totp {
+        set_recovery_codes(conn, &user.id, &totp.recovery_codes)?;
+    }
+    Ok(())
+}
+
+/// Adds a session of the user `id`, evicting the oldest beyond `max_sessions`, and
+/// returns its id.
+fn insert_session(
+    conn: &mut dyn SqlConnection,
+    id: &str,
+    token_hash: String,
+    now: i64,
+    client: ClientInfo,
+    max_sessions: i64,
+) -> DbResult<String> {
+    let seq = next_seq(conn, "sessions")?;
+    let session_id = new_session_id();
+    conn.execute(
+        "INSERT INTO sessions (id, user_id, seq, token_hash, created_at, last_seen, user_agent, ip)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            session_id.as_str(),
+            id,
+            seq,
+            token_hash,
+            now,
+            now,
+            client.user_agent,
+            client.ip,
+        ],
+    )?;
+    conn.execute(
+        "DELETE FROM sessions WHERE user_id = ? AND id NOT IN (
+            SELECT id FROM sessions WHERE user_id = ?
+            ORDER BY created_at DESC, seq DESC LIMIT ?
+        )",
+        params![id, id, max_sessions],
+    )?;
+    Ok(session_id)
+}
+
+/// Ends the session that had `token_hash` before a refresh, a stolen copy is in use.
+fn revoke_reused(conn: &mut dyn SqlConnection, token_hash: &str) -> DbResult<Rotation> {
+    let reused = conn.execute(
+        "DELETE FROM sessions WHERE id IN (
+            SELECT session_id FROM rotated_session_hashes WHERE token_hash = ?
+        )",
+        params![token_hash],
+    )? > 0;
+    Ok(if reused { Rotation::Reused } else { Rotation::Unknown })
+}
+
+#[async_trait]
+impl UserStore for SqlUserStore {
+    async fn get_id(&self, id: &UserId) -> DbResult<Option<BackendUserMe>> {
+        self.find_user("id = ?", params![id.to_string()].to_vec())
+            .await
+    }
+
+    async fn get_verified_email(&self, email: &str) -> DbResult<Option<BackendUserMe>> {
+        self.find_user(
+            "email_key = ? AND email_verified = ?",
+            params![email_key(email), true].to_vec(),
+        )
+        .await
+    }
+
+    async fn get_username(&self, username: &str) -> DbResult<Option<BackendUserMe>> {
+        self.find_user("username_key = ?", params![username_key(username)].to_vec())
+            .await
+    }
+
+    async fn get_identity(&self, identity: &ExternalIdentity) -> DbResult<Option<BackendUserMe>> {
+        self.find_user(
+            "id IN (SELECT user_id FROM user_identities WHERE issuer = ? AND subject = ?)",
+            params![identity.issuer.as_str(), identity.subject.as_str()].to_vec(),
+        )
+        .await
+    }
+
+    async fn link_identity(&self, id: &UserId, identity: &ExternalIdentity) -> DbResult<()> {
+        let params = params![
+            id.to_string(),
+            identity.issuer.as_str(),
+            identity.subject.as_str(),
+        ]
+        .to_vec();
+        self.db
+            .transaction(move |conn| {
+                let linked = conn.query_one(
+                    "SELECT 1 FROM user_identities WHERE user_id = ? AND issuer = ? AND subject = ?",
+                    &params,
+                )?;
+                let user = conn.query_one("SELECT 1 FROM users WHERE id = ?", &params[..1])?;
+                if linked.is_none() && user.is_some() {
+                    conn.execute(
+                        "INSERT INTO user_identities (user_id, issuer, subject) VALUES (?, ?, ?)",
+                        &params,
+                    )?;
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    #[cfg(test)]
+    async fn insert(&self, user: BackendUserMe) -> DbResult<()> {
+        self.db
+            .transaction(move |conn| insert_user(conn, &user, None))
+            .await
+    }
+
+    async fn insert_with_session(
+        &self,
+        user: BackendUserMe,
+        email_nonce: Option<&str>,
+        client: ClientInfo,
+    ) -> DbResult<NewSession> {
+        let now = unix_timestamp();
+        let session_token = SessionToken::new();
+        let token_hash = session_token.hash(&self.session_secret);
+        let max_sessions = self.session_config.max_sessions_per_user as i64;
+        let email_nonce = email_nonce.map(str::to_string);
+        let user_id = user.id;
+        let session_id = self
+            .db
+            .transaction(move |conn| {
+                insert_user(conn, &user, email_nonce.as_deref())?;
+                insert_session(conn, &user.id.to_string(), token_hash, now, client, max_sessions)
+            })
+            .await?;
+        Ok(NewSession {
+            user_id,
+            session_id,
+            token: session_token,
+        })
+    }
+
+    async fn remove(&self, id: &UserId) -> DbResult<()> {
+        self.execute("DELETE FROM users WHERE id = ?", params![id.to_string()].to_vec())
+            .await?;
+        Ok(())
+    }
+
+    async fn set_email(&self, id: &UserId, email: &str, nonce: &str) -> DbResult<()> {
+        self.execute(
+            "UPDATE users
+            SET email = ?, email_key = NULL, email_verified = ?, email_verification_nonce = ?
+            WHERE id = ?",
+            params![email, false, nonce, id.to_string()].to_vec(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn verify_email(&self, id: &UserId, email: &str, nonce: &str) -> DbResult<bool> {
+        self.execute(
+            "UPDATE users SET email_verified = ?, email_key = ?, email_verification_nonce = NULL
+            WHERE id = ? AND email = ? AND email_verification_nonce = ?",
+            params![true, email_key(email), id.to_string(), email, nonce].to_vec(),
+        )
+        .await
+    }
+
+    async fn set_username(&self, id: &UserId, username: &str) -> DbResult<()> {
+        self.execute(
+            "UPDATE users SET username = ?, username_key = ? WHERE id = ?",
+            params![username, username_key(username), id.to_string()].to_vec(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_password_hash(&self, id: &UserId, password: &HashedPassword) -> DbResult<()> {
+        self.execute(
+            "UPDATE users SET password = ? WHERE id = ?",
+            params![password.to_string(), id.to_string()].to_vec(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_password(&self, id: &UserId, password: HashedPassword, keep: &SessionToken) -> DbResult<()> {
+        let id = id.to_string();
+        let keep = keep.hash(&self.session_secret);
+        self.db
+            .transaction(move |conn| {
+                conn.execute(
+                    "UPDATE users SET password = ? WHERE id = ?",
+                    params![password.to_string(), id.as_str()],
+                )?;
+                conn.execute(
+                    "DELETE FROM sessions WHERE user_id = ? AND token_hash <> ?",
+                    params![id, keep],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn set_password_reset(&self, id: &UserId, token_hash: &str, expires_at: i64) -> DbResult<()> {
+        self.execute(
+            "UPDATE users SET password_reset_hash = ?, password_reset_expires_at = ? WHERE id = ?",
+            params![token_hash, expires_at, id.to_string()].to_vec(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_password_reset(&self, token_hash: &str) -> DbResult<Option<BackendUserMe>> {
+        self.find_user(
+            "password_reset_hash = ? AND password_reset_expires_at > ?",
+            params![token_hash, unix_timestamp()].to_vec(),
+        )
+        .await
+    }
+
+    async fn reset_password(&self, id: &UserId, token_hash: &str, password: HashedPassword) -> DbResult<bool> {
+        let id = id.to_string();
+        let token_hash = token_hash.to_string();
+        self.db
+            .transaction(move |conn| {
+                let reset = conn.execute(
+                    "UPDATE users
+                    SET password = ?, password_reset_hash = NULL, password_reset_expires_at = NULL
+                    WHERE id = ? AND password_reset_hash = ? AND password_reset_expires_at > ?",
+                    params![password.to_string(), id.as_str(), token_hash, unix_timestamp()],
+                )? > 0;
+                if reset {
+                    conn.execute("DELETE FROM sessions WHERE user_id = ?", params![id])?;
+                }
+                Ok(reset)
+            })
+            .await
+    }
+
+    async fn get_session_token(&self, session_token: SessionToken) -> DbResult<Option<BackendUserMe>> {
+        let now = unix_timestamp();
+        let token_hash = session_token.hash(&self.session_secret);
+        let (created_after, seen_after) = (self.session_created_after(now), self.session_seen_after(now));
+        self.db
+            .transaction(move |conn| {
+                let renewed = conn.execute(
+                    "UPDATE sessions SET last_seen = ?
+                    WHERE token_hash = ? AND created_at > ? AND last_seen > ?",
+                    params![now, token_hash.as_str(), created_after, seen_after],
+                )? > 0;
+                if !renewed {
+                    return Ok(None);
+                }
+                let sql = format!(
+                    "SELECT {} FROM users
+                    WHERE id = (SELECT user_id FROM sessions WHERE token_hash = ?)",
+                    USER_COLUMNS
+                );
+                match conn.query_one(&sql, params![token_hash])? {
+                    Some(row) => Ok(Some(user_from_row(conn, &row)?)),
+                    None => Ok(None),
+                }
+            })
+            .await
+    }
+
+    async fn create_session(&self, id: &UserId, client: ClientInfo) -> DbResult<Option<NewSession>> {
+        let now = unix_timestamp();
+        let user_id = *id;
+        let id = id.to_string();
+        let session_token = SessionToken::new();
+        let token_hash = session_token.hash(&self.session_secret);
+        let max_sessions = self.session_config.max_sessions_per_user as i64;
+        let session_id = self
+            .db
+            .transaction(move |conn| {
+                if conn.query_one("SELECT 1 FROM users WHERE id = ?", params![id.as_str()])?.is_none() {
+                    return Ok(None);
+                }
+                insert_session(conn, &id, token_hash, now, client, max_sessions).map(Some)
+            })
+            .await?;
+        Ok(session_id.map(|session_id| NewSession {
+            user_id,
+            session_id,
+            token: session_token,
+        }))
+    }
+
+    async fn rotate_session_token(&self, session_token: &SessionToken) -> DbResult<Rotation> {
+        let now = unix_timestamp();
+        let old_hash = session_token.hash(&self.session_secret);
+        let new_token = SessionToken::new();
+        let new_hash = new_token.hash(&self.session_secret);
+        let (created_after, seen_after) = (self.session_created_after(now), self.session_seen_after(now));
+        self.db
+            .transaction(move |conn| {
+                let session = conn.query_one(
+                    "SELECT id, user_id FROM sessions
+                    WHERE token_hash = ? AND created_at > ? AND last_seen > ?",
+                    params![old_hash.as_str(), created_after, seen_after],
+                )?;
+                if let Some(session) = session {
+                    let session_id = session.text(0)?;
+                    // Matching the old hash again: a concurrent refresh with the same token
+                    // may have rotated it since the select, then this one is a reuse.
+                    let rotated = conn.execute(
+                        "UPDATE sessions SET token_hash = ?, last_seen = ? WHERE id = ? AND token_hash = ?",
+                        params![new_hash.as_str(), now, session_id.as_str(), old_hash.as_str()],
+                    )? > 0;
+                    if !rotated {
+                        return revoke_reused(conn, &old_hash);
+                    }
+                    let seq = next_seq(conn, "rotated_session_hashes")?;
+                    conn.execute(
+                        "INSERT INTO rotated_session_hashes (session_id, seq, token_hash) VALUES (?, ?, ?)",
+                        params![session_id.as_str(), seq, old_hash],
+                    )?;
+                    conn.execute(
+                        "DELETE FROM rotated_session_hashes WHERE session_id = ? AND seq NOT IN (
+                            SELECT seq FROM rotated_session_hashes WHERE session_id = ?
+                            ORDER BY seq DESC LIMIT ?
+                        )",
+                        params![session_id.as_str(), session_id.as_str(), MAX_ROTATED_HASHES],
+                    )?;
+                    return Ok(Rotation::Rotated(NewSession {
+                        user_id: session.parse(1)?,
+                        session_id,
+                        token: new_token,
+                    }));
+                }
+
+                revoke_reused(conn, &old_hash)
+            })
+            .await
+    }
+
+    async fn remove_session_token(&self, session_token: SessionToken) -> DbResult<()> {
+        self.execute(
+            "DELETE FROM sessions WHERE token_hash = ?",
+            params![session_token.hash(&self.session_secret)].to_vec(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self, id: &UserId, current: &SessionToken) -> DbResult<Vec<SessionInfo>> {
+        let now = unix_timestamp();
+        let current_hash = current.hash(&self.session_secret);
+        let params = params![
+            id.to_string(),
+            self.session_created_after(now),
+            self.session_seen_after(now),
+        ]
+        .to_vec();
+        self.db
+            .transaction(move |conn| {
+                conn.query(
+                    "SELECT id, token_hash, created_at, last_seen, user_agent, ip FROM sessions
+                    WHERE user_id = ? AND created_at > ? AND last_seen > ?
+                    ORDER BY created_at, seq",
+                    &params,
+                )?
+                .iter()
+                .map(|row| {
+                    let token_hash = row.text(1)?;
+                    Ok(SessionInfo {
+                        id: row.text(0)?,
+                        fingerprint: fingerprint(&token_hash),
+                        created_at: row.int(2)?,
+                        last_seen: row.int(3)?,
+                        user_agent: row.opt_text(4)?,
+                        ip: row.opt_text(5)?,
+                        current: token_hash == current_hash,
+                    })
+                })
+                .collect()
+            })
+            .await
+    }
+
+    async fn has_session(&self, id: &UserId, session_id: &str) -> DbResult<bool> {
+        let now = unix_timestamp();
+        let params = params![
+            id.to_string(),
+            session_id,
+            self.session_created_after(now),
+            self.session_seen_after(now),
+        ]
+        .to_vec();
+        self.db
+            .transaction(move |conn| {
+                let session = conn.query_one(
+                    "SELECT 1 FROM sessions
+                    WHERE user_id = ? AND id = ? AND created_at > ? AND last_seen > ?",
+                    &params,
+                )?;
+                Ok(session.is_some())
+            })
+            .await
+    }
+
+    async fn remove_session(&self, id: &UserId, session_id: &str) -> DbResult<bool> {
+        self.execute(
+            "DELETE FROM sessions WHERE user_id = ? AND id = ?",
+            params![id.to_string(), session_id].to_vec(),
+        )
+        .await
+    }
+
+    async fn remove_all_sessions(&self, id: &UserId) -> DbResult<()> {
+        self.execute("DELETE FROM sessions WHERE user_id = ?", params![id.to_string()].to_vec())
+            .await?;
+        Ok(())
+    }
+
+    async fn prune_expired_sessions(&self) -> DbResult<()> {
+        let now = unix_timestamp();
+        self.execute(
+            "DELETE FROM sessions WHERE created_at <= ? OR last_seen <= ?",
+            params![self.session_created_after(now), self.session_seen_after(now)].to_vec(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_pending_totp(&self, id: &UserId, totp: &TotpEnrollment) -> DbResult<bool> {
+        let user_id = *id;
+        let totp = totp.clone();
+        self.db
+            .transaction(move |conn| {
+                let set = conn.execute(
+                    "UPDATE users SET totp_secret = ?, totp_confirmed = ?, totp_last_step = ?
+                    WHERE id = ? AND (totp_confirmed IS NULL OR totp_confirmed = ?)",
+                    params![
+                        totp.secret.as_str(),
+                        totp.confirmed,
+                        totp.last_step,
+                        user_id.to_string(),
+                        false,
+                    ],
+                )? > 0;
+                if set {
+                    set_recovery_codes(conn, &user_id, &totp.recovery_codes)?;
+                }
+                Ok(set)
+            })
+            .await
+    }
+
+    async fn confirm_totp(&self, id: &UserId, step: i64, recovery_code_hashes: Vec<String>) -> DbResult<bool> {
+        let user_id = *id;
+        self.db
+            .transaction(move |conn| {
+                let confirmed = conn.execute(
+                    "UPDATE users SET totp_confirmed = ?, totp_last_step = ?
+                    WHERE id = ? AND totp_confirmed = ?",
+                    params![true, step, user_id.to_string(), false],
+                )? > 0;
+                if confirmed {
+                    set_recovery_codes(conn, &user_id, &recovery_code_hashes)?;
+                }
+                Ok(confirmed)
+            })
+            .await
+    }
+
+    async fn use_totp_step(&self, id: &UserId, step: i64) -> DbResult<bool> {
+        self.execute(
+            "UPDATE users SET totp_last_step = ? WHERE id = ? AND totp_last_step < ?",
+            params![step, id.to_string(), step].to_vec(),
+        )
+        .await
+    }
+
+    async fn use_recovery_code(&self, id: &UserId, code_hash: &str) -> DbResult<bool> {
+        self.execute(
+            "DELETE FROM totp_recovery_codes WHERE user_id = ? AND code_hash = ?",
+            params![id.to_string(), code_hash].to_vec(),
+        )
+        .await
+    }
+
+    async fn remove_totp(&self, id: &UserId) -> DbResult<()> {
+        let user_id = *id;
+        self.db
+            .transaction(move |conn| {
+                conn.execute(
+                    "UPDATE users SET totp_secret = NULL, totp_confirmed = NULL, totp_last_step = NULL
+                    WHERE id = ?",
+                    params![user_id.to_string()],
+                )?;
+                set_recovery_codes(conn, &user_id, &[])
+            })
+            .await
+    }
+}
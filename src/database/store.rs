@@ -0,0 +1,183 @@
+use async_trait::async_trait;
+
+use super::{
+    api_tokens::ApiTokenAccess,
+    user_todo::{Todo, TodoItem, TodoItemId, TodoItemPatch},
+    DbResult,
+};
+use crate::api::users::{
+    api_token::ApiTokenInfo,
+    oidc::ExternalIdentity,
+    session_token::{ClientInfo, NewSession, SessionInfo, SessionToken},
+    totp::TotpEnrollment,
+    user::{BackendUserMe, HashedPassword, UserId},
+};
+
+/// What became of a refresh token, see `UserStore::rotate_session_token`.
+#[derive(Debug)]
+pub enum Rotation {
+    /// The session lives on with the new token.
+    Rotated(NewSession),
+    /// The token was replaced before, and its session has been ended.
+    Reused,
+    /// No live session has or had the token.
+    Unknown,
+}
+
+/// Accounts and their sessions.
+///
+/// Usernames, email addresses and external identities are unique, writes that would
+/// break that fail with `DbError::DuplicateKey` naming the index of the MongoDB store.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn get_id(&self, id: &UserId) -> DbResult<Option<BackendUserMe>>;
+
+    async fn get_verified_email(&self, email: &str) -> DbResult<Option<BackendUserMe>>;
+
+    async fn get_username(&self, username: &str) -> DbResult<Option<BackendUserMe>>;
+
+    /// The user an external identity is linked to.
+    async fn get_identity(&self, identity: &ExternalIdentity) -> DbResult<Option<BackendUserMe>>;
+
+    /// Lets an external identity log in as the user.
+    async fn link_identity(&self, id: &UserId, identity: &ExternalIdentity) -> DbResult<()>;
+
+    /// Stores a user without a session, for tests. Registration uses `insert_with_session`.
+    #[cfg(test)]
+    async fn insert(&self, user: BackendUserMe) -> DbResult<()>;
+
+    /// Stores a new user together with their first session in one atomic write, so a
+    /// failed registration leaves nothing behind. `email_nonce` awaits the verification
+    /// link of `user.email`.
+    async fn insert_with_session(
+        &self,
+        user: BackendUserMe,
+        email_nonce: Option<&str>,
+        client: ClientInfo,
+    ) -> DbResult<NewSession>;
+
+    async fn remove(&self, id: &UserId) -> DbResult<()>;
+
+    /// Changes the email address to an unverified one, awaiting the link with `nonce`.
+    ///
+    /// Unverified addresses don't count towards uniqueness, anyone can claim one and the
+    /// first to verify it gets it.
+    async fn set_email(&self, id: &UserId, email: &str, nonce: &str) -> DbResult<()>;
+
+    /// Marks the email as verified if the link with `nonce` is still the current one.
+    /// Fails with `DbError::DuplicateKey` if another user verified it first.
+    async fn verify_email(&self, id: &UserId, email: &str, nonce: &str) -> DbResult<bool>;
+
+    async fn set_username(&self, id: &UserId, username: &str) -> DbResult<()>;
+
+    /// Replaces the password hash and nothing else, for rehashing.
+    async fn set_password_hash(&self, id: &UserId, password: &HashedPassword) -> DbResult<()>;
+
+    /// Sets a new password and ends every session except `keep`.
+    async fn set_password(&self, id: &UserId, password: HashedPassword, keep: &SessionToken) -> DbResult<()>;
+
+    /// Stores the hash of a reset token for the user, replacing earlier ones.
+    async fn set_password_reset(&self, id: &UserId, token_hash: &str, expires_at: i64) -> DbResult<()>;
+
+    /// The user of an unexpired reset token.
+    async fn get_password_reset(&self, token_hash: &str) -> DbResult<Option<BackendUserMe>>;
+
+    /// Sets the new password and ends every session, if the reset token is still unused.
+    async fn reset_password(&self, id: &UserId, token_hash: &str, password: HashedPassword) -> DbResult<bool>;
+
+    /// Looks up the user of a live session and renews its idle timeout.
+    async fn get_session_token(&self, session_token: SessionToken) -> DbResult<Option<BackendUserMe>>;
+
+    /// Starts a new session for an authenticated user, evicting the oldest ones beyond
+    /// the per-user limit. `None` if the user doesn't exist (anymore).
+    async fn create_session(&self, id: &UserId, client: ClientInfo) -> DbResult<Option<NewSession>>;
+
+    /// Replaces the token of a live session with a new one, for refresh tokens.
+    ///
+    /// Replaced tokens are remembered: one coming back means it was copied, so the
+    /// session is ended for both the thief and the user, who has to log in again.
+    async fn rotate_session_token(&self, session_token: &SessionToken) -> DbResult<Rotation>;
+
+    async fn remove_session_token(&self, session_token: SessionToken) -> DbResult<()>;
+
+    /// The live sessions of a user, oldest first.
+    async fn list_sessions(&self, id: &UserId, current: &SessionToken) -> DbResult<Vec<SessionInfo>>;
+
+    /// Whether the user has a live session with the public id `session_id`.
+    async fn has_session(&self, id: &UserId, session_id: &str) -> DbResult<bool>;
+
+    /// Ends one session of a user, returning whether it existed.
+    async fn remove_session(&self, id: &UserId, session_id: &str) -> DbResult<bool>;
+
+    async fn remove_all_sessions(&self, id: &UserId) -> DbResult<()>;
+
+    /// Removes sessions past their absolute or idle timeout.
+    async fn prune_expired_sessions(&self) -> DbResult<()>;
+
+    /// Starts or restarts two-factor enrollment, false if it's already confirmed.
+    async fn set_pending_totp(&self, id: &UserId, totp: &TotpEnrollment) -> DbResult<bool>;
+
+    /// Finishes enrollment with the step of the first accepted code.
+    async fn confirm_totp(&self, id: &UserId, step: i64, recovery_code_hashes: Vec<String>) -> DbResult<bool>;
+
+    /// Marks the codes of `step` and earlier as spent, false if they already were, so
+    /// one code can't log in twice.
+    async fn use_totp_step(&self, id: &UserId, step: i64) -> DbResult<bool>;
+
+    /// Removes a recovery code, false if it didn't exist or was already used.
+    async fn use_recovery_code(&self, id: &UserId, code_hash: &str) -> DbResult<bool>;
+
+    async fn remove_totp(&self, id: &UserId) -> DbResult<()>;
+}
+
+/// The todo list of every user.
+#[async_trait]
+pub trait TodoStore: Send + Sync {
+    /// The list of a user, empty if they never added anything.
+    async fn get_user_todo(&self, user_id: UserId) -> DbResult<Todo>;
+
+    /// Creates the empty list of a new user, an existing one is kept.
+    async fn create_user_todo(&self, user_id: UserId) -> DbResult<()>;
+
+    /// Appends an item to the list made by `create_user_todo`. Returns the new item, or
+    /// `None` without a list, like after the account was removed.
+    async fn add_to_todo(
+        &self,
+        user_id: UserId,
+        title: String,
+        description: Option<String>,
+    ) -> DbResult<Option<TodoItem>>;
+
+    async fn get_todo_item(&self, user_id: UserId, item_id: &TodoItemId) -> DbResult<Option<TodoItem>>;
+
+    async fn update_todo_item(
+        &self,
+        user_id: UserId,
+        item_id: &TodoItemId,
+        patch: TodoItemPatch,
+    ) -> DbResult<Option<TodoItem>>;
+
+    /// Removes the item, returning whether it existed.
+    async fn remove_todo_item(&self, user_id: UserId, item_id: &TodoItemId) -> DbResult<bool>;
+
+    async fn remove_user_todo(&self, user_id: UserId) -> DbResult<()>;
+}
+
+/// Personal API tokens, kept only as hashes.
+#[async_trait]
+pub trait ApiTokenStore: Send + Sync {
+    async fn insert(&self, user_id: &UserId, token_hash: String, info: &ApiTokenInfo) -> DbResult<()>;
+
+    async fn count(&self, user_id: &UserId) -> DbResult<i64>;
+
+    /// The tokens of a user, oldest first.
+    async fn list(&self, user_id: &UserId) -> DbResult<Vec<ApiTokenInfo>>;
+
+    /// Looks up the owner of an unexpired token and records that it was used.
+    async fn get_access(&self, token: &str) -> DbResult<Option<ApiTokenAccess>>;
+
+    /// Revokes one token of a user, returning whether it existed.
+    async fn remove(&self, user_id: &UserId, id: &str) -> DbResult<bool>;
+
+    async fn remove_all(&self, user_id: &UserId) -> DbResult<()>;
+}
@@ -0,0 +1,405 @@
+//! Behaviour every storage backend shares, checked against each of them.
+//!
+//! Names are random, so the checks can run against a database that already has data.
+//!
+//! The MongoDB and PostgreSQL runs are ignored by default, they need a server. Point them
+//! at a scratch database and run them with
+//!
+//! ```text
+//! MONGO_URL=mongodb://localhost:27017 cargo test store_tests::mongodb -- --ignored
+//! POSTGRES_URL=postgresql://localhost/todo_test \
+//!     cargo test --features postgres store_tests::postgres -- --ignored
+//! ```
+
+use std::time::Duration;
+
+use futures::future::join_all;
+use mongodb::bson::doc;
+
+use super::{
+    store::{ApiTokenStore, Rotation, UserStore},
+    user_todo::TodoItemPatch,
+    DatabaseManager, DbError,
+};
+use crate::{
+    api::users::{
+        api_token::{ApiScope, ApiToken, ApiTokenInfo},
+        oidc::ExternalIdentity,
+        session_token::{ClientInfo, SessionConfig},
+        totp::TotpEnrollment,
+        user::{BackendUserMe, HashedPassword, UserId},
+    },
+    config::Config,
+    crypto,
+    util::unix_timestamp,
+};
+
+fn test_config() -> Config {
+    let mut config = Config::from_env();
+    config.session = SessionConfig {
+        absolute_timeout: Duration::from_secs(3600),
+        idle_timeout: Duration::from_secs(600),
+        max_sessions_per_user: 2,
+        prune_interval: Duration::from_secs(60),
+    };
+    config
+}
+
+/// A new user with a random name, stored with `insert`.
+async fn inserted_user(users: &dyn UserStore) -> BackendUserMe {
+    let mut user = BackendUserMe::new(
+        format!("user_{}", crypto::random_token(8)),
+        HashedPassword::new(String::from("password")),
+    );
+    user.email = Some(format!("{}@example.com", user.username));
+    users.insert(user.clone()).await.unwrap();
+    user
+}
+
+async fn check_all(db: &DatabaseManager) {
+    usernames_and_identities_stay_unique(db.users.as_ref()).await;
+    only_verified_emails_are_unique(db.users.as_ref()).await;
+    concurrent_registrations_store_one_user(db.users.as_ref()).await;
+    email_verification_needs_the_current_nonce(db.users.as_ref()).await;
+    password_reset_ends_every_session(db.users.as_ref()).await;
+    oldest_sessions_are_evicted(db.users.as_ref()).await;
+    reused_refresh_token_ends_the_session(db.users.as_ref()).await;
+    totp_codes_and_recovery_codes_are_single_use(db.users.as_ref()).await;
+    todo_items_are_patched_in_place(db).await;
+    api_tokens_are_found_by_their_hash(db.api_tokens.as_ref()).await;
+}
+
+async fn usernames_and_identities_stay_unique(users: &dyn UserStore) {
+    let alice = inserted_user(users).await;
+    let bob = inserted_user(users).await;
+
+    let password = HashedPassword::new(String::from("password"));
+    let copy = BackendUserMe::new(alice.username.to_uppercase(), password);
+    assert!(matches!(
+        users.insert(copy).await,
+        Err(DbError::DuplicateKey(index)) if index == "username_key_unique"
+    ));
+    assert!(matches!(
+        users.set_username(&bob.id, &alice.username).await,
+        Err(DbError::DuplicateKey(index)) if index == "username_key_unique"
+    ));
+    users.set_username(&alice.id, &alice.username.to_uppercase()).await.unwrap();
+
+    let identity = ExternalIdentity {
+        issuer: String::from("https://id.example.com"),
+        subject: crypto::random_token(8),
+    };
+    users.link_identity(&alice.id, &identity).await.unwrap();
+    users.link_identity(&alice.id, &identity).await.unwrap();
+    assert!(matches!(
+        users.link_identity(&bob.id, &identity).await,
+        Err(DbError::DuplicateKey(index)) if index == "identity_unique"
+    ));
+    let linked = users.get_identity(&identity).await.unwrap().unwrap();
+    assert_eq!(linked.id, alice.id);
+
+    users.remove(&alice.id).await.unwrap();
+    assert!(users.get_id(&alice.id).await.unwrap().is_none());
+    assert!(users.get_identity(&identity).await.unwrap().is_none());
+}
+
+async fn concurrent_registrations_store_one_user(users: &dyn UserStore) {
+    let username = format!("user_{}", crypto::random_token(8));
+    let email = format!("{}@example.com", username);
+    let registrations = (0..8).map(|_| {
+        let mut user = BackendUserMe::new(username.clone(), HashedPassword::new(String::from("password")));
+        user.email = Some(email.clone());
+        users.insert_with_session(user, Some("nonce"), ClientInfo::default())
+    });
+    let (registered, failed): (Vec<_>, Vec<_>) = join_all(registrations)
+        .await
+        .into_iter()
+        .partition(Result::is_ok);
+    assert_eq!(registered.len(), 1);
+    assert!(failed.iter().all(|res| matches!(
+        res,
+        Err(DbError::DuplicateKey(index)) if index == "username_key_unique"
+    )));
+
+    let session = registered.into_iter().next().unwrap().unwrap();
+    let user = users.get_session_token(session.token.clone()).await.unwrap().unwrap();
+    assert_eq!(user.id, session.user_id);
+    assert!(users.verify_email(&user.id, &email, "nonce").await.unwrap());
+
+    // A taken id fails as a whole, without a session for the existing user.
+    let password = HashedPassword::new(String::from("password"));
+    let mut copy = BackendUserMe::new(format!("{}_copy", username), password);
+    copy.id = user.id;
+    assert!(matches!(
+        users.insert_with_session(copy, None, ClientInfo::default()).await,
+        Err(DbError::DuplicateKey(index)) if index == "_id_"
+    ));
+    let sessions = users.list_sessions(&user.id, &session.token).await.unwrap();
+    assert_eq!(sessions.len(), 1);
+}
+
+async fn only_verified_emails_are_unique(users: &dyn UserStore) {
+    let alice = inserted_user(users).await;
+    let bob = inserted_user(users).await;
+    let email = alice.email.clone().unwrap();
+
+    // Entering an address reserves nothing, the first to verify it gets it.
+    users.set_email(&bob.id, &email.to_uppercase(), "bob").await.unwrap();
+    assert!(users.verify_email(&bob.id, &email.to_uppercase(), "bob").await.unwrap());
+    users.set_email(&alice.id, &email, "alice").await.unwrap();
+    assert!(matches!(
+        users.verify_email(&alice.id, &email, "alice").await,
+        Err(DbError::DuplicateKey(index)) if index == "email_key_unique"
+    ));
+    assert_eq!(users.get_verified_email(&email).await.unwrap().unwrap().id, bob.id);
+
+    let password = HashedPassword::new(String::from("password"));
+    let mut copy = BackendUserMe::new(format!("{}_copy", alice.username), password);
+    copy.email = Some(email);
+    copy.email_verified = true;
+    assert!(matches!(
+        users.insert(copy.clone()).await,
+        Err(DbError::DuplicateKey(index)) if index == "email_key_unique"
+    ));
+    copy.email_verified = false;
+    users.insert(copy).await.unwrap();
+}
+
+async fn email_verification_needs_the_current_nonce(users: &dyn UserStore) {
+    let user = inserted_user(users).await;
+    let email = format!("new_{}", user.email.as_deref().unwrap());
+    users.set_email(&user.id, &email, "first").await.unwrap();
+    users.set_email(&user.id, &email, "second").await.unwrap();
+    assert!(users.get_verified_email(&email).await.unwrap().is_none());
+
+    assert!(!users.verify_email(&user.id, &email, "first").await.unwrap());
+    assert!(users.verify_email(&user.id, &email, "second").await.unwrap());
+    assert!(!users.verify_email(&user.id, &email, "second").await.unwrap());
+    let verified = users.get_verified_email(&email.to_uppercase()).await.unwrap().unwrap();
+    assert_eq!(verified.id, user.id);
+}
+
+async fn password_reset_ends_every_session(users: &dyn UserStore) {
+    let user = inserted_user(users).await;
+    let session = users.create_session(&user.id, ClientInfo::default()).await.unwrap().unwrap();
+    let token_hash = crypto::random_token(16);
+    users
+        .set_password_reset(&user.id, &token_hash, unix_timestamp() + 60)
+        .await
+        .unwrap();
+    assert_eq!(users.get_password_reset(&token_hash).await.unwrap().map(|user| user.id), Some(user.id));
+
+    let password = HashedPassword::new(String::from("new password"));
+    assert!(users.reset_password(&user.id, &token_hash, password.clone()).await.unwrap());
+    assert!(!users.reset_password(&user.id, &token_hash, password).await.unwrap());
+    assert!(users.get_session_token(session.token).await.unwrap().is_none());
+    let user = users.get_id(&user.id).await.unwrap().unwrap();
+    assert!(user.password.matches("new password"));
+}
+
+async fn oldest_sessions_are_evicted(users: &dyn UserStore) {
+    let user = inserted_user(users).await;
+    let mut tokens = vec![];
+    for _ in 0..3 {
+        let session = users.create_session(&user.id, ClientInfo::default()).await.unwrap();
+        tokens.push(session.unwrap().token);
+    }
+    assert!(users.get_session_token(tokens[0].clone()).await.unwrap().is_none());
+    assert!(users.get_session_token(tokens[1].clone()).await.unwrap().is_some());
+
+    let sessions = users.list_sessions(&user.id, &tokens[2]).await.unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert!(sessions[1].current && !sessions[0].current);
+    assert!(users.has_session(&user.id, &sessions[0].id).await.unwrap());
+    assert!(!users.has_session(&UserId::new(), &sessions[0].id).await.unwrap());
+    assert!(users.remove_session(&user.id, &sessions[0].id).await.unwrap());
+    assert!(!users.remove_session(&user.id, &sessions[0].id).await.unwrap());
+    assert!(!users.has_session(&user.id, &sessions[0].id).await.unwrap());
+    assert!(users.create_session(&UserId::new(), ClientInfo::default()).await.unwrap().is_none());
+}
+
+async fn reused_refresh_token_ends_the_session(users: &dyn UserStore) {
+    let user = inserted_user(users).await;
+    let first = users.create_session(&user.id, ClientInfo::default()).await.unwrap().unwrap();
+
+    let second = match users.rotate_session_token(&first.token).await.unwrap() {
+        Rotation::Rotated(second) => second,
+        rotation => panic!("expected a rotation, got {:?}", rotation),
+    };
+    assert_eq!((second.user_id, &second.session_id), (user.id, &first.session_id));
+    assert!(users.get_session_token(first.token.clone()).await.unwrap().is_none());
+    assert!(matches!(
+        users.rotate_session_token(&first.token).await,
+        Ok(Rotation::Reused)
+    ));
+    assert!(users.get_session_token(second.token.clone()).await.unwrap().is_none());
+    assert!(!users.has_session(&user.id, &second.session_id).await.unwrap());
+    assert!(matches!(
+        users.rotate_session_token(&second.token).await,
+        Ok(Rotation::Unknown)
+    ));
+
+    // Replayed in parallel, one refresh wins and the next is caught as reuse, which ends
+    // the session for the rest.
+    let session = users.create_session(&user.id, ClientInfo::default()).await.unwrap().unwrap();
+    let refreshes = (0..4).map(|_| users.rotate_session_token(&session.token));
+    let rotations: Vec<_> = join_all(refreshes).await.into_iter().map(Result::unwrap).collect();
+    let rotated = rotations.iter().filter(|r| matches!(r, Rotation::Rotated(_))).count();
+    let reused = rotations.iter().filter(|r| matches!(r, Rotation::Reused)).count();
+    assert_eq!((rotated, reused), (1, 1), "{:?}", rotations);
+    assert!(!users.has_session(&user.id, &session.session_id).await.unwrap());
+}
+
+async fn totp_codes_and_recovery_codes_are_single_use(users: &dyn UserStore) {
+    let user = inserted_user(users).await;
+    let enrollment = TotpEnrollment {
+        secret: String::from("encrypted secret"),
+        confirmed: false,
+        recovery_codes: vec![],
+        last_step: 0,
+    };
+    assert!(users.set_pending_totp(&user.id, &enrollment).await.unwrap());
+    assert!(users.confirm_totp(&user.id, 10, vec![String::from("code")]).await.unwrap());
+    assert!(!users.confirm_totp(&user.id, 11, vec![]).await.unwrap());
+    assert!(!users.set_pending_totp(&user.id, &enrollment).await.unwrap());
+
+    assert!(!users.use_totp_step(&user.id, 10).await.unwrap());
+    assert!(users.use_totp_step(&user.id, 11).await.unwrap());
+    assert!(users.use_recovery_code(&user.id, "code").await.unwrap());
+    assert!(!users.use_recovery_code(&user.id, "code").await.unwrap());
+
+    let totp = users.get_id(&user.id).await.unwrap().unwrap().totp.unwrap();
+    assert!(totp.confirmed && totp.recovery_codes.is_empty());
+    assert_eq!(totp.last_step, 11);
+    users.remove_totp(&user.id).await.unwrap();
+    assert!(users.get_id(&user.id).await.unwrap().unwrap().totp.is_none());
+}
+
+async fn todo_items_are_patched_in_place(db: &DatabaseManager) {
+    let todo = db.todo.as_ref();
+    let user_id = inserted_user(db.users.as_ref()).await.id;
+    assert!(todo.get_user_todo(user_id).await.unwrap().list.is_empty());
+    todo.create_user_todo(user_id).await.unwrap();
+    assert!(todo.get_user_todo(user_id).await.unwrap().list.is_empty());
+    let milk = todo
+        .add_to_todo(user_id, String::from("milk"), Some(String::from("2l")))
+        .await
+        .unwrap()
+        .unwrap();
+    let eggs = todo.add_to_todo(user_id, String::from("eggs"), None).await.unwrap().unwrap();
+    // Creating it again keeps the items.
+    todo.create_user_todo(user_id).await.unwrap();
+    assert_eq!(todo.get_user_todo(user_id).await.unwrap().list.len(), 2);
+
+    let patch = TodoItemPatch {
+        completed: Some(true),
+        ..TodoItemPatch::default()
+    };
+    let updated = todo.update_todo_item(user_id, &milk.id, patch).await.unwrap().unwrap();
+    assert!(updated.completed && updated.completed_at.is_some());
+    assert_eq!(updated.description.as_deref(), Some("2l"));
+    let patch = TodoItemPatch {
+        description: Some(None),
+        ..TodoItemPatch::default()
+    };
+    let updated = todo.update_todo_item(user_id, &milk.id, patch).await.unwrap().unwrap();
+    assert!(updated.completed && updated.description.is_none());
+    assert_eq!(todo.get_user_todo(user_id).await.unwrap().list, vec![updated, eggs.clone()]);
+    assert!(todo
+        .update_todo_item(UserId::new(), &eggs.id, TodoItemPatch::default())
+        .await
+        .unwrap()
+        .is_none());
+
+    assert!(todo.remove_todo_item(user_id, &milk.id).await.unwrap());
+    assert!(!todo.remove_todo_item(user_id, &milk.id).await.unwrap());
+    assert_eq!(todo.get_todo_item(user_id, &eggs.id).await.unwrap(), Some(eggs));
+    db.remove_user(user_id).await.unwrap();
+    assert!(todo.get_user_todo(user_id).await.unwrap().list.is_empty());
+    // A token that outlived the account can't bring the list back.
+    assert!(todo.add_to_todo(user_id, String::from("milk"), None).await.unwrap().is_none());
+    assert!(todo.get_user_todo(user_id).await.unwrap().list.is_empty());
+}
+
+async fn api_tokens_are_found_by_their_hash(tokens: &dyn ApiTokenStore) {
+    let user_id = UserId::new();
+    let now = unix_timestamp();
+    let mut stored = vec![];
+    for expires_at in [None, Some(now - 1)] {
+        let token = ApiToken::new();
+        let info = ApiTokenInfo {
+            id: token.id.clone(),
+            name: String::from("script"),
+            scopes: vec![ApiScope::TodoRead],
+            created_at: now,
+            expires_at,
+            last_used: None,
+        };
+        tokens.insert(&user_id, token.token_hash(), &info).await.unwrap();
+        stored.push(token);
+    }
+    assert_eq!(tokens.count(&user_id).await.unwrap(), 2);
+
+    let access = tokens.get_access(&stored[0].token).await.unwrap().unwrap();
+    assert_eq!((access.user_id, access.scopes), (user_id, vec![ApiScope::TodoRead]));
+    assert!(tokens.get_access(&stored[1].token).await.unwrap().is_none());
+    let listed = tokens.list(&user_id).await.unwrap();
+    assert!(listed.iter().any(|info| info.id == stored[0].id && info.last_used.is_some()));
+
+    assert!(!tokens.remove(&UserId::new(), &stored[0].id).await.unwrap());
+    assert!(tokens.remove(&user_id, &stored[0].id).await.unwrap());
+    tokens.remove_all(&user_id).await.unwrap();
+    assert_eq!(tokens.count(&user_id).await.unwrap(), 0);
+}
+
+#[actix_rt::test]
+async fn memory() {
+    check_all(&DatabaseManager::in_memory(&test_config())).await;
+}
+
+#[cfg(feature = "sqlite")]
+#[actix_rt::test]
+async fn sqlite() {
+    let config = test_config();
+    let db = super::sql::SqlDatabase::open_sqlite(":memory:").await.unwrap();
+    check_all(&DatabaseManager::sql(db, &config)).await;
+}
+
+#[cfg(feature = "postgres")]
+#[actix_rt::test]
+#[ignore = "needs PostgreSQL at POSTGRES_URL"]
+async fn postgres() {
+    let config = test_config();
+    let db = super::sql::SqlDatabase::connect_postgres(
+        &config.database.postgres_url,
+        config.database.postgres_max_connections,
+    )
+    .await
+    .unwrap();
+    check_all(&DatabaseManager::sql(db, &config)).await;
+}
+
+#[actix_rt::test]
+#[ignore = "needs MongoDB at MONGO_URL"]
+async fn mongodb() {
+    let config = test_config();
+    let db = super::mongodb_test_database().await;
+    check_all(&DatabaseManager::mongodb(&db, &config).await.unwrap()).await;
+
+    // Logins look their session up by these, without them every one scans all users.
+    let indexes = db
+        .run_command(doc! { "listIndexes": "users" }, None)
+        .await
+        .unwrap();
+    let names: Vec<_> = indexes
+        .get_document("cursor")
+        .and_then(|cursor| cursor.get_array("firstBatch"))
+        .unwrap()
+        .iter()
+        .filter_map(|index| index.as_document()?.get_str("name").ok())
+        .collect();
+    for index in &["session_token_hash", "session_rotated_hashes"] {
+        assert!(names.contains(index), "no index {} in {:?}", index, names);
+    }
+    db.drop(None).await.unwrap();
+}
@@ -1,29 +1,148 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use crate::api::users::user::UserId;
+use crate::database::{store::TodoStore, DbError, DbResult};
+use crate::util::unix_timestamp;
+use async_trait::async_trait;
 use futures::future::OptionFuture;
 use mongodb::{
-    bson::{doc,self},
+    bson::{doc, self, Bson, Document},
+    options::{FindOneAndUpdateOptions, FindOneOptions, ReturnDocument, UpdateOptions},
     Collection, Database,
 };
+use rand::{thread_rng, Rng};
+use std::fmt;
+
+const TODO_ITEM_ID_LEN: usize = 16;
+const VALID_TODO_ITEM_ID_CHARS: &str = "0123456789abcdef";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct TodoItemId(String);
+
+impl TodoItemId {
+    pub fn new() -> TodoItemId {
+        let abc = VALID_TODO_ITEM_ID_CHARS.chars().collect::<Vec<_>>();
+        TodoItemId(
+            (0..TODO_ITEM_ID_LEN)
+                .map(|_| abc[thread_rng().gen_range(0, abc.len())])
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for TodoItemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
 #[derive(Clone, Serialize, Default, PartialEq, Debug, Eq, Deserialize)]
 pub struct Todo {
-    list: Vec<String>,
+    pub list: Vec<TodoItem>,
+}
+
+#[derive(Clone, Serialize, PartialEq, Debug, Eq, Deserialize)]
+pub struct TodoItem {
+    pub id: TodoItemId,
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub completed: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+    #[serde(default)]
+    pub completed_at: Option<i64>,
+}
+
+impl TodoItem {
+    pub fn new(title: String, description: Option<String>) -> TodoItem {
+        let now = unix_timestamp();
+        TodoItem {
+            id: TodoItemId::new(),
+            title,
+            description,
+            completed: false,
+            created_at: now,
+            updated_at: now,
+            completed_at: None,
+        }
+    }
+}
+
+/// Partial update of a todo item, fields left out stay unchanged.
+#[derive(Deserialize, Debug, Default)]
+pub struct TodoItemPatch {
+    pub title: Option<String>,
+    /// `Some(None)` for a description set to `null`, which removes it.
+    #[serde(default, deserialize_with = "present")]
+    pub description: Option<Option<String>>,
+    pub completed: Option<bool>,
+}
+
+/// Deserializes a field that is present, even as `null`, into `Some`. Missing fields
+/// are left to `#[serde(default)]`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Entries in `users_todo` used to be bare strings; both shapes are accepted when loading.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+enum StoredTodoItem {
+    Item(TodoItem),
+    Legacy(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+struct StoredTodo {
+    #[serde(default)]
+    list: Vec<StoredTodoItem>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct TodoStorage {
     user_id: UserId,
-    todo: Todo,
+    #[serde(default)]
+    todo: StoredTodo,
 }
 
 impl TodoStorage {
-    async fn to_todo_list(
+    /// Converts the stored list, upgrading legacy entries. The flag tells whether any were found.
+    async fn into_todo_list(
         self,
-    ) -> Todo {
-        Todo {
-            list: self.todo.list,
-        }
+    ) -> (Todo, bool) {
+        let mut upgraded = false;
+        let list = self
+            .todo
+            .list
+            .into_iter()
+            .map(|item| match item {
+                StoredTodoItem::Item(item) => item,
+                StoredTodoItem::Legacy(title) => {
+                    upgraded = true;
+                    TodoItem::new(title, None)
+                }
+            })
+            .collect();
+        (Todo { list }, upgraded)
+    }
+
+    /// The first item of a document fetched with an `$elemMatch` projection on the list.
+    async fn into_todo_item(self) -> Option<TodoItem> {
+        let (todo, _) = self.into_todo_list().await;
+        todo.list.into_iter().next()
+    }
+}
+
+fn item_projection(item_id: &TodoItemId) -> Document {
+    doc! {
+        "user_id": 1,
+        "todo.list": { "$elemMatch": { "id": item_id.to_string() } },
     }
 }
 
@@ -38,34 +157,298 @@ impl UserTodo {
         }
     }
 
-    pub async fn get_user_todo(&self, user_id: UserId) -> Option<Todo> {
-        let user: OptionFuture<_> = self
+    /// One list per user, so concurrent first adds can't each create one.
+    pub async fn create_indexes(db: &Database) -> mongodb::error::Result<Document> {
+        db.run_command(
+            doc! {
+                "createIndexes": "users_todo",
+                "indexes": [
+                    { "key": { "user_id": 1 }, "name": "user_id_unique", "unique": true },
+                ],
+            },
+            None,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl TodoStore for UserTodo {
+    async fn get_user_todo(&self, user_id: UserId) -> DbResult<Todo> {
+        let filter = doc! { "user_id": user_id.to_string() };
+        loop {
+            let stored = match self
+                .todo
+                .clone_with_type::<Document>()
+                .find_one(filter.clone(), None)
+                .await?
+            {
+                Some(stored) => stored,
+                None => return Ok(Todo::default()),
+            };
+            let read_list = stored
+                .get_document("todo")
+                .ok()
+                .and_then(|todo| todo.get("list"))
+                .cloned();
+            let storage: TodoStorage =
+                bson::from_document(stored).map_err(mongodb::error::Error::from)?;
+            let (todo, upgraded) = storage.into_todo_list().await;
+            if !upgraded {
+                return Ok(todo);
+            }
+
+            // Persist the generated ids so the upgraded items stay addressable. Only the
+            // list that was read is replaced, a concurrent change means reading it again.
+            let mut unchanged = filter.clone();
+            unchanged.insert("todo.list", read_list.unwrap_or(Bson::Null));
+            let res = self
+                .todo
+                .update_one(
+                    unchanged,
+                    doc! { "$set": { "todo.list": bson::to_bson(&todo.list)? } },
+                    None,
+                )
+                .await?;
+            if res.matched_count > 0 {
+                return Ok(todo);
+            }
+        }
+    }
+
+    async fn create_user_todo(&self, user_id: UserId) -> DbResult<()> {
+        let res = self
             .todo
-            .find_one(doc! {"user_id": user_id.to_string()}, None)
+            .update_one(
+                doc! { "user_id": user_id.to_string() },
+                doc! { "$setOnInsert": { "todo": { "list": [] } } },
+                UpdateOptions::builder().upsert(Some(true)).build(),
+            )
             .await
-            .ok()
-            .flatten()
-            .map(|user| user.to_todo_list())
-            .into();
-        user.await
+            .map_err(DbError::from);
+        match res {
+            // A concurrent upsert created it.
+            Ok(_) | Err(DbError::DuplicateKey(_)) => Ok(()),
+            Err(err) => Err(err),
+        }
     }
 
-    pub async fn add_to_todo(&self, user_id: UserId, task: String) -> bool {
-        let mut todo = self.get_user_todo(user_id).await;
+    async fn add_to_todo(
+        &self,
+        user_id: UserId,
+        title: String,
+        description: Option<String>,
+    ) -> DbResult<Option<TodoItem>> {
+        let item = TodoItem::new(title, description);
+        let res = self
+            .todo
+            .update_one(
+                doc! { "user_id": user_id.to_string() },
+                doc! { "$push": { "todo.list": bson::to_bson(&item)? } },
+                None,
+            )
+            .await?;
+        Ok(Some(item).filter(|_| res.matched_count > 0))
+    }
+
+    async fn get_todo_item(&self, user_id: UserId, item_id: &TodoItemId) -> DbResult<Option<TodoItem>> {
+        let todo = self
+            .todo
+            .find_one(
+                doc! { "user_id": user_id.to_string(), "todo.list.id": item_id.to_string() },
+                FindOneOptions::builder()
+                    .projection(Some(item_projection(item_id)))
+                    .build(),
+            )
+            .await?;
+        let item: OptionFuture<_> = todo.map(|todo| todo.into_todo_item()).into();
+        Ok(item.await.flatten())
+    }
 
-        if let Some(mut value) =  todo {
-            value.list.push(task);
-            todo = Some(value);
+    async fn update_todo_item(
+        &self,
+        user_id: UserId,
+        item_id: &TodoItemId,
+        patch: TodoItemPatch,
+    ) -> DbResult<Option<TodoItem>> {
+        let now = unix_timestamp();
+        let mut set = doc! { "todo.list.$.updated_at": now };
+        if let Some(title) = patch.title {
+            set.insert("todo.list.$.title", title);
+        }
+        if let Some(description) = patch.description {
+            set.insert("todo.list.$.description", description.map_or(Bson::Null, Bson::String));
+        }
+        if let Some(completed) = patch.completed {
+            set.insert("todo.list.$.completed", completed);
+            set.insert(
+                "todo.list.$.completed_at",
+                if completed { Bson::Int64(now) } else { Bson::Null },
+            );
         }
 
-        self.todo
+        let todo = self
+            .todo
+            .find_one_and_update(
+                doc! { "user_id": user_id.to_string(), "todo.list.id": item_id.to_string() },
+                doc! { "$set": set },
+                FindOneAndUpdateOptions::builder()
+                    .projection(Some(item_projection(item_id)))
+                    .return_document(Some(ReturnDocument::After))
+                    .build(),
+            )
+            .await?;
+        let item: OptionFuture<_> = todo.map(|todo| todo.into_todo_item()).into();
+        Ok(item.await.flatten())
+    }
+
+    async fn remove_todo_item(&self, user_id: UserId, item_id: &TodoItemId) -> DbResult<bool> {
+        let res = self
+            .todo
             .update_one(
-                doc! { "user_id": user_id.to_string()},
-                doc! { "$set": bson::to_document(&todo).unwrap() },
+                doc! { "user_id": user_id.to_string() },
+                doc! { "$pull": { "todo.list": { "id": item_id.to_string() } } },
                 None,
             )
-            .await
-            .is_ok()
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    async fn remove_user_todo(&self, user_id: UserId) -> DbResult<()> {
+        self.todo
+            .delete_one(doc! { "user_id": user_id.to_string() }, None)
+            .await?;
+        Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::mongodb_test_database;
+    use futures::future::join_all;
+
+    /// A fresh database with the todo indexes, dropped again by `drop_db`.
+    async fn test_db() -> Database {
+        let db = mongodb_test_database().await;
+        UserTodo::create_indexes(&db).await.unwrap();
+        db
+    }
+
+    async fn drop_db(db: Database) {
+        db.drop(None).await.unwrap();
+    }
+
+    #[test]
+    fn patch_tells_null_from_missing_description() {
+        let patch: TodoItemPatch = serde_json::from_str(r#"{"title": "milk"}"#).unwrap();
+        assert_eq!(patch.description, None);
+        let patch: TodoItemPatch = serde_json::from_str(r#"{"description": null}"#).unwrap();
+        assert_eq!(patch.description, Some(None));
+        let patch: TodoItemPatch = serde_json::from_str(r#"{"description": "2l"}"#).unwrap();
+        assert_eq!(patch.description, Some(Some(String::from("2l"))));
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs MongoDB at MONGO_URL"]
+    async fn items_are_added_to_the_created_list() {
+        let db = test_db().await;
+        let todo = UserTodo::new(&db);
+        let user_id = UserId::new();
+
+        let add = || todo.add_to_todo(user_id, String::from("first"), Some(String::from("details")));
+        assert_eq!(add().await.unwrap(), None);
+        todo.create_user_todo(user_id).await.unwrap();
+        let item = add().await.unwrap().unwrap();
+        assert_eq!(item.title, "first");
+        assert_eq!(todo.get_user_todo(user_id).await.unwrap().list, vec![item.clone()]);
+        assert_eq!(todo.get_todo_item(user_id, &item.id).await.unwrap(), Some(item));
+        drop_db(db).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs MongoDB at MONGO_URL"]
+    async fn concurrent_creates_and_adds_keep_one_list_with_every_item() {
+        let db = test_db().await;
+        let todo = UserTodo::new(&db);
+        let user_id = UserId::new();
+
+        for created in join_all((0..4).map(|_| todo.create_user_todo(user_id))).await {
+            created.unwrap();
+        }
+        let added = join_all((0..10).map(|i| todo.add_to_todo(user_id, i.to_string(), None))).await;
+        let mut added: Vec<_> = added
+            .into_iter()
+            .map(|item| item.unwrap().unwrap().id.to_string())
+            .collect();
+        let mut stored: Vec<_> = todo
+            .get_user_todo(user_id)
+            .await
+            .unwrap()
+            .list
+            .into_iter()
+            .map(|item| item.id.to_string())
+            .collect();
+        added.sort();
+        stored.sort();
+        assert_eq!(added, stored);
+
+        let lists = db
+            .collection("users_todo")
+            .count_documents(doc! { "user_id": user_id.to_string() }, None)
+            .await
+            .unwrap();
+        assert_eq!(lists, 1);
+        drop_db(db).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs MongoDB at MONGO_URL"]
+    async fn upgrading_legacy_items_keeps_concurrent_adds() {
+        let db = test_db().await;
+        let todo = UserTodo::new(&db);
+        let user_id = UserId::new();
+        db.collection("users_todo")
+            .insert_one(
+                doc! { "user_id": user_id.to_string(), "todo": { "list": ["legacy"] } },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let (read, added) = futures::join!(
+            todo.get_user_todo(user_id),
+            todo.add_to_todo(user_id, String::from("new"), None),
+        );
+        assert_eq!(read.unwrap().list[0].title, "legacy");
+        let added = added.unwrap().unwrap();
+        let list = todo.get_user_todo(user_id).await.unwrap().list;
+        let titles: Vec<_> = list.iter().map(|item| item.title.as_str()).collect();
+        assert_eq!(titles, vec!["legacy", "new"]);
+        assert!(list.contains(&added));
+        // The upgrade was stored, reading again gives the same ids.
+        assert_eq!(todo.get_user_todo(user_id).await.unwrap().list, list);
+        drop_db(db).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs MongoDB at MONGO_URL"]
+    async fn users_without_a_list_have_nothing() {
+        let db = test_db().await;
+        let todo = UserTodo::new(&db);
+        let user_id = UserId::new();
+        let item_id = TodoItemId::new();
+
+        assert_eq!(todo.get_user_todo(user_id).await.unwrap(), Todo::default());
+        assert_eq!(todo.get_todo_item(user_id, &item_id).await.unwrap(), None);
+        let patch = TodoItemPatch {
+            completed: Some(true),
+            ..TodoItemPatch::default()
+        };
+        assert_eq!(todo.update_todo_item(user_id, &item_id, patch).await.unwrap(), None);
+        assert!(!todo.remove_todo_item(user_id, &item_id).await.unwrap());
+        assert_eq!(todo.add_to_todo(user_id, String::from("new"), None).await.unwrap(), None);
+        todo.remove_user_todo(user_id).await.unwrap();
+        drop_db(db).await;
+    }
+}
@@ -1,124 +1,737 @@
-use futures::future::OptionFuture;
+use async_trait::async_trait;
+use futures::{future::OptionFuture, StreamExt};
 use mongodb::{
-    bson::doc,
+    bson::{self, doc, Document},
+    options::{
+        FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument, UpdateOptions,
+    },
     Collection, Database,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::
+use crate::{
     api::users::{
-        session_token::SessionToken,
+        email::email_key,
+        policy::username_key,
+        oidc::ExternalIdentity,
+        totp::TotpEnrollment,
+        session_token::{
+            fingerprint, new_session_id, ClientInfo, NewSession, SessionConfig, SessionInfo,
+            SessionToken,
+        },
         user::{BackendUserMe, HashedPassword, UserId},
-        user_mgr::UserAuth,
-    };
+    },
+    database::{
+        store::{Rotation, UserStore},
+        DbError,
+        DbResult,
+    },
+    util::unix_timestamp,
+};
+
+/// Replaced refresh tokens remembered per session to recognize their reuse.
+pub(super) const MAX_ROTATED_HASHES: i64 = 20;
 
 pub struct UserCollection {
     collection: Collection<DbUser>,
+    /// The same collection, reading only the sessions of a user.
+    sessions: Collection<DbUserSessions>,
+    session_config: SessionConfig,
+    /// Key of the stored session token hashes, see `SessionToken::hash`.
+    session_secret: Vec<u8>,
 }
 
 impl UserCollection {
-    pub fn new(db: &Database) -> Self {
+    pub fn new(db: &Database, session_config: SessionConfig, session_secret: Vec<u8>) -> Self {
         UserCollection {
             collection: db.collection_with_type("users"),
+            sessions: db.collection_with_type("users"),
+            session_config,
+            session_secret,
         }
     }
 
-    async fn get_auth(
-        &self,
-        auth: UserAuth,
-    ) -> Option<BackendUserMe> {
-        if let Some(user) = self.get_username(&auth.username).await {
-            if user.password.matches(&auth.password) {
-                return Some(user);
+    fn new_session(&self, client: ClientInfo) -> (SessionToken, DbSession) {
+        let now = unix_timestamp();
+        let session_token = SessionToken::new();
+        let session = DbSession {
+            id: new_session_id(),
+            token_hash: session_token.hash(&self.session_secret),
+            created_at: now,
+            last_seen: now,
+            user_agent: client.user_agent,
+            ip: client.ip,
+            rotated_hashes: vec![],
+        };
+        (session_token, session)
+    }
+
+    pub async fn create_indexes(db: &Database) -> mongodb::error::Result<Document> {
+        db.run_command(
+            doc! {
+                "createIndexes": "users",
+                "indexes": [
+                    {
+                        "key": { "username_key": 1 },
+                        "name": "username_key_unique",
+                        "unique": true,
+                        "partialFilterExpression": { "username_key": { "$exists": true } },
+                    },
+                    {
+                        "key": { "email_key": 1 },
+                        "name": "email_key_unique",
+                        "unique": true,
+                        "partialFilterExpression": { "email_key": { "$exists": true } },
+                    },
+                    {
+                        "key": { "identities.issuer": 1, "identities.subject": 1 },
+                        "name": "identity_unique",
+                        "unique": true,
+                        "partialFilterExpression": { "identities.subject": { "$exists": true } },
+                    },
+                    // Multikey, for looking up the user of a session token. Not unique: users
+                    // without sessions would all collide on the missing value.
+                    { "key": { "session_tokens.token_hash": 1 }, "name": "session_token_hash" },
+                    {
+                        "key": { "session_tokens.rotated_hashes": 1 },
+                        "name": "session_rotated_hashes",
+                    },
+                ],
+            },
+            None,
+        )
+        .await
+    }
+
+    /// Sets `username_key` on users stored before it existed. Users whose key collides
+    /// with another account are left as they are and reported.
+    pub async fn backfill_username_keys(&self) -> DbResult<()> {
+        let mut cursor = self
+            .collection
+            .find(doc! { "username_key": { "$exists": false } }, None)
+            .await?;
+        while let Some(user) = cursor.next().await {
+            let user = user?;
+            let res = self
+                .collection
+                .update_one(
+                    doc! { "_id": user.id.to_string() },
+                    doc! { "$set": { "username_key": username_key(&user.username) } },
+                    None,
+                )
+                .await
+                .map_err(DbError::from);
+            match res {
+                Ok(_) => {}
+                Err(DbError::DuplicateKey(_)) => println!(
+                    "Could not set username_key of user {} ('{}'), it collides with another account",
+                    user.id, user.username
+                ),
+                Err(err) => return Err(err),
             }
         }
-        None
+        Ok(())
     }
 
-    pub async fn get_id(
-        &self,
-        id: &UserId,
-    ) -> Option<BackendUserMe> {
+    /// Unsets `email_key` of unverified addresses, which used to reserve them for whoever
+    /// entered them first.
+    pub async fn release_unverified_emails(&self) -> DbResult<()> {
+        self.collection
+            .update_many(
+                doc! { "email_verified": { "$ne": true }, "email_key": { "$exists": true } },
+                doc! { "$unset": { "email_key": "" } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Replaces the session tokens stored in plain before they were hashed, so the
+    /// sessions stay valid. Plain string tokens are dropped, they would be pruned anyway.
+    ///
+    /// Each session is replaced where it is, servers still running the previous version
+    /// may add sessions in the meantime.
+    pub async fn hash_plain_session_tokens(&self) -> DbResult<()> {
+        let mut cursor = self
+            .sessions
+            .find(
+                doc! { "$or": [
+                    { "session_tokens.token": { "$exists": true } },
+                    { "session_tokens": { "$type": "string" } },
+                ] },
+                FindOptions::builder()
+                    .projection(Some(doc! { "session_tokens": 1 }))
+                    .build(),
+            )
+            .await?;
+        while let Some(user) = cursor.next().await {
+            let user = user?;
+            let id = user.id.to_string();
+            for session in user.session_tokens {
+                let session = match session {
+                    StoredSession::Plain(session) => session,
+                    StoredSession::Hashed(_) | StoredSession::Legacy(_) => continue,
+                };
+                let token = bson::to_bson(&session.token)?;
+                let hashed = bson::to_bson(&session.into_hashed(&self.session_secret))?;
+                self.collection
+                    .update_one(
+                        doc! { "_id": &id },
+                        doc! { "$set": { "session_tokens.$[plain]": hashed } },
+                        UpdateOptions::builder()
+                            .array_filters(Some(vec![doc! { "plain.token": token }]))
+                            .build(),
+                    )
+                    .await?;
+            }
+            self.collection
+                .update_one(
+                    doc! { "_id": &id },
+                    doc! { "$pull": { "session_tokens": { "$type": "string" } } },
+                    None,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sessions created before this timestamp have exceeded the absolute timeout.
+    fn session_created_after(&self, now: i64) -> i64 {
+        now - self.session_config.absolute_timeout.as_secs() as i64
+    }
+
+    /// Sessions not seen since this timestamp have exceeded the idle timeout.
+    fn session_seen_after(&self, now: i64) -> i64 {
+        now - self.session_config.idle_timeout.as_secs() as i64
+    }
+
+    async fn find_user(&self, filter: Document) -> DbResult<Option<BackendUserMe>> {
         let user: OptionFuture<_> = self
             .collection
-            .find_one(doc! {"_id": id.to_string()}, None)
-            .await
-            .ok()
-            .flatten()
-            .map(|user| user.to_backend_user())
+            .find_one(filter, None)
+            .await?
+            .map(|user| user.into_backend_user())
             .into();
-        user.await
+        Ok(user.await)
     }
+}
 
+#[async_trait]
+impl UserStore for UserCollection {
+    async fn set_password_hash(&self, id: &UserId, password: &HashedPassword) -> DbResult<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! { "$set": { "password": password.to_string() } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
 
-    pub async fn get_username(
+    async fn get_id(
         &self,
-        username: &str,
-    ) -> Option<BackendUserMe> {
-        let user: OptionFuture<_> = self
-            .collection
-            .find_one(doc! { "username": username }, None)
+        id: &UserId,
+    ) -> DbResult<Option<BackendUserMe>> {
+        self.find_user(doc! {"_id": id.to_string()}).await
+    }
+
+    async fn get_verified_email(
+        &self,
+        email: &str,
+    ) -> DbResult<Option<BackendUserMe>> {
+        self.find_user(doc! { "email_key": email_key(email), "email_verified": true })
             .await
-            .ok()
-            .flatten()
-            .map(|user| user.to_backend_user())
-            .into();
-        user.await
     }
 
-    pub async fn get_session_token(
+    async fn get_identity(&self, identity: &ExternalIdentity) -> DbResult<Option<BackendUserMe>> {
+        self.find_user(doc! { "identities": { "$elemMatch": {
+            "issuer": &identity.issuer,
+            "subject": &identity.subject,
+        } } })
+        .await
+    }
+
+    async fn link_identity(&self, id: &UserId, identity: &ExternalIdentity) -> DbResult<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! { "$addToSet": { "identities": bson::to_bson(identity)? } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_email(
+        &self,
+        id: &UserId,
+        email: &str,
+        nonce: &str,
+    ) -> DbResult<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! {
+                    "$set": {
+                        "email": email,
+                        "email_verified": false,
+                        "email_verification_nonce": nonce,
+                    },
+                    "$unset": { "email_key": "" },
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn verify_email(&self, id: &UserId, email: &str, nonce: &str) -> DbResult<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! {
+                    "_id": id.to_string(),
+                    "email": email,
+                    "email_verification_nonce": nonce,
+                },
+                doc! {
+                    "$set": { "email_verified": true, "email_key": email_key(email) },
+                    "$unset": { "email_verification_nonce": "" },
+                },
+                None,
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    async fn set_username(&self, id: &UserId, username: &str) -> DbResult<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! { "$set": {
+                    "username": username,
+                    "username_key": username_key(username),
+                } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_password(
+        &self,
+        id: &UserId,
+        password: HashedPassword,
+        keep: &SessionToken,
+    ) -> DbResult<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! {
+                    "$set": { "password": password.to_string() },
+                    "$pull": { "session_tokens": {
+                        "token_hash": { "$ne": keep.hash(&self.session_secret) }
+                    } },
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_password_reset(
+        &self,
+        id: &UserId,
+        token_hash: &str,
+        expires_at: i64,
+    ) -> DbResult<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! { "$set": { "password_reset": {
+                    "token_hash": token_hash,
+                    "expires_at": expires_at,
+                } } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_password_reset(
+        &self,
+        token_hash: &str,
+    ) -> DbResult<Option<BackendUserMe>> {
+        self.find_user(doc! {
+            "password_reset.token_hash": token_hash,
+            "password_reset.expires_at": { "$gt": unix_timestamp() },
+        })
+        .await
+    }
+
+    async fn reset_password(
+        &self,
+        id: &UserId,
+        token_hash: &str,
+        password: HashedPassword,
+    ) -> DbResult<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! {
+                    "_id": id.to_string(),
+                    "password_reset.token_hash": token_hash,
+                    "password_reset.expires_at": { "$gt": unix_timestamp() },
+                },
+                doc! {
+                    "$set": { "password": password.to_string(), "session_tokens": [] },
+                    "$unset": { "password_reset": "" },
+                },
+                None,
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    async fn get_username(
+        &self,
+        username: &str,
+    ) -> DbResult<Option<BackendUserMe>> {
+        self.find_user(doc! { "$or": [
+            { "username_key": username_key(username) },
+            { "username_key": { "$exists": false }, "username": username },
+        ] })
+        .await
+    }
+
+    async fn get_session_token(
         &self,
         session_token: SessionToken,
-    ) -> Option<BackendUserMe> {
+    ) -> DbResult<Option<BackendUserMe>> {
+        let now = unix_timestamp();
         let user: OptionFuture<_> = self
             .collection
-            .find_one(doc! {"session_tokens": session_token.to_string() }, None)
-            .await
-            .ok()
-            .flatten()
-            .map(|user| user.to_backend_user())
+            .find_one_and_update(
+                doc! {
+                    "session_tokens": { "$elemMatch": {
+                        "token_hash": session_token.hash(&self.session_secret),
+                        "created_at": { "$gt": self.session_created_after(now) },
+                        "last_seen": { "$gt": self.session_seen_after(now) },
+                    } }
+                },
+                doc! { "$set": { "session_tokens.$.last_seen": now } },
+                FindOneAndUpdateOptions::builder()
+                    .projection(Some(doc! { "session_tokens": 0 }))
+                    .return_document(Some(ReturnDocument::After))
+                    .build(),
+            )
+            .await?
+            .map(|user| user.into_backend_user())
             .into();
-        user.await
+        Ok(user.await)
     }
 
-    pub async fn create_session_token(
-        &self,
-        auth: UserAuth,
-    ) -> Option<SessionToken> {
-        if let Some(user) = self.get_auth(auth).await {
-            let session_token = SessionToken::new();
-            return self
-                .collection
-                .update_one(
-                    doc! {"username": user.username},
-                    doc! { "$push": { "session_tokens": session_token.to_string() } },
-                    None,
-                )
-                .await
-                .map(|_| session_token)
-                .ok();
+    async fn create_session(&self, id: &UserId, client: ClientInfo) -> DbResult<Option<NewSession>> {
+        let (session_token, session) = self.new_session(client);
+        let session_id = session.id.clone();
+        let max_sessions = self.session_config.max_sessions_per_user as i64;
+        let res = self
+            .collection
+            .update_one(
+                doc! {"_id": id.to_string()},
+                doc! { "$push": { "session_tokens": {
+                    "$each": [bson::to_bson(&session)?],
+                    "$sort": { "created_at": 1 },
+                    "$slice": -max_sessions,
+                } } },
+                None,
+            )
+            .await?;
+        Ok(Some(NewSession {
+            user_id: *id,
+            session_id,
+            token: session_token,
+        })
+        .filter(|_| res.matched_count > 0))
+    }
+
+    async fn rotate_session_token(&self, session_token: &SessionToken) -> DbResult<Rotation> {
+        let now = unix_timestamp();
+        let old_hash = session_token.hash(&self.session_secret);
+        let new_token = SessionToken::new();
+        let new_hash = new_token.hash(&self.session_secret);
+        let user = self
+            .sessions
+            .find_one_and_update(
+                doc! {
+                    "session_tokens": { "$elemMatch": {
+                        "token_hash": &old_hash,
+                        "created_at": { "$gt": self.session_created_after(now) },
+                        "last_seen": { "$gt": self.session_seen_after(now) },
+                    } }
+                },
+                doc! {
+                    "$set": {
+                        "session_tokens.$.token_hash": &new_hash,
+                        "session_tokens.$.last_seen": now,
+                    },
+                    "$push": { "session_tokens.$.rotated_hashes": {
+                        "$each": [&old_hash],
+                        "$slice": -MAX_ROTATED_HASHES,
+                    } },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .projection(Some(doc! {
+                        "session_tokens": { "$elemMatch": { "token_hash": &new_hash } },
+                    }))
+                    .return_document(Some(ReturnDocument::After))
+                    .build(),
+            )
+            .await?;
+        let rotated = user.and_then(|user| match user.session_tokens.into_iter().next() {
+            Some(StoredSession::Hashed(session)) => Some((user.id, session.id)),
+            _ => None,
+        });
+        if let Some((user_id, session_id)) = rotated {
+            return Ok(Rotation::Rotated(NewSession {
+                user_id,
+                session_id,
+                token: new_token,
+            }));
         }
-        None
+
+        let res = self
+            .collection
+            .update_one(
+                doc! { "session_tokens.rotated_hashes": &old_hash },
+                doc! { "$pull": { "session_tokens": { "rotated_hashes": &old_hash } } },
+                None,
+            )
+            .await?;
+        Ok(if res.modified_count > 0 { Rotation::Reused } else { Rotation::Unknown })
+    }
+
+    async fn set_pending_totp(&self, id: &UserId, totp: &TotpEnrollment) -> DbResult<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! { "_id": id.to_string(), "totp.confirmed": { "$ne": true } },
+                doc! { "$set": { "totp": bson::to_bson(totp)? } },
+                None,
+            )
+            .await?;
+        Ok(res.matched_count > 0)
     }
 
-    pub async fn remove_session_token(&self, session_token: SessionToken) -> Result<(), ()> {
-        let session_token_str = session_token.to_string();
+    async fn confirm_totp(&self, id: &UserId, step: i64, recovery_code_hashes: Vec<String>) -> DbResult<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! { "_id": id.to_string(), "totp.confirmed": false },
+                doc! { "$set": {
+                    "totp.confirmed": true,
+                    "totp.last_step": step,
+                    "totp.recovery_codes": recovery_code_hashes,
+                } },
+                None,
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    async fn use_totp_step(&self, id: &UserId, step: i64) -> DbResult<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! { "_id": id.to_string(), "totp.last_step": { "$lt": step } },
+                doc! { "$set": { "totp.last_step": step } },
+                None,
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    async fn use_recovery_code(&self, id: &UserId, code_hash: &str) -> DbResult<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! { "_id": id.to_string(), "totp.recovery_codes": code_hash },
+                doc! { "$pull": { "totp.recovery_codes": code_hash } },
+                None,
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    async fn remove_totp(&self, id: &UserId) -> DbResult<()> {
         self.collection
             .update_one(
-                doc! { "session_tokens": &session_token_str },
-                doc! { "$pull": { "session_tokens": session_token_str } },
+                doc! { "_id": id.to_string() },
+                doc! { "$unset": { "totp": "" } },
                 None,
             )
-            .await
-            .map(|_| ())
-            .map_err(|_| ())
+            .await?;
+        Ok(())
     }
 
-    pub async fn insert(&self, user: BackendUserMe) -> bool {
+    async fn remove_session_token(&self, session_token: SessionToken) -> DbResult<()> {
+        let token_hash = session_token.hash(&self.session_secret);
+        self.collection
+            .update_one(
+                doc! { "session_tokens.token_hash": &token_hash },
+                doc! { "$pull": { "session_tokens": { "token_hash": token_hash } } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(
+        &self,
+        id: &UserId,
+        current: &SessionToken,
+    ) -> DbResult<Vec<SessionInfo>> {
+        let now = unix_timestamp();
+        let current_hash = current.hash(&self.session_secret);
+        let user = self
+            .sessions
+            .find_one(
+                doc! { "_id": id.to_string() },
+                FindOneOptions::builder()
+                    .projection(Some(doc! { "session_tokens": 1 }))
+                    .build(),
+            )
+            .await?;
+
+        Ok(user
+            .map(|user| user.session_tokens)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|session| match session {
+                StoredSession::Hashed(session) => Some(session),
+                StoredSession::Plain(_) | StoredSession::Legacy(_) => None,
+            })
+            .filter(|session| {
+                session.created_at > self.session_created_after(now)
+                    && session.last_seen > self.session_seen_after(now)
+            })
+            .map(|session| SessionInfo {
+                fingerprint: fingerprint(&session.token_hash),
+                current: session.token_hash == current_hash,
+                id: session.id,
+                created_at: session.created_at,
+                last_seen: session.last_seen,
+                user_agent: session.user_agent,
+                ip: session.ip,
+            })
+            .collect())
+    }
+
+    async fn has_session(&self, id: &UserId, session_id: &str) -> DbResult<bool> {
+        let now = unix_timestamp();
+        let sessions = self
+            .collection
+            .count_documents(
+                doc! {
+                    "_id": id.to_string(),
+                    "session_tokens": { "$elemMatch": {
+                        "id": session_id,
+                        "created_at": { "$gt": self.session_created_after(now) },
+                        "last_seen": { "$gt": self.session_seen_after(now) },
+                    } },
+                },
+                None,
+            )
+            .await?;
+        Ok(sessions > 0)
+    }
+
+    async fn remove_session(&self, id: &UserId, session_id: &str) -> DbResult<bool> {
+        let res = self
+            .collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! { "$pull": { "session_tokens": { "id": session_id } } },
+                None,
+            )
+            .await?;
+        Ok(res.modified_count > 0)
+    }
+
+    async fn remove_all_sessions(&self, id: &UserId) -> DbResult<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! { "$set": { "session_tokens": [] } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn prune_expired_sessions(&self) -> DbResult<()> {
+        let now = unix_timestamp();
+        let expired = doc! { "$or": [
+            { "created_at": { "$lte": self.session_created_after(now) } },
+            { "last_seen": { "$lte": self.session_seen_after(now) } },
+        ] };
+        self.collection
+            .update_many(
+                doc! { "session_tokens": { "$elemMatch": expired.clone() } },
+                doc! { "$pull": { "session_tokens": expired } },
+                None,
+            )
+            .await?;
+        self.collection
+            .update_many(
+                doc! { "session_tokens": { "$type": "string" } },
+                doc! { "$pull": { "session_tokens": { "$type": "string" } } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: &UserId) -> DbResult<()> {
+        self.collection
+            .delete_one(doc! { "_id": id.to_string() }, None)
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    async fn insert(&self, user: BackendUserMe) -> DbResult<()> {
         self.collection
             .insert_one(DbUser::from_backend_user(user), None)
-            .await
-            .is_ok()
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_with_session(
+        &self,
+        user: BackendUserMe,
+        email_nonce: Option<&str>,
+        client: ClientInfo,
+    ) -> DbResult<NewSession> {
+        let user_id = user.id;
+        let (token, session) = self.new_session(client);
+        let session_id = session.id.clone();
+        // A single document is written atomically, sessions are skipped by `DbUser`.
+        let mut user = bson::to_document(&DbUser::from_backend_user(user))?;
+        user.insert("session_tokens", vec![bson::to_bson(&session)?]);
+        if let Some(nonce) = email_nonce {
+            user.insert("email_verification_nonce", nonce);
+        }
+        self.collection
+            .clone_with_type::<Document>()
+            .insert_one(user, None)
+            .await?;
+        Ok(NewSession {
+            user_id,
+            session_id,
+            token,
+        })
     }
 }
 
@@ -127,27 +740,108 @@ struct DbUser {
     #[serde(rename = "_id")]
     pub id: UserId,
     pub username: String,
+    /// Unique, see `policy::username_key`. Missing on users stored before it existed.
+    #[serde(default)]
+    pub username_key: Option<String>,
     pub password: HashedPassword,
 
     #[serde(default)]
     pub email: Option<String>,
+    /// Unique, see `email::email_key`. Only set once the address is verified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_key: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<TotpEnrollment>,
+    /// Accounts at identity providers that log in as this user.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identities: Vec<ExternalIdentity>,
 
     #[serde(skip)]
     #[allow(dead_code)]
-    pub session_tokens: Vec<SessionToken>,
+    pub session_tokens: Vec<DbSession>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DbSession {
+    id: String,
+    token_hash: String,
+    created_at: i64,
+    last_seen: i64,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    ip: Option<String>,
+    /// Hashes of the tokens this session had before refreshes, see `rotate_session_token`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rotated_hashes: Vec<String>,
+}
+
+/// A session stored before tokens were hashed, see `hash_plain_session_tokens`.
+#[derive(Debug, Serialize, Deserialize)]
+struct DbPlainSession {
+    /// Missing on sessions stored before they could be listed.
+    #[serde(default)]
+    id: String,
+    token: SessionToken,
+    created_at: i64,
+    last_seen: i64,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    ip: Option<String>,
+}
+
+impl DbPlainSession {
+    fn into_hashed(self, secret: &[u8]) -> DbSession {
+        DbSession {
+            id: if self.id.is_empty() { new_session_id() } else { self.id },
+            token_hash: self.token.hash(secret),
+            created_at: self.created_at,
+            last_seen: self.last_seen,
+            user_agent: self.user_agent,
+            ip: self.ip,
+            rotated_hashes: vec![],
+        }
+    }
+}
+
+/// Every shape sessions were stored in. Before sessions had timestamps they were
+/// plain token strings, which are pruned but may still be around.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredSession {
+    Hashed(DbSession),
+    Plain(DbPlainSession),
+    Legacy(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DbUserSessions {
+    #[serde(rename = "_id")]
+    id: UserId,
+    #[serde(default)]
+    session_tokens: Vec<StoredSession>,
 }
 
 impl DbUser {
     fn from_backend_user(user: BackendUserMe) -> Self {
         DbUser {
             id: user.id,
+            username_key: Some(username_key(&user.username)),
             username: user.username,
             password: user.password,
+            email_key: user.email.as_deref().filter(|_| user.email_verified).map(email_key),
             email: user.email,
+            email_verified: user.email_verified,
+            totp: user.totp,
+            identities: vec![],
             session_tokens: vec![],
         }
     }
-    async fn to_backend_user(
+    async fn into_backend_user(
         self,
     ) -> BackendUserMe {
         BackendUserMe {
@@ -155,6 +849,81 @@ impl DbUser {
             username: self.username,
             password: self.password,
             email: self.email,
+            email_verified: self.email_verified,
+            totp: self.totp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api::ApiError, database::mongodb_test_database};
+    use futures::future::join_all;
+
+    /// A fresh database with the user indexes, for the test to drop.
+    async fn test_users() -> (Database, UserCollection) {
+        let db = mongodb_test_database().await;
+        UserCollection::create_indexes(&db).await.unwrap();
+        let users = UserCollection::new(&db, SessionConfig::from_env(), b"secret".to_vec());
+        (db, users)
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs MongoDB at MONGO_URL"]
+    async fn concurrent_registrations_keep_usernames_unique() {
+        let (db, users) = test_users().await;
+
+        let inserts = ["alice", "Alice", "ALICE", "alice"].iter().map(|username| {
+            let password = HashedPassword::new(String::from("password"));
+            users.insert(BackendUserMe::new(username.to_string(), password))
+        });
+        let results = join_all(inserts).await;
+        assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+        for err in results.into_iter().filter_map(Result::err) {
+            assert!(matches!(ApiError::from(err), ApiError::UsernameInUse));
         }
+        db.drop(None).await.unwrap();
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    #[ignore = "needs MongoDB at MONGO_URL"]
+    async fn hashing_plain_tokens_keeps_every_session() {
+        let (db, users) = test_users().await;
+
+        let password = HashedPassword::new(String::from("password"));
+        let user = BackendUserMe::new(String::from("alice"), password);
+        let id = user.id;
+        users.insert(user).await.unwrap();
+        let plain = SessionToken::new();
+        let now = unix_timestamp();
+        users
+            .collection
+            .update_one(
+                doc! { "_id": id.to_string() },
+                doc! { "$set": { "session_tokens": [
+                    { "token": bson::to_bson(&plain).unwrap(), "created_at": now, "last_seen": now },
+                    "legacy",
+                ] } },
+                None,
+            )
+            .await
+            .unwrap();
+        // Like one created by a server still running the previous version.
+        let hashed = users.create_session(&id, ClientInfo::default()).await.unwrap().unwrap();
+
+        users.hash_plain_session_tokens().await.unwrap();
+        assert!(users.get_session_token(plain).await.unwrap().is_some());
+        assert!(users.get_session_token(hashed.token).await.unwrap().is_some());
+        let sessions = users
+            .sessions
+            .find_one(doc! { "_id": id.to_string() }, None)
+            .await
+            .unwrap()
+            .unwrap()
+            .session_tokens;
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().all(|session| matches!(session, StoredSession::Hashed(_))));
+        db.drop(None).await.unwrap();
+    }
+}
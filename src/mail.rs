@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use actix_web::web;
+use lettre::{
+    message::Mailbox,
+    transport::{
+        file::FileTransport,
+        smtp::{authentication::Credentials, SmtpTransport},
+        stub::StubTransport,
+    },
+    Message, Transport,
+};
+
+use crate::config::env_var;
+
+const MAIL_FROM_DEFAULT: &str = "TODO <noreply@localhost>";
+const MAIL_FILE_DIR_DEFAULT: &str = "mail";
+const PUBLIC_URL_DEFAULT: &str = "http://localhost:8080";
+
+/// Where outgoing mail goes, picked with `MAIL_TRANSPORT`, which must be set.
+pub enum MailTransport {
+    /// Sends through `SMTP_HOST`, on `SMTP_PORT` and logged in with `SMTP_USERNAME` and
+    /// `SMTP_PASSWORD` when they are set.
+    Smtp(SmtpTransport),
+    /// Writes every mail as a file into a directory, for local development.
+    File(FileTransport),
+    /// Keeps mails in memory, see `StubTransport::messages`.
+    Stub(StubTransport),
+    /// Prints mails to stdout, reset and verification links included. Anyone reading the
+    /// logs could take over accounts with them, so it's never picked without being asked for.
+    Log,
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    from: Mailbox,
+    transport: Arc<MailTransport>,
+    /// Base URL of the server as seen by users, links in mails point there.
+    public_url: String,
+}
+
+impl Mailer {
+    pub fn new(from: Mailbox, transport: MailTransport, public_url: String) -> Mailer {
+        Mailer {
+            from,
+            transport: Arc::new(transport),
+            public_url: public_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn from_env() -> Mailer {
+        let transport = std::env::var("MAIL_TRANSPORT")
+            .expect("MAIL_TRANSPORT is not set, use smtp, file, stub or log");
+        let transport = match transport.as_str() {
+            "smtp" => {
+                let host = std::env::var("SMTP_HOST").expect("MAIL_TRANSPORT=smtp needs SMTP_HOST");
+                let mut builder = SmtpTransport::relay(&host).expect("Invalid SMTP_HOST");
+                if let Ok(port) = std::env::var("SMTP_PORT") {
+                    builder = builder.port(port.parse().expect("Invalid SMTP_PORT"));
+                }
+                if let (Ok(username), Ok(password)) =
+                    (std::env::var("SMTP_USERNAME"), std::env::var("SMTP_PASSWORD"))
+                {
+                    builder = builder.credentials(Credentials::new(username, password));
+                }
+                MailTransport::Smtp(builder.build())
+            }
+            "file" => {
+                let dir = env_var("MAIL_FILE_DIR", String::from(MAIL_FILE_DIR_DEFAULT));
+                std::fs::create_dir_all(&dir).expect("Failed to create MAIL_FILE_DIR");
+                MailTransport::File(FileTransport::new(dir))
+            }
+            "stub" => MailTransport::Stub(StubTransport::new_ok()),
+            "log" => MailTransport::Log,
+            other => panic!("Unknown MAIL_TRANSPORT '{}'", other),
+        };
+
+        Mailer::new(
+            env_var("MAIL_FROM", String::from(MAIL_FROM_DEFAULT))
+                .parse()
+                .expect("Invalid MAIL_FROM"),
+            transport,
+            env_var("PUBLIC_URL", String::from(PUBLIC_URL_DEFAULT)),
+        )
+    }
+
+    /// Keeps mails in memory, for tests that don't read them.
+    #[cfg(test)]
+    pub fn stub() -> Mailer {
+        Mailer::new(
+            MAIL_FROM_DEFAULT.parse().unwrap(),
+            MailTransport::Stub(StubTransport::new_ok()),
+            String::from(PUBLIC_URL_DEFAULT),
+        )
+    }
+
+    /// Absolute link to `path_and_query` on the public URL.
+    pub fn link(&self, path_and_query: &str) -> String {
+        format!("{}{}", self.public_url, path_and_query)
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), ()> {
+        let to: Mailbox = to.parse().map_err(|_| ())?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body)
+            .map_err(|_| ())?;
+
+        let transport = self.transport.clone();
+        web::block(move || match transport.as_ref() {
+            MailTransport::Smtp(transport) => transport
+                .send(&message)
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+            MailTransport::File(transport) => transport
+                .send(&message)
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+            MailTransport::Stub(transport) => transport
+                .send(&message)
+                .map_err(|err| err.to_string()),
+            MailTransport::Log => {
+                println!("{}", String::from_utf8_lossy(&message.formatted()));
+                Ok(())
+            }
+        })
+        .await
+        .map_err(|err| println!("Failed to send mail: {:?}", err))
+    }
+}
@@ -1,33 +1,74 @@
 mod api;
+mod config;
+mod crypto;
 mod database;
+mod mail;
+mod util;
 
 use std::sync::Arc;
 
 use actix::Actor;
 use actix_cors::Cors;
 use actix_web::{middleware, web, App, HttpServer};
+use api::rate_limit::RateLimit;
 
-use api::users::user_mgr::UserManager;
+use api::users::{session_pruner::SessionPruner, user_mgr::UserManager};
+use config::Config;
 use database::DatabaseManager;
+use mail::Mailer;
 
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let db_mgr = Arc::new(DatabaseManager::new().await);
-    let user_mgr_addr = UserManager::new(db_mgr.clone()).start();
+    dotenv::dotenv().ok();
+    let config = Arc::new(Config::from_env());
+    // `--dry-run` lists pending migrations, `--migrate-only` applies them, both then exit.
+    if std::env::args().any(|arg| arg == "--dry-run") {
+        let pending = DatabaseManager::pending_migrations(&config).await.map_err(|err| {
+            std::io::Error::other(format!("Failed to look up migrations: {}", err))
+        })?;
+        println!("{} pending migrations", pending.len());
+        for migration in pending {
+            println!("  {}: {}", migration.version, migration.name);
+        }
+        return Ok(());
+    }
+    let db_mgr = DatabaseManager::new(&config).await.map_err(|err| {
+        std::io::Error::other(format!("Failed to set up the database: {}", err))
+    })?;
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        println!("The database is up to date");
+        return Ok(());
+    }
+    let db_mgr = Arc::new(db_mgr);
+    let user_mgr_addr = UserManager::new(db_mgr.clone(), config.clone(), Mailer::from_env()).start();
+    SessionPruner::new(db_mgr.clone(), config.session.prune_interval).start();
+
+    let rate_limit = RateLimit::new(config.rate_limit.clone(), config.trusted_proxies.clone());
+    let pruned_rate_limit = rate_limit.clone();
+    let rate_limit_window = config.rate_limit.window;
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(rate_limit_window);
+        loop {
+            interval.tick().await;
+            pruned_rate_limit.prune();
+        }
+    });
 
     HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
             .wrap(middleware::Compress::default())
+            .data(config.clone())
             .data(db_mgr.clone())
             .data(user_mgr_addr.clone())
             .service(
                 web::scope("/api")
+                    .wrap(rate_limit.clone())
                     .wrap(
                         Cors::default()
                             .allowed_origin("localhost")
-                            .allowed_methods(vec!["GET", "POST", "DELETE"])
+                            .allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
                             .allow_any_header()
                             .max_age(3600),
                     )
@@ -0,0 +1,9 @@
+use std::time::SystemTime;
+
+/// Seconds since the Unix epoch, the timestamp format used in stored documents.
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}